@@ -0,0 +1,136 @@
+//! Round trips per second against the dummy server, the old polling loop
+//! versus the `Controller`. The last line is the time of the former over that
+//! of the latter, above 1 where the controller is faster.
+//!
+//! The controller is not faster: the polling loop receives on the calling
+//! thread, while the controller hands each reply over from its I/O thread,
+//! which is what lets several requests share the socket. On a single core host
+//! that costs a quarter to a third of the rate, at 64 as at 512 devices.
+//!
+//! Run it as `cargo bench --bench throughput`, `SDAA_BENCH_DEVICES` and
+//! `SDAA_BENCH_ROUNDS` override the defaults.
+
+use std::{
+    collections::BTreeSet,
+    io::Cursor,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use binrw::{BinRead, BinWrite};
+use rand::{Rng, rng};
+use sdaa_ctrl::{
+    controller::Controller,
+    ctrl_msg::{CtrlMsg, Outcome},
+};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::Simulator;
+
+const LOCAL_ADDR: &str = "127.0.0.1:0";
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(default)
+}
+
+/// What `send_cmd` did before the I/O core: a fresh socket per call, draining it
+/// nonblocking between two sends and a new buffer for every datagram
+fn legacy_send(cmd: &mut CtrlMsg, targets: &[SocketAddr], timeout: Duration) -> usize {
+    let socket = UdpSocket::bind(LOCAL_ADDR).expect("failed to bind");
+    socket
+        .set_nonblocking(true)
+        .expect("nonblocking set failed");
+    let mut msg_set = BTreeSet::new();
+    let mut replied = 0;
+    let mut handle = |buf: Vec<u8>, _a: SocketAddr, msg_set: &mut BTreeSet<u32>| {
+        let reply = CtrlMsg::read(&mut Cursor::new(buf)).expect("failed to read reply");
+        msg_set.remove(&reply.get_msg_id());
+        replied += 1;
+    };
+    for addr in targets {
+        let msg_id: u32 = rng().random();
+        cmd.set_msg_id(msg_id);
+        msg_set.insert(msg_id);
+        let mut buf = Cursor::new(Vec::new());
+        cmd.write(&mut buf).expect("failed to write cmd to buf");
+        socket.send_to(&buf.into_inner(), addr).expect("send error");
+
+        let mut buf = vec![0_u8; 9000];
+        while let Ok((_, a)) = socket.recv_from(&mut buf) {
+            let buf1 = std::mem::replace(&mut buf, vec![0_u8; 9000]);
+            handle(buf1, a, &mut msg_set);
+        }
+    }
+    socket
+        .set_nonblocking(false)
+        .expect("nonblocking set failed");
+    socket
+        .set_read_timeout(Some(timeout))
+        .expect("failed to set timeout");
+    while !msg_set.is_empty() {
+        let mut buf = vec![0_u8; 9000];
+        let Ok((_, a)) = socket.recv_from(&mut buf) else {
+            break;
+        };
+        handle(buf, a, &mut msg_set);
+    }
+    replied
+}
+
+fn report(name: &str, rounds: usize, replies: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    eprintln!(
+        "{name:>10}: {rounds} rounds in {secs:.3} s, {:.1} rounds/s, {:.0} replies/s",
+        rounds as f64 / secs,
+        replies as f64 / secs
+    );
+}
+
+fn main() {
+    let n_devices = env_or("SDAA_BENCH_DEVICES", 64);
+    let rounds = env_or("SDAA_BENCH_ROUNDS", 200);
+    let timeout = Duration::from_secs(1);
+    let server = Simulator::spawn(&vec!["127.0.0.1:0"; n_devices], 0);
+    let devices = &server.addrs;
+    let cmd = CtrlMsg::Query { msg_id: 0 };
+
+    let start = Instant::now();
+    let mut replies = 0;
+    for _ in 0..rounds {
+        replies += legacy_send(&mut cmd.clone(), devices, timeout);
+    }
+    let legacy = start.elapsed();
+
+    let ctrl = Controller::bind(LOCAL_ADDR)
+        .expect("failed to bind")
+        .with_timeout(Some(timeout));
+    let start = Instant::now();
+    let mut replies_ctrl = 0;
+    for _ in 0..rounds {
+        let summary = ctrl
+            .send_many(cmd.clone(), devices)
+            .expect("send_many failed");
+        replies_ctrl += summary
+            .targets
+            .values()
+            .filter(|r| !matches!(r.outcome, Outcome::Timeout))
+            .count();
+    }
+    let evented = start.elapsed();
+
+    drop(server);
+
+    eprintln!("{n_devices} devices");
+    report("polling", rounds, replies, legacy);
+    report("controller", rounds, replies_ctrl, evented);
+    eprintln!(
+        "{:>10}: x{:.2}",
+        "ratio",
+        legacy.as_secs_f64() / evented.as_secs_f64()
+    );
+}
@@ -0,0 +1,445 @@
+#ifndef SDAA_CTRL_H
+#define SDAA_CTRL_H
+
+#include <cstdarg>
+#include <cstdint>
+#include <cstdlib>
+#include <ostream>
+#include <new>
+
+namespace sdaa {
+
+/// Port the devices listen on for control messages
+constexpr static const uint16_t DEFAULT_REMOTE_PORT = 3000;
+
+/// Bytes of `SdaaDeviceInfo::addr`, enough for any "ip:port"
+constexpr static const uintptr_t DEVICE_ADDR_LEN = 64;
+
+/// Words of `TEHealth` payload a `QueryResult` holds, the rest is cut off
+constexpr static const uintptr_t TE_HEALTH_MAX = 64;
+
+/// Nominal number of 10 MHz ticks between two pps edges
+constexpr static const uint32_t NOMINAL_PPS_PERIOD = 10000000;
+
+/// What an exported function returns, details of a failure are in `sdaa_last_error`
+enum class sdaa_status {
+  Ok = 0,
+  /// a null pointer or a malformed string was passed
+  InvalidArgument,
+  /// the output buffer cannot hold the whole result, it holds what fits
+  BufferTooSmall,
+  /// the local socket could not be bound
+  Bind,
+  /// an address could not be resolved
+  Resolve,
+  /// sending or receiving failed
+  Io,
+  /// the device did not reply in time
+  Timeout,
+  /// the device answered with an InvalidMsg, its code is in `sdaa_last_error`
+  DeviceError,
+  /// an I2C transaction failed, its code is in `sdaa_last_error`
+  I2CError,
+  /// the device answered with another reply than the cmd expects
+  UnexpectedReply,
+  /// the reply could not be decoded
+  Decode,
+  /// anything else, see the message of `sdaa_last_error`
+  Internal,
+};
+
+/// Which of the health fields of a `QueryResult` the device filled in
+enum class HealthKind : uint32_t {
+  Hl = 0,
+  Te = 1,
+  T510 = 2,
+};
+
+/// Opaque handle of the C API, talking to one device from one local port
+struct sdaa_ctrl_t;
+
+/// Where and how the `_cfg` functions talk to the devices
+struct sdaa_config {
+  /// "ip:port" to bind, null for an ephemeral port on any address of the
+  /// device's address family
+  const char *local_addr;
+  /// 0 for `DEFAULT_REMOTE_PORT`
+  uint16_t remote_port;
+  /// how long each attempt waits for the reply, 0 for 1 s
+  uint32_t timeout_ms;
+  /// 0 counts as 1, broadcasts are sent once
+  uint32_t max_attempts;
+};
+
+/// Firmware version word as `status::FirmwareVersion` reads it
+struct sdaa_firmware {
+  uint32_t raw;
+  uint16_t year;
+  uint8_t month;
+  uint8_t day;
+  uint8_t revision;
+  /// year, month and day make a valid date
+  bool has_date;
+};
+
+/// Status of a QueryReply as `status::DeviceStatus` decodes it, checked against
+/// the default `StatusCriteria` and `HealthThresholds`
+struct sdaa_device_status {
+  sdaa_firmware firmware;
+  /// data is being transmitted
+  bool streaming;
+  /// the device is not sleeping
+  bool awake;
+  /// the raw lock word
+  uint32_t locked;
+  /// lock flag of each PLL, taken from the lowest byte of the lock word
+  bool pll_locked[8];
+  /// 10 MHz ticks between the last two pps edges
+  uint32_t pps_period;
+  /// `pps_period` minus the nominal period
+  int64_t pps_deviation;
+  /// every PLL the criteria require is locked
+  bool lock_ok;
+  /// the pps period is within the criteria
+  bool pps_ok;
+  /// every health reading is within its nominal range
+  bool health_ok;
+};
+
+/// A device answering `find_devices_ex`
+struct sdaa_device_info {
+  /// "ip:port", null-terminated
+  char addr[DEVICE_ADDR_LEN];
+  /// the IPv4 address in host byte order, 0 for an IPv6 device
+  uint32_t ipv4;
+  uint16_t port;
+  HealthKind health_kind;
+  sdaa_device_status status;
+  /// round-trip time of the Query in microseconds
+  uint64_t latency_us;
+};
+
+/// Called by `find_devices_cb` with each device as soon as it has answered and
+/// the `user_data` passed along; returning false stops the discovery.
+/// `device` is only valid during the call.
+using sdaa_device_cb = bool(*)(const sdaa_device_info *device, void *user_data);
+
+/// The last failure on the calling thread.
+///
+/// The strings are null when not known and stay valid until the next failure
+/// on the same thread.
+struct sdaa_error {
+  sdaa_status status;
+  /// the cmd that failed, e.g. "Init" or "Sync"
+  const char *cmd;
+  /// "ip:port" of the device the failure is about
+  const char *addr;
+  /// whether `device_err_code` holds the code of an InvalidMsg or I2C reply
+  bool has_device_err_code;
+  uint32_t device_err_code;
+  const char *message;
+};
+
+/// Reply to a Query
+struct QueryResult {
+  uint32_t fm_ver;
+  uint32_t tick_cnt1;
+  uint32_t tick_cnt2;
+  uint32_t trans_state;
+  uint32_t locked;
+  HealthKind health_kind;
+  /// number of health words, for `Hl` and `Te`
+  uint32_t nhealth;
+  /// `Hl` only
+  uint32_t xgbe_state[4];
+  uint64_t pkt_sent[4];
+  uint32_t volt12_inner;
+  uint32_t volt12_input;
+  uint32_t vcc1v0;
+  uint32_t vcc1v8;
+  uint32_t mgtavtt1v2;
+  uint32_t mgtavtt1v0;
+  uint32_t temperatures[2];
+  /// `Te` only, the first `min(nhealth, TE_HEALTH_MAX)` words
+  uint32_t te_payload[TE_HEALTH_MAX];
+  /// `T510` only
+  uint32_t rfdc_restart_cnt;
+  int32_t temperature;
+  /// the fields above decoded and checked
+  sdaa_device_status status;
+};
+
+/// Addresses of one XGbe port, `#[repr(C)]` for the C API
+struct XGbeCfg {
+  uint8_t dst_mac[6];
+  uint8_t src_mac[6];
+  uint8_t dst_ip[4];
+  uint8_t src_ip[4];
+  uint16_t dst_port;
+  uint16_t src_port;
+};
+
+extern "C" {
+
+/// Default config: port 3000, any local address, 1 s timeout, a single attempt
+sdaa_config sdaa_config_default();
+
+/// Number of devices found, unlike the other functions it returns a count: 0
+/// is also what a failure returns, `sdaa_last_error` tells them apart
+///
+/// # Safety
+///
+/// This function should not be called before the horsemen are ready.
+uintptr_t find_device(uint32_t addr, uint32_t *result, uintptr_t max_n, uint16_t local_port);
+
+/// Broadcasts a Query to `bcast_ip` and describes up to `capacity` of the devices
+/// answering, in address order. `nfilled` is set to the number written to `devices`,
+/// `total` to the number of devices that answered; if that is more, the status is
+/// `BufferTooSmall` like that of `find_device_cfg`.
+///
+/// # Safety
+///
+/// `bcast_ip` is a null-terminated string, `cfg` is null or readable, `devices`
+/// holds `capacity` entries, `nfilled` and `total` are null or writable.
+sdaa_status find_devices_ex(const char *bcast_ip,
+                            const sdaa_config *cfg,
+                            sdaa_device_info *devices,
+                            uintptr_t capacity,
+                            uintptr_t *nfilled,
+                            uintptr_t *total);
+
+/// Broadcasts a Query to `bcast_ip` ("192.168.1.255" or an IPv6 address) and fills
+/// `result` with the IPv4 addresses of up to `max_n` devices answering,
+/// host byte order; `n` is set to the number filled in
+///
+/// # Safety
+///
+/// `bcast_ip` is a null-terminated string, `cfg` is null or readable, `result` holds
+/// `max_n` entries and `n` is null or writable.
+sdaa_status find_device_cfg(const char *bcast_ip,
+                            const sdaa_config *cfg,
+                            uint32_t *result,
+                            uintptr_t max_n,
+                            uintptr_t *n);
+
+/// True on success, `sdaa_last_error` tells what failed otherwise
+///
+/// # Safety
+///
+/// This function should not be called before the horsemen are ready.
+bool make_device(uint32_t ip, uint16_t local_port);
+
+/// Inits then syncs the device at `ip`, the last error names the step that failed
+///
+/// # Safety
+///
+/// `ip` is a null-terminated string, `cfg` is null or readable.
+sdaa_status make_device_cfg(const char *ip, const sdaa_config *cfg);
+
+/// True on success, `sdaa_last_error` tells what failed otherwise
+///
+/// # Safety
+///
+/// This function should not be called before the horsemen are ready.
+bool unmake_device(uint32_t ip, uint16_t local_port);
+
+/// # Safety
+///
+/// `ip` is a null-terminated string, `cfg` is null or readable.
+sdaa_status unmake_device_cfg(const char *ip, const sdaa_config *cfg);
+
+/// True on success, `sdaa_last_error` tells what failed otherwise
+///
+/// # Safety
+///
+/// This function should not be called before the horsemen are ready.
+bool start_stream(uint32_t ip, uint16_t local_port);
+
+/// # Safety
+///
+/// `ip` is a null-terminated string, `cfg` is null or readable.
+sdaa_status start_stream_cfg(const char *ip, const sdaa_config *cfg);
+
+/// Broadcasts a Query to `bcast_ip` and reports the devices to `callback` as
+/// their replies arrive, instead of after the timeout; `nreported` is set to the
+/// number of calls made
+///
+/// # Safety
+///
+/// `bcast_ip` is a null-terminated string, `cfg` is null or readable, `nreported`
+/// is null or writable; `user_data` is only passed on to `callback`.
+sdaa_status find_devices_cb(const char *bcast_ip,
+                            const sdaa_config *cfg,
+                            sdaa_device_cb callback,
+                            void *user_data,
+                            uintptr_t *nreported);
+
+/// Details of the last failure on the calling thread, `status` is `Ok` if
+/// nothing failed yet
+sdaa_error sdaa_last_error();
+
+/// Creates a handle talking to the device at `remote_addr` ("ip:port") from
+/// `local_addr` ("ip:port"), each attempt waiting `timeout_ms` (0 for 1 s) for
+/// the reply and making up to `max_attempts` attempts
+///
+/// # Safety
+///
+/// Both addresses are null-terminated strings, `handle` is writable.
+sdaa_status sdaa_ctrl_new(const char *local_addr,
+                          const char *remote_addr,
+                          uint32_t timeout_ms,
+                          uint32_t max_attempts,
+                          sdaa_ctrl_t **handle);
+
+/// Releases the handle and its local port
+///
+/// # Safety
+///
+/// `handle` is null or was returned by `sdaa_ctrl_new` and is not used afterwards.
+void sdaa_ctrl_free(sdaa_ctrl_t *handle);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `result` is writable.
+sdaa_status sdaa_ctrl_query(const sdaa_ctrl_t *handle, QueryResult *result);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_sync(const sdaa_ctrl_t *handle);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_init(const sdaa_ctrl_t *handle);
+
+/// Configures all 4 XGbe ports
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `cfg` points to 4 entries.
+sdaa_status sdaa_ctrl_xgbe_cfg(const sdaa_ctrl_t *handle, const XGbeCfg *cfg);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `cfg` is readable.
+sdaa_status sdaa_ctrl_xgbe_cfg_single(const sdaa_ctrl_t *handle,
+                                      uint32_t port_id,
+                                      const XGbeCfg *cfg);
+
+/// Reads the configuration of the XGbe ports into `cfg`, `nports` is set to the number of ports
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `cfg` holds `capacity` entries, `nports` is writable.
+sdaa_status sdaa_ctrl_xgbe_cfg_query(const sdaa_ctrl_t *handle,
+                                     XGbeCfg *cfg,
+                                     uintptr_t capacity,
+                                     uintptr_t *nports);
+
+/// Addresses of the devices on the I2C bus, `ndev` is set to their number
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `devices` holds `capacity` bytes, `ndev` is writable.
+sdaa_status sdaa_ctrl_i2c_scan(const sdaa_ctrl_t *handle,
+                               uint8_t *devices,
+                               uintptr_t capacity,
+                               uintptr_t *ndev);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `data` holds `len` bytes.
+sdaa_status sdaa_ctrl_i2c_write(const sdaa_ctrl_t *handle,
+                                uint32_t dev_addr,
+                                const uint8_t *data,
+                                uintptr_t len);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `data` holds `len` bytes.
+sdaa_status sdaa_ctrl_i2c_write_reg(const sdaa_ctrl_t *handle,
+                                    uint32_t dev_addr,
+                                    uint32_t reg_addr,
+                                    const uint8_t *data,
+                                    uintptr_t len);
+
+/// Reads `nbytes` bytes into `buf`, `len` is set to the number the device returned
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `buf` holds `nbytes` bytes, `len` is null or writable.
+sdaa_status sdaa_ctrl_i2c_read(const sdaa_ctrl_t *handle,
+                               uint32_t dev_addr,
+                               uint8_t *buf,
+                               uintptr_t nbytes,
+                               uintptr_t *len);
+
+/// Reads `nbytes` bytes from register `reg_addr` into `buf`, `len` is set to the
+/// number the device returned
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `buf` holds `nbytes` bytes, `len` is null or writable.
+sdaa_status sdaa_ctrl_i2c_read_reg(const sdaa_ctrl_t *handle,
+                                   uint32_t dev_addr,
+                                   uint32_t reg_addr,
+                                   uint8_t *buf,
+                                   uintptr_t nbytes,
+                                   uintptr_t *len);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_stream_start(const sdaa_ctrl_t *handle);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_stream_stop(const sdaa_ctrl_t *handle);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_bit_shift(const sdaa_ctrl_t *handle, uint32_t shift_bits);
+
+/// op_code 1 wakes the device up, 0 puts it to sleep
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_pwr_ctrl(const sdaa_ctrl_t *handle, uint32_t op_code);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_sleep(const sdaa_ctrl_t *handle);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_wakeup(const sdaa_ctrl_t *handle);
+
+/// Selects the clock and pps sources, `clk_state` is set to what the device reports
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `clk_state` is null or writable.
+sdaa_status sdaa_ctrl_set_clk(const sdaa_ctrl_t *handle,
+                              uint32_t clk_src,
+                              uint32_t pps_src,
+                              uint32_t *clk_state);
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+sdaa_status sdaa_ctrl_mixer_set(const sdaa_ctrl_t *handle,
+                                double freq,
+                                double phase,
+                                uint32_t sync);
+
+}  // extern "C"
+
+}  // namespace sdaa
+
+#endif  // SDAA_CTRL_H
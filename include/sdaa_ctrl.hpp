@@ -0,0 +1,392 @@
+// C++17 wrapper of the handle API of sdaa_ctrl.h, header only.
+//
+// Failures throw sdaa::Error carrying what sdaa_last_error() reported, the
+// try_ variants return std::nullopt instead.
+
+#ifndef SDAA_CTRL_HPP
+#define SDAA_CTRL_HPP
+
+#include <algorithm>
+#include <array>
+#include <chrono>
+#include <cstdint>
+#include <exception>
+#include <iterator>
+#include <optional>
+#include <stdexcept>
+#include <string>
+#include <utility>
+#include <variant>
+#include <vector>
+
+#include "sdaa_ctrl.h"
+
+namespace sdaa {
+
+/// A failed call, with the details of sdaa_last_error()
+class Error : public std::runtime_error {
+ public:
+  explicit Error(const sdaa_error &e)
+      : std::runtime_error(e.message ? e.message : "unknown error"),
+        status_(e.status),
+        cmd_(e.cmd ? e.cmd : ""),
+        addr_(e.addr ? e.addr : "") {
+    if (e.has_device_err_code) {
+      device_err_code_ = e.device_err_code;
+    }
+  }
+
+  sdaa_status status() const { return status_; }
+  /// the cmd that failed, e.g. "Init" or "Sync"
+  const std::string &cmd() const { return cmd_; }
+  /// "ip:port" of the device, empty if the failure is about none
+  const std::string &addr() const { return addr_; }
+  /// code of the InvalidMsg or I2C reply
+  std::optional<uint32_t> device_err_code() const { return device_err_code_; }
+
+ private:
+  sdaa_status status_;
+  std::string cmd_;
+  std::string addr_;
+  std::optional<uint32_t> device_err_code_;
+};
+
+namespace detail {
+
+inline void check(sdaa_status status) {
+  if (status != sdaa_status::Ok) {
+    throw Error(sdaa_last_error());
+  }
+}
+
+}  // namespace detail
+
+/// Firmware version word, decoded by the library as BCD `YY MM DD` followed by a revision byte
+struct FirmwareVersion {
+  uint32_t raw = 0;
+  uint16_t year = 0;
+  uint8_t month = 0;
+  uint8_t day = 0;
+  uint8_t revision = 0;
+  /// year, month and day make a valid date
+  bool has_date = false;
+
+  static FirmwareVersion from_raw(const sdaa_firmware &f) {
+    return FirmwareVersion{f.raw, f.year, f.month, f.day, f.revision, f.has_date};
+  }
+};
+
+/// Status fields of a QueryReply as the library decodes and checks them, see sdaa_device_status
+struct DeviceStatus {
+  FirmwareVersion firmware;
+  /// data is being transmitted
+  bool streaming = false;
+  /// the device is not sleeping
+  bool awake = false;
+  /// the raw lock word
+  uint32_t locked = 0;
+  /// lock flag of each PLL, from the lowest byte of the lock word
+  std::array<bool, 8> pll_locked{};
+  /// 10 MHz ticks between the last two pps edges
+  uint32_t pps_period = 0;
+  /// `pps_period` minus the nominal period
+  int64_t pps_deviation = 0;
+  /// every PLL the default criteria require is locked
+  bool lock_ok = false;
+  /// the pps period is within the default criteria
+  bool pps_ok = false;
+  /// every health reading is within its default nominal range
+  bool health_ok = false;
+
+  static DeviceStatus from_raw(const sdaa_device_status &r) {
+    DeviceStatus s;
+    s.firmware = FirmwareVersion::from_raw(r.firmware);
+    s.streaming = r.streaming;
+    s.awake = r.awake;
+    s.locked = r.locked;
+    std::copy(std::begin(r.pll_locked), std::end(r.pll_locked), s.pll_locked.begin());
+    s.pps_period = r.pps_period;
+    s.pps_deviation = r.pps_deviation;
+    s.lock_ok = r.lock_ok;
+    s.pps_ok = r.pps_ok;
+    s.health_ok = r.health_ok;
+    return s;
+  }
+};
+
+struct HlHealth {
+  uint32_t nhealth = 0;
+  std::array<uint32_t, 4> xgbe_state{};
+  std::array<uint64_t, 4> pkt_sent{};
+  uint32_t volt12_inner = 0;
+  uint32_t volt12_input = 0;
+  uint32_t vcc1v0 = 0;
+  uint32_t vcc1v8 = 0;
+  uint32_t mgtavtt1v2 = 0;
+  uint32_t mgtavtt1v0 = 0;
+  std::array<uint32_t, 2> temperatures{};
+};
+
+struct TeHealth {
+  uint32_t nhealth = 0;
+  /// at most TE_HEALTH_MAX words
+  std::vector<uint32_t> payload;
+};
+
+struct T510Health {
+  uint32_t rfdc_restart_cnt = 0;
+  int32_t temperature = 0;
+};
+
+using Health = std::variant<HlHealth, TeHealth, T510Health>;
+
+/// Reply to a Query
+struct QueryStatus {
+  DeviceStatus status;
+  Health health;
+
+  static QueryStatus from_raw(const QueryResult &r) {
+    QueryStatus q;
+    q.status = DeviceStatus::from_raw(r.status);
+    switch (r.health_kind) {
+      case HealthKind::Hl: {
+        HlHealth h;
+        h.nhealth = r.nhealth;
+        std::copy(std::begin(r.xgbe_state), std::end(r.xgbe_state), h.xgbe_state.begin());
+        std::copy(std::begin(r.pkt_sent), std::end(r.pkt_sent), h.pkt_sent.begin());
+        h.volt12_inner = r.volt12_inner;
+        h.volt12_input = r.volt12_input;
+        h.vcc1v0 = r.vcc1v0;
+        h.vcc1v8 = r.vcc1v8;
+        h.mgtavtt1v2 = r.mgtavtt1v2;
+        h.mgtavtt1v0 = r.mgtavtt1v0;
+        std::copy(std::begin(r.temperatures), std::end(r.temperatures), h.temperatures.begin());
+        q.health = h;
+        break;
+      }
+      case HealthKind::Te: {
+        TeHealth h;
+        h.nhealth = r.nhealth;
+        size_t n = r.nhealth < TE_HEALTH_MAX ? r.nhealth : TE_HEALTH_MAX;
+        h.payload.assign(r.te_payload, r.te_payload + n);
+        q.health = h;
+        break;
+      }
+      case HealthKind::T510:
+        q.health = T510Health{r.rfdc_restart_cnt, r.temperature};
+        break;
+    }
+    return q;
+  }
+};
+
+/// A device answering a discovery broadcast
+struct Device {
+  /// "ip:port"
+  std::string addr;
+  /// host byte order, 0 for an IPv6 device
+  uint32_t ipv4 = 0;
+  uint16_t port = 0;
+  HealthKind health_kind = HealthKind::Hl;
+  DeviceStatus status;
+  std::chrono::microseconds latency{0};
+
+  static Device from_raw(const sdaa_device_info &d) {
+    Device dev;
+    dev.addr = d.addr;
+    dev.ipv4 = d.ipv4;
+    dev.port = d.port;
+    dev.health_kind = d.health_kind;
+    dev.status = DeviceStatus::from_raw(d.status);
+    dev.latency = std::chrono::microseconds(d.latency_us);
+    return dev;
+  }
+};
+
+/// Where and how discovery talks to the devices, see sdaa_config
+struct Config {
+  /// "ip:port" to bind, empty for an ephemeral port on any address
+  std::string local_addr;
+  uint16_t remote_port = DEFAULT_REMOTE_PORT;
+  std::chrono::milliseconds timeout{1000};
+  uint32_t max_attempts = 1;
+
+  sdaa_config raw() const {
+    sdaa_config c = sdaa_config_default();
+    c.local_addr = local_addr.empty() ? nullptr : local_addr.c_str();
+    c.remote_port = remote_port;
+    c.timeout_ms = uint32_t(timeout.count());
+    c.max_attempts = max_attempts;
+    return c;
+  }
+};
+
+/// Calls `on_device(const Device &)` for each device answering a Query broadcast
+/// to `bcast_ip` as soon as it answers, until it returns false; returns the
+/// number of calls. An exception thrown by `on_device` stops the discovery
+/// and is rethrown.
+template <class F>
+size_t find_devices_each(const std::string &bcast_ip, const Config &cfg, F &&on_device) {
+  struct State {
+    F &f;
+    std::exception_ptr error;
+  } state{on_device, nullptr};
+  auto trampoline = [](const sdaa_device_info *d, void *user_data) -> bool {
+    auto &s = *static_cast<State *>(user_data);
+    try {
+      return s.f(Device::from_raw(*d));
+    } catch (...) {
+      s.error = std::current_exception();
+      return false;
+    }
+  };
+  sdaa_config raw = cfg.raw();
+  size_t n = 0;
+  sdaa_status status = find_devices_cb(bcast_ip.c_str(), &raw, trampoline, &state, &n);
+  if (state.error) {
+    std::rethrow_exception(state.error);
+  }
+  detail::check(status);
+  return n;
+}
+
+/// Every device answering a Query broadcast to `bcast_ip`, in the order they answered
+inline std::vector<Device> find_devices(const std::string &bcast_ip, const Config &cfg = {}) {
+  std::vector<Device> devices;
+  find_devices_each(bcast_ip, cfg, [&](const Device &d) {
+    devices.push_back(d);
+    return true;
+  });
+  return devices;
+}
+
+/// One device reached from one local port, owning a sdaa_ctrl_t
+class Controller {
+ public:
+  /// Addresses are "ip:port", `timeout` is per attempt
+  Controller(const std::string &local_addr, const std::string &remote_addr,
+             std::chrono::milliseconds timeout = std::chrono::milliseconds(1000),
+             uint32_t max_attempts = 1) {
+    detail::check(sdaa_ctrl_new(local_addr.c_str(), remote_addr.c_str(),
+                                uint32_t(timeout.count()), max_attempts, &handle_));
+  }
+
+  ~Controller() { sdaa_ctrl_free(handle_); }
+
+  Controller(const Controller &) = delete;
+  Controller &operator=(const Controller &) = delete;
+
+  Controller(Controller &&other) noexcept : handle_(std::exchange(other.handle_, nullptr)) {}
+
+  Controller &operator=(Controller &&other) noexcept {
+    if (this != &other) {
+      sdaa_ctrl_free(handle_);
+      handle_ = std::exchange(other.handle_, nullptr);
+    }
+    return *this;
+  }
+
+  QueryStatus query() const {
+    QueryResult r{};
+    detail::check(sdaa_ctrl_query(handle_, &r));
+    return QueryStatus::from_raw(r);
+  }
+
+  /// Like query(), std::nullopt instead of an exception
+  std::optional<QueryStatus> try_query() const noexcept {
+    QueryResult r{};
+    if (sdaa_ctrl_query(handle_, &r) != sdaa_status::Ok) {
+      return std::nullopt;
+    }
+    return QueryStatus::from_raw(r);
+  }
+
+  void sync() const { detail::check(sdaa_ctrl_sync(handle_)); }
+
+  void init() const { detail::check(sdaa_ctrl_init(handle_)); }
+
+  void xgbe_cfg(const std::array<XGbeCfg, 4> &cfg) const {
+    detail::check(sdaa_ctrl_xgbe_cfg(handle_, cfg.data()));
+  }
+
+  void xgbe_cfg_single(uint32_t port_id, const XGbeCfg &cfg) const {
+    detail::check(sdaa_ctrl_xgbe_cfg_single(handle_, port_id, &cfg));
+  }
+
+  std::vector<XGbeCfg> xgbe_cfg_query() const {
+    std::vector<XGbeCfg> cfg(MAX_XGBE_PORTS);
+    size_t n = 0;
+    detail::check(sdaa_ctrl_xgbe_cfg_query(handle_, cfg.data(), cfg.size(), &n));
+    cfg.resize(n);
+    return cfg;
+  }
+
+  std::vector<uint8_t> i2c_scan() const {
+    std::vector<uint8_t> devices(MAX_I2C_DEVICES);
+    size_t n = 0;
+    detail::check(sdaa_ctrl_i2c_scan(handle_, devices.data(), devices.size(), &n));
+    devices.resize(n);
+    return devices;
+  }
+
+  void i2c_write(uint32_t dev_addr, const std::vector<uint8_t> &data) const {
+    detail::check(sdaa_ctrl_i2c_write(handle_, dev_addr, data.data(), data.size()));
+  }
+
+  void i2c_write_reg(uint32_t dev_addr, uint32_t reg_addr, const std::vector<uint8_t> &data) const {
+    detail::check(sdaa_ctrl_i2c_write_reg(handle_, dev_addr, reg_addr, data.data(), data.size()));
+  }
+
+  std::vector<uint8_t> i2c_read(uint32_t dev_addr, size_t nbytes) const {
+    std::vector<uint8_t> buf(nbytes);
+    size_t n = 0;
+    detail::check(sdaa_ctrl_i2c_read(handle_, dev_addr, buf.data(), nbytes, &n));
+    buf.resize(n);
+    return buf;
+  }
+
+  std::vector<uint8_t> i2c_read_reg(uint32_t dev_addr, uint32_t reg_addr, size_t nbytes) const {
+    std::vector<uint8_t> buf(nbytes);
+    size_t n = 0;
+    detail::check(sdaa_ctrl_i2c_read_reg(handle_, dev_addr, reg_addr, buf.data(), nbytes, &n));
+    buf.resize(n);
+    return buf;
+  }
+
+  void stream_start() const { detail::check(sdaa_ctrl_stream_start(handle_)); }
+
+  void stream_stop() const { detail::check(sdaa_ctrl_stream_stop(handle_)); }
+
+  void bit_shift(uint32_t shift_bits) const {
+    detail::check(sdaa_ctrl_bit_shift(handle_, shift_bits));
+  }
+
+  /// op_code 1 wakes the device up, 0 puts it to sleep
+  void pwr_ctrl(uint32_t op_code) const { detail::check(sdaa_ctrl_pwr_ctrl(handle_, op_code)); }
+
+  void sleep() const { detail::check(sdaa_ctrl_sleep(handle_)); }
+
+  void wakeup() const { detail::check(sdaa_ctrl_wakeup(handle_)); }
+
+  /// Returns the clock state the device reports
+  uint32_t set_clk(uint32_t clk_src, uint32_t pps_src) const {
+    uint32_t clk_state = 0;
+    detail::check(sdaa_ctrl_set_clk(handle_, clk_src, pps_src, &clk_state));
+    return clk_state;
+  }
+
+  void mixer_set(double freq, double phase, uint32_t sync) const {
+    detail::check(sdaa_ctrl_mixer_set(handle_, freq, phase, sync));
+  }
+
+ private:
+  static constexpr size_t MAX_XGBE_PORTS = 16;
+  /// every 7 bit address
+  static constexpr size_t MAX_I2C_DEVICES = 128;
+
+  sdaa_ctrl_t *handle_ = nullptr;
+};
+
+}  // namespace sdaa
+
+#endif  // SDAA_CTRL_HPP
@@ -0,0 +1,354 @@
+use std::{
+    collections::BTreeSet,
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket, lookup_host},
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    task::JoinHandle,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{Instrument, debug, info, info_span, trace, warn};
+
+use crate::{
+    client::typed_reply,
+    controller::CollectUntil,
+    ctrl_msg::{
+        CmdReplySummary, CtrlError, CtrlMsg, Outcome, dump_received, encode_cmd, outcome_of,
+        try_decode,
+    },
+    logging::HexDump,
+    msg_id::{MsgIdAllocator, SequentialIds, next_free_id},
+    reactor::{RouteTable, peek_msg_id},
+    retry::{Exchange, RetryPolicy, Step, wait_time},
+    typed_msg::{Command, Query, QueryReply},
+};
+
+/// A received datagram and its source
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Where the receive task routes the received datagrams
+type Routes = Arc<Mutex<RouteTable<UnboundedSender<Datagram>>>>;
+
+/// Replies to a broadcast in order of arrival, one per responder
+pub type ReplyStream = UnboundedReceiverStream<(SocketAddr, Outcome)>;
+
+/// Async counterpart of `controller::Controller` for tokio based callers.
+///
+/// A task spawned on the runtime receives every datagram and hands it to the
+/// request waiting for its msg_id; encoding, decoding and reply matching are
+/// the same as for the blocking API.
+#[derive(Debug)]
+pub struct Controller {
+    socket: Arc<UdpSocket>,
+    routes: Routes,
+    ids: Mutex<Box<dyn MsgIdAllocator>>,
+    retry: RetryPolicy,
+    receiver: JoinHandle<()>,
+}
+
+impl Controller {
+    /// Binds `local_addr`, has to be called from within a tokio runtime
+    pub async fn bind<B: ToSocketAddrs>(local_addr: B) -> Result<Self, CtrlError> {
+        let socket = UdpSocket::bind(local_addr).await.map_err(CtrlError::Bind)?;
+        socket.set_broadcast(true).map_err(CtrlError::Bind)?;
+        let socket = Arc::new(socket);
+        let routes = Routes::default();
+        let receiver = tokio::spawn(receive_loop(Arc::clone(&socket), Arc::clone(&routes)));
+        Ok(Controller {
+            socket,
+            routes,
+            ids: Mutex::new(Box::new(SequentialIds::with_random_prefix())),
+            retry: RetryPolicy::default(),
+            receiver,
+        })
+    }
+
+    /// How long to wait for the next reply before giving up, `None` waits forever
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.retry.attempt_timeout = timeout;
+        self
+    }
+
+    /// How the targets of `send_many` are retried, a single attempt by default
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Replaces the default allocator, sequential ids under a random session prefix
+    pub fn with_id_allocator<I: MsgIdAllocator + 'static>(self, ids: I) -> Self {
+        *self.ids.lock().expect("msg_id allocator poisoned") = Box::new(ids);
+        self
+    }
+
+    /// Allocates the msg_id of the next cmd, skipping the ids still in flight
+    pub fn next_id(&self) -> u32 {
+        let mut ids = self.ids.lock().expect("msg_id allocator poisoned");
+        let routes = self.routes.lock().expect("routes poisoned");
+        next_free_id(&mut **ids, |id| routes.contains(id))
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, CtrlError> {
+        self.socket.local_addr().map_err(CtrlError::Io)
+    }
+
+    /// Sends `cmd` to a single target and waits for its typed reply
+    pub async fn request<C, A>(&self, addr: A, cmd: &C) -> Result<C::Reply, CtrlError>
+    where
+        C: Command,
+        A: ToSocketAddrs,
+    {
+        let (addr, report) = self
+            .send_many(cmd.to_msg(), &[addr])
+            .await?
+            .targets
+            .pop_first()
+            .expect("one target, one report");
+        typed_reply(addr, report.outcome.into_result(addr))
+    }
+
+    pub async fn query<A: ToSocketAddrs>(&self, addr: A) -> Result<QueryReply, CtrlError> {
+        self.request(addr, &Query).await
+    }
+
+    /// Sends `cmd` to every target, each with a fresh msg_id, resolving to the outcome of each
+    pub async fn send_many<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        targets: &[A],
+    ) -> Result<CmdReplySummary, CtrlError> {
+        let targets = targets
+            .iter()
+            .map(|a| (a, self.next_id()))
+            .collect::<Vec<_>>();
+        self.send_many_with_ids(cmd, &targets).await
+    }
+
+    /// Like `send_many` with the msg_id of each target given,
+    /// e.g. to retry with the id of a timed out attempt
+    pub async fn send_many_with_ids<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        targets: &[(A, u32)],
+    ) -> Result<CmdReplySummary, CtrlError> {
+        let span = info_span!("cmd", cmd = cmd.name());
+        self.exchange(cmd, targets).instrument(span).await
+    }
+
+    /// Drives the same `Exchange` as the blocking controller
+    async fn exchange<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        targets: &[(A, u32)],
+    ) -> Result<CmdReplySummary, CtrlError> {
+        let mut exchange = Exchange::new(cmd, self.retry)?;
+        for (addr, msg_id) in targets.iter() {
+            exchange.add_target(resolve(addr).await?, *msg_id);
+        }
+
+        // registered before sending, so that no early reply is missed
+        let mut route = self.register(&exchange.ids(), true)?;
+        loop {
+            match exchange.step()? {
+                Step::Send(datagrams) => {
+                    let mut sent = Vec::with_capacity(datagrams.len());
+                    // a target that cannot be reached does not hold up the others
+                    for (out, addr) in datagrams {
+                        let result = self.socket.send_to(&out, addr).await;
+                        sent.push(result.map(|_| Instant::now()));
+                    }
+                    exchange.sent(sent);
+                }
+                Step::Wait(timeout) => match route.recv(timeout).await {
+                    Some((data, a)) => exchange.received(&data, a),
+                    None => exchange.timed_out(),
+                },
+                Step::Done => break,
+            }
+        }
+        Ok(exchange.finish())
+    }
+
+    /// Sends `cmd` to a broadcast address, yielding the outcome of each responder as it replies.
+    ///
+    /// The stream ends once no reply has come for the timeout of the controller.
+    pub async fn broadcast<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        baddr: A,
+    ) -> Result<ReplyStream, CtrlError> {
+        self.broadcast_until(cmd, baddr, CollectUntil::default())
+            .await
+    }
+
+    /// Like `broadcast`, the stream also ends once the expected number of
+    /// devices have answered or the deadline of `until` has passed
+    pub async fn broadcast_until<A: ToSocketAddrs>(
+        &self,
+        mut cmd: CtrlMsg,
+        baddr: A,
+        until: CollectUntil,
+    ) -> Result<ReplyStream, CtrlError> {
+        cmd.validate()?;
+        let msg_id = self.next_id();
+        cmd.set_msg_id(msg_id);
+        let baddr = resolve(baddr).await?;
+        let out = encode_cmd(&cmd)?;
+
+        let span = info_span!("broadcast", cmd = cmd.name(), msg_id, addr = %baddr[0]);
+        // the stream has no room for stray replies, they are left to other requests
+        let mut route = self.register(&[msg_id], false)?;
+        self.socket
+            .send_to(&out, baddr[0])
+            .await
+            .map_err(|source| CtrlError::Send {
+                addr: baddr[0],
+                source,
+            })?;
+        let sent_at = Instant::now();
+        debug!(parent: &span, "{cmd}");
+        trace!(parent: &span, "\n{}", HexDump(&out));
+        info!(parent: &span, "sent");
+
+        let (tx, rx) = unbounded_channel();
+        let timeout = self.retry.attempt_timeout;
+        tokio::spawn(
+            async move {
+                let end = until.deadline.map(|d| sent_at + d);
+                let mut responders = BTreeSet::new();
+                while !until.is_complete(responders.len()) {
+                    let received = tokio::select! {
+                        received = route.recv(wait_time(timeout, end)) => received,
+                        // the caller dropped the stream, which may never time out
+                        () = tx.closed() => None,
+                    };
+                    let Some((data, a)) = received else {
+                        break;
+                    };
+                    dump_received(&data, a);
+                    if !responders.insert(a) {
+                        debug!(addr = %a, "stray reply");
+                        continue;
+                    }
+                    let outcome = match try_decode(&data, a) {
+                        Ok(reply) => outcome_of(&cmd, reply),
+                        Err(error) => Outcome::Undecodable { raw: data, error },
+                    };
+                    info!(addr = %a, latency = ?sent_at.elapsed(), "replied");
+                    if tx.send((a, outcome)).is_err() {
+                        break;
+                    }
+                }
+                info!(replies = responders.len(), "done");
+            }
+            .instrument(span),
+        );
+        Ok(UnboundedReceiverStream::new(rx))
+    }
+
+    /// Routes the replies carrying any of `ids` to the returned channel until it is dropped,
+    /// with the datagrams no other request is waiting for if `unrouted`
+    fn register(&self, ids: &[u32], unrouted: bool) -> Result<Route, CtrlError> {
+        let (tx, rx) = unbounded_channel();
+        let key = self
+            .routes
+            .lock()
+            .expect("routes poisoned")
+            .register(ids, tx, unrouted)?;
+        Ok(Route {
+            routes: Arc::clone(&self.routes),
+            key,
+            ids: ids.to_vec(),
+            rx,
+        })
+    }
+}
+
+impl Drop for Controller {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+async fn resolve<A: ToSocketAddrs>(addr: A) -> Result<Vec<SocketAddr>, CtrlError> {
+    let resolved = lookup_host(addr)
+        .await
+        .map_err(CtrlError::Resolve)?
+        .collect::<Vec<_>>();
+    if resolved.is_empty() {
+        return Err(CtrlError::Resolve(std::io::Error::new(
+            ErrorKind::NotFound,
+            "target resolved to no address",
+        )));
+    }
+    Ok(resolved)
+}
+
+/// Replies routed to one request, unregistered on drop
+struct Route {
+    routes: Routes,
+    key: u64,
+    ids: Vec<u32>,
+    rx: UnboundedReceiver<Datagram>,
+}
+
+impl Route {
+    /// Waits at most `timeout` for the next reply, forever if `None`
+    async fn recv(&mut self, timeout: Option<Duration>) -> Option<Datagram> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.rx.recv())
+                .await
+                .ok()
+                .flatten(),
+            None => self.rx.recv().await,
+        }
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        self.routes
+            .lock()
+            .expect("routes poisoned")
+            .unregister(self.key, &self.ids);
+    }
+}
+
+async fn receive_loop(socket: Arc<UdpSocket>, routes: Routes) {
+    let mut buf = vec![0_u8; 9000];
+    loop {
+        let (l, addr) = match socket.recv_from(&mut buf).await {
+            Ok(x) => x,
+            // ICMP port unreachable from an earlier send, not fatal for the others
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => {
+                warn!(error = %e, "receive failed");
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                continue;
+            }
+        };
+        let data = &buf[..l];
+        let msg_id = peek_msg_id(data);
+        let tx = routes
+            .lock()
+            .expect("routes poisoned")
+            .route(msg_id)
+            .cloned();
+        // a reply to a request that has already given up is as late as a stray one
+        if tx.is_none_or(|tx| tx.send((data.to_vec(), addr)).is_err()) {
+            debug!(?msg_id, %addr, "stray datagram while no request is active");
+        }
+    }
+}
@@ -1,13 +1,17 @@
 use clap::Parser;
-use sdaa_ctrl::ctrl_msg::{bcast_cmd, CtrlMsg};
-use serde_yaml::from_reader;
+use sdaa_ctrl::{
+    controller::CollectUntil,
+    ctrl_msg::{discover_cmd, load_cmds, print_bytes},
+    logging,
+};
 use std::{fs::File, time::Duration};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short = 'a', long = "addr", value_name = "<bcast_addr:port>")]
-    addr: String,
+    /// several addresses are broadcast to in turn, e.g. one per subnet
+    #[clap(short = 'a', long = "addr", num_args(1..), value_name = "<bcast_addr:port>")]
+    addr: Vec<String>,
 
     #[clap(
         short = 'L',
@@ -23,40 +27,76 @@ struct Args {
     #[clap(short = 't', value_name = "timeout in sec", default_value = "1")]
     timeout: u64,
 
+    #[clap(short = 'n', long = "expect", value_name = "number of devices expected to answer")]
+    expected: Option<usize>,
+
+    #[clap(long = "deadline", value_name = "time limit per address in sec")]
+    deadline: Option<f64>,
+
+    #[clap(
+        long = "gap",
+        value_name = "pause between two addresses in ms",
+        default_value = "0"
+    )]
+    gap: u64,
+
     #[clap(
         short = 'd',
         long = "debug",
-        value_name = "debug level",
+        value_name = "debug level, 0: sent and replied, 1: details, 2: hex dumps",
         default_value("0")
     )]
     debug_level: u32,
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let debug_level = args.debug_level;
+    logging::init(args.debug_level);
+
+    let until = CollectUntil::new(args.expected, args.deadline.map(Duration::from_secs_f64));
 
-    let cmds: Vec<CtrlMsg> = from_reader(File::open(&args.cmd).expect("file not open")).expect("failed to load cmd");
+    let cmds = load_cmds(File::open(&args.cmd)?)?;
     for c in cmds {
-        let summary = bcast_cmd(
+        let summary = discover_cmd(
             c,
             &args.addr,
             &args.local_addr,
             Some(Duration::from_secs(args.timeout)),
-            debug_level,
-        );
+            until,
+            Duration::from_millis(args.gap),
+        )?;
 
         println!("replied:");
 
-        for (a, r) in &summary.normal_reply {
+        for (a, r) in summary.ok_replies() {
             println!("{a} \n{r}");
         }
 
-        if !summary.invalid_reply.is_empty() {
+        let failed = summary.failed_targets();
+        if !failed.is_empty() {
             println!("Invalid reply:");
-            for (a, r) in summary.invalid_reply {
-                println!("{a} \n{r}");
+            for a in failed {
+                println!("{a} \n{}", summary.targets[&a].outcome);
+            }
+        }
+
+        if let Some(n) = args.expected
+            && summary.targets.len() < n
+        {
+            println!("only {} of {n} devices replied", summary.targets.len());
+        }
+
+        if let Some(n) = summary.kernel_drops.filter(|&n| n > 0) {
+            println!("{n} replies dropped by the kernel, raise the receive buffer");
+        }
+
+        if !summary.undecodable.is_empty() {
+            println!("Undecodable reply:");
+            for (a, raw, e) in summary.undecodable {
+                println!("{a} {} bytes: {e}", raw.len());
+                print_bytes(&raw);
             }
         }
     }
+    Ok(())
 }
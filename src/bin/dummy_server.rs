@@ -3,121 +3,109 @@ use clap::Parser;
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// config
+    /// config, one simulated device per address, port 0 binds a free port;
+    /// the bound addresses are printed before serving
     #[clap(short = 'a', long = "addr", num_args(1..), value_name="ip:port")]
-    addr: String,
+    addr: Vec<String>,
+
+    /// do not dump the received and sent messages
+    #[clap(short = 'q', long = "quiet")]
+    quiet: bool,
+
+    /// treat each address as a broadcast address answered by this many devices,
+    /// replying all at once from the ports following it, or from free ports
+    /// if its port is 0
+    #[clap(short = 'f', long = "fanout", value_name = "n")]
+    fanout: Option<u16>,
 }
 
-use binrw::{BinRead, BinWrite};
-use sdaa_ctrl::ctrl_msg::{
-    print_bytes,
-    CtrlMsg::{self, *},
-    Health,
+use binrw::BinWrite;
+use sdaa_ctrl::{
+    ctrl_msg::{decode_msg, print_bytes, CtrlMsg::Unknown},
+    logging,
+    sim::dummy_reply,
+};
+use std::{
+    io::Cursor,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
 };
-use std::{io::Cursor, net::UdpSocket};
 fn main() {
     let args = Args::parse();
-    let socket = UdpSocket::bind(args.addr).unwrap();
+    // the hex dumps are logged at trace level
+    if !args.quiet {
+        logging::init(2);
+    }
+    let servers = args
+        .addr
+        .iter()
+        .map(|addr| {
+            let addr: SocketAddr = addr.to_socket_addrs().unwrap().next().unwrap();
+            let socket = UdpSocket::bind(addr).unwrap();
+            let devices = (1..=args.fanout.unwrap_or(0))
+                .map(|i| UdpSocket::bind(device_addr(addr, i)).unwrap())
+                .collect::<Vec<_>>();
+            println!("listening on {}", socket.local_addr().unwrap());
+            for d in &devices {
+                println!("device {}", d.local_addr().unwrap());
+            }
+            std::thread::spawn(move || serve(socket, devices, args.quiet))
+        })
+        .collect::<Vec<_>>();
+    for s in servers {
+        s.join().unwrap();
+    }
+}
+
+/// Address of the `i`th device behind `addr`, `i` ports above it, any free
+/// port if `addr` has port 0
+fn device_addr(addr: SocketAddr, i: u16) -> SocketAddr {
+    if addr.port() == 0 {
+        return addr;
+    }
+    match addr.port().checked_add(i) {
+        Some(port) => SocketAddr::new(addr.ip(), port),
+        None => {
+            eprintln!("{addr}: no port left for device {i}, lower the port or the fanout");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Answers every request on `socket`, from each of `devices` if there are any
+fn serve(socket: UdpSocket, devices: Vec<UdpSocket>, quiet: bool) {
     socket.set_nonblocking(false).unwrap();
+    let mut buf = vec![0_u8; 9000];
     loop {
-        let mut buf = vec![0_u8; 9000];
         let (sz, addr) = socket.recv_from(&mut buf).unwrap();
-        println!("received {sz} Bytes from {addr}");
-        print_bytes(&buf[..sz]);
-        let mut cursor = Cursor::new(buf);
-        let msg = CtrlMsg::read(&mut cursor).unwrap();
-        //println!("{msg:?}");
-        println!("{msg}");
-
-        let reply = match msg {
-            Query { msg_id } => QueryReply {
-                msg_id,
-                fm_ver: 0x24122420,
-                tick_cnt1: 10,
-                tick_cnt2: 10,
-                trans_state: 0,
-                locked: 0,
-                health: Health::HLHealth {
-                    nhealth: 10,
-                    xgbe_state: [10, 10, 10, 10],
-                    pkt_sent: [0x1234, 0x1234, 0x1234, 0x1234],
-                    volt12_inner: 12000,
-                    volt12_input: 12000,
-                    vcc1v0: 120000,
-                    vcc1v8: 12000,
-                    mgtavtt1v2: 12000,
-                    mgtavtt1v0: 12000,
-                    temperatures: [40000, 30000],
-                },
-            },
-            //QueryReply { msg_id } => *msg_id = mid,
-            Sync { msg_id } => SyncReply { msg_id },
-            //SyncReply { msg_id } => *msg_id = mid,
-            XGbeCfg { msg_id, .. } => XgbeCfgReply { msg_id },
-            //XgbeCfgReply { msg_id } => *msg_id = mid,
-            I2CScan { msg_id } => I2CScanReply {
-                msg_id,
-                ndev: 4,
-                payload: vec![0x11, 0x22, 0x33, 0x44],
-            },
-            //I2CScanReply { msg_id, .. } => *msg_id = mid,
-            I2CWrite { msg_id, .. } => I2CWriteReply {
-                msg_id,
-                err_code: 0,
-            },
-            //I2CWriteReply { msg_id, .. } => *msg_id = mid,
-            I2CWriteReg { msg_id, .. } => I2CWriteRegReply {
-                msg_id,
-                err_code: 0,
-            },
-            //I2CWriteRegReply { msg_id, .. } => *msg_id = mid,
-            I2CRead { msg_id, .. } => I2CReadReply {
-                msg_id,
-                err_code: 0,
-                len: 10,
-                payload: vec![0; 10],
-            },
-            //I2CReadReply { msg_id, .. } => *msg_id = mid,
-            I2CReadReg { msg_id, .. } => I2CReadRegReply {
-                msg_id,
-                err_code: 0,
-                len: 10,
-                payload: vec![0; 10],
-            },
-            //I2CReadRegReply { msg_id, .. } => *msg_id = mid,
-            StreamStart { msg_id } => StreamStartReply { msg_id },
-            //StreamStartReply { msg_id } => *msg_id = mid,
-            StreamStop { msg_id } => StreamStopReply { msg_id },
-            //StreamStopReply { msg_id } => *msg_id = mid,
-            Init { msg_id, .. } => InitReply { msg_id },
-
-            PwrCtrl { msg_id, .. } => PwrCtrlReply { msg_id },
-
-            XGbeCfgSingle { msg_id, port_id:_, cfg:_ }=>XGbeCfgSingleReply { msg_id },
-
-            XGbeCfgQuery { msg_id }=>XGbeCfgQueryReply { msg_id, nports: 4, cfg: vec![sdaa_ctrl::ctrl_msg::XGbeCfg{
-                dst_ip:[192,168,4,10],
-                dst_mac:[0xaa,0xbb,0xcc,0xdd,0xee,0xff],
-                dst_port:3000,
-                src_ip:[192,168,10,11],
-                src_mac:[0xaa,0xbb,0xcc,0xdd,0xee,0xfe],
-                src_port:3000,
-            }; 4] },
-
-            x => {
-                let desc = "invalid".to_string().as_bytes().to_vec();
-                InvalidMsg {
-                    msg_id: x.get_msg_id(),
-                    err_code: 0,
-                    len: desc.len() as u32,
-                    description: desc,
-                }
+        if !quiet {
+            println!("received {sz} Bytes from {addr}");
+            print_bytes(&buf[..sz]);
+        }
+        let msg = match decode_msg(&buf[..sz], addr) {
+            Ok(msg) => msg,
+            Err(e) => {
+                println!("{e}, ignored");
+                continue;
             }
         };
+        //println!("{msg:?}");
+        if !quiet {
+            println!("{msg}");
+        }
+        if let Unknown { magic, .. } = msg {
+            println!("unknown magic 0x{magic:08x}, replying InvalidMsg");
+        }
+
+        let reply = dummy_reply(msg);
 
         let mut cursor = Cursor::new(Vec::new());
         reply.write(&mut cursor).unwrap();
         let buf = cursor.into_inner();
-        socket.send_to(&buf, addr).unwrap();
+        if devices.is_empty() {
+            socket.send_to(&buf, addr).unwrap();
+        }
+        for d in &devices {
+            d.send_to(&buf, addr).unwrap();
+        }
     }
 }
@@ -1,7 +1,7 @@
 use std::{net::SocketAddrV4, fs::File};
-use serde_yaml::{from_reader, to_writer};
+use serde_yaml::to_writer;
 use clap::Parser;
-use sdaa_ctrl::ctrl_msg::CtrlMsg;
+use sdaa_ctrl::ctrl_msg::{load_cmds, CtrlMsg};
 
 use pnet::datalink::{self, NetworkInterface};
 use std::net::IpAddr;
@@ -46,7 +46,7 @@ pub fn main() {
 
     println!("dest addr: {dest_addr:?}");
 
-    let cfg: Vec<CtrlMsg> = from_reader(File::open(&args.iname).expect("file not open")).expect("failed to load cfg");
+    let cfg = load_cmds(File::open(&args.iname).expect("file not open")).expect("failed to load cfg");
     if let CtrlMsg::XGbeCfgSingle { msg_id, port_id, mut cfg }=cfg[0]{
         if let Some(addr)=dest_addr{
             cfg.dst_ip.copy_from_slice(&addr.ip().octets());
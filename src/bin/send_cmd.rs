@@ -1,6 +1,12 @@
 use clap::Parser;
-use sdaa_ctrl::ctrl_msg::{self, send_cmd, CtrlMsg};
-use serde_yaml::from_reader;
+use sdaa_ctrl::{
+    ctrl_msg::{load_cmds, send_cmd},
+    health::{HealthReport, HealthThresholds},
+    logging,
+    retry::{RetryPolicy, TimeoutMode},
+    status::{DeviceStatus, StatusCriteria},
+    typed_msg::QueryReply,
+};
 use std::{fmt::Display, fs::File, time::Duration};
 
 #[derive(Parser, Debug)]
@@ -20,13 +26,34 @@ struct Args {
     #[clap(short = 'c', long = "cmd", value_name = "cmd.yaml")]
     cmd: String,
 
+    #[clap(short = 'H', long = "health", value_name = "thresholds.yaml")]
+    health_thresholds: Option<String>,
+
+    #[clap(short = 'S', long = "status", value_name = "criteria.yaml")]
+    status_criteria: Option<String>,
+
     #[clap(short = 't', value_name = "timeout in sec", default_value = "1")]
     timeout: u64,
 
+    #[clap(
+        short = 'r',
+        long = "retries",
+        value_name = "max attempts per target",
+        default_value = "1"
+    )]
+    max_attempts: u32,
+
+    #[clap(long = "deadline", value_name = "overall time limit in sec")]
+    deadline: Option<f64>,
+
+    /// count the timeout from the send instead of from the last reply
+    #[clap(short = 'w', long = "wall-clock")]
+    wall_clock: bool,
+
     #[clap(
         short = 'd',
         long = "debug",
-        value_name = "debug level",
+        value_name = "debug level, 0: sent and replied, 1: details, 2: hex dumps",
         default_value("0")
     )]
     debug_level: u32,
@@ -55,42 +82,69 @@ impl std::error::Error for MsgError {}
 
 fn main()->Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let debug_level = args.debug_level;
+    logging::init(args.debug_level);
+
+    let thresholds = match &args.health_thresholds {
+        Some(path) => HealthThresholds::from_yaml(File::open(path)?)?,
+        None => HealthThresholds::default(),
+    };
+    let criteria = match &args.status_criteria {
+        Some(path) => StatusCriteria::from_yaml(File::open(path)?)?,
+        None => StatusCriteria::default(),
+    };
 
-    let cmds: Vec<CtrlMsg> = from_reader(File::open(&args.cmd).expect("file not open")).expect("failed to load cmd");
+    let retry = RetryPolicy::new(args.max_attempts, Some(Duration::from_secs(args.timeout)))
+        .with_deadline(args.deadline.map(Duration::from_secs_f64))
+        .with_timeout_mode(if args.wall_clock {
+            TimeoutMode::WallClock
+        } else {
+            TimeoutMode::Idle
+        });
+
+    let cmds = load_cmds(File::open(&args.cmd)?)?;
     for c in cmds {
-        let summary = send_cmd(
-            c,
-            &args.addr,
-            &args.local_addr,
-            Some(Duration::from_secs(args.timeout)),
-            debug_level,
-        );
-
-        for (_a,msg) in &summary.normal_reply{
-            if let ctrl_msg::CtrlMsg::QueryReply { msg_id:_, fm_ver:_, tick_cnt1, tick_cnt2, trans_state:_, locked, health:_ }=msg.clone(){
-                println!("{}", tick_cnt2-tick_cnt1);
-                if tick_cnt2-tick_cnt1!=10_000_000 || locked&0x00_00_00_0f!=0x0f{
-                    return Err(Box::new(MsgError::StatAbnormal))
-                }
+        let summary = send_cmd(c, &args.addr, &args.local_addr, retry)?;
+
+        for (a, reply) in summary.replies_of::<QueryReply>() {
+            let status = DeviceStatus::from(&reply);
+            println!("{}", status.pps_period);
+            for r in HealthReport::new(&reply.health, &thresholds).out_of_range() {
+                println!("{a} {r}");
+            }
+            if !status.is_healthy(&criteria) {
+                return Err(Box::new(MsgError::StatAbnormal));
+            }
+        }
+
+        if !summary.stray_count.is_empty() {
+            println!("stray replies:");
+            for (addr, n) in &summary.stray_count {
+                println!("{addr} {n}");
             }
         }
-        
 
-        if summary.no_reply.is_empty() {
+        if !summary.unexpected_source.is_empty() {
+            println!("Reply from unexpected source:");
+            for (a, r) in &summary.unexpected_source {
+                println!("{a} {r}");
+            }
+        }
+
+        let no_reply = summary.timed_out_targets();
+        if no_reply.is_empty() {
             println!("all replied");
         } else {
             println!("not replied:");
-            for (addr, msg_id) in &summary.no_reply {
-                println!("{addr:?} {msg_id}");
+            for addr in &no_reply {
+                println!("{addr:?} {}", summary.targets[addr].msg_id);
             }
             return Err(Box::new(MsgError::NotAllReplied));
         }
 
-        if !summary.invalid_reply.is_empty() {
+        if !summary.all_ok() {
             println!("Invalid reply:");
-            for (a, r) in summary.invalid_reply {
-                println!("{a} {r}");
+            for a in summary.failed_targets() {
+                println!("{a} {}", summary.targets[&a].outcome);
             }
             return Err(Box::new(MsgError::HasInvalidReply));
         }
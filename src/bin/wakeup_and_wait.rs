@@ -1,6 +1,12 @@
 use clap::Parser;
-use sdaa_ctrl::ctrl_msg::{send_cmd, CtrlMsg};
-use std::time::Duration;
+use sdaa_ctrl::{
+    ctrl_msg::send_cmd,
+    logging,
+    retry::RetryPolicy,
+    status::{DeviceStatus, StatusCriteria},
+    typed_msg::{Command, PwrCtrl, Query, QueryReply},
+};
+use std::{fs::File, time::Duration};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -16,111 +22,85 @@ struct Args {
     )]
     local_addr: String,
 
+    #[clap(short = 'S', long = "status", value_name = "criteria.yaml")]
+    status_criteria: Option<String>,
+
     #[clap(short = 't', value_name = "timeout in sec", default_value = "1")]
     timeout: u64,
 
+    #[clap(
+        short = 'r',
+        long = "retries",
+        value_name = "max attempts per target",
+        default_value = "10"
+    )]
+    max_attempts: u32,
+
+    #[clap(long = "deadline", value_name = "overall time limit in sec")]
+    deadline: Option<f64>,
+
     #[clap(
         short = 'd',
         long = "debug",
-        value_name = "debug level",
+        value_name = "debug level, 0: sent and replied, 1: details, 2: hex dumps",
         default_value("0")
     )]
     debug_level: u32,
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let debug_level = args.debug_level;
+    logging::init(args.debug_level);
 
-    let cmd = CtrlMsg::PwrCtrl {
-        msg_id: 0,
-        op_code: 1,
+    let criteria = match &args.status_criteria {
+        Some(path) => StatusCriteria::from_yaml(File::open(path)?)?,
+        None => StatusCriteria::default(),
     };
-    let mut summary = send_cmd(
-        cmd,
-        &args.addr,
-        &args.local_addr,
-        Some(Duration::from_secs(args.timeout)),
-        debug_level,
-    );
-    while !summary.no_reply.is_empty() {
-        let addr = summary
-            .no_reply
-            .iter()
-            .map(|(a, _)| a[0])
-            .collect::<Vec<_>>();
-        let cmd = CtrlMsg::PwrCtrl {
-            msg_id: 0,
-            op_code: 1,
-        };
-        eprintln!("addrs {addr:?} not reply, retrying");
-        summary = send_cmd(
-            cmd,
-            &addr,
-            &args.local_addr,
-            Some(Duration::from_secs(args.timeout)),
-            debug_level,
-        );
+
+    let retry = RetryPolicy::new(args.max_attempts, Some(Duration::from_secs(args.timeout)))
+        .with_deadline(args.deadline.map(Duration::from_secs_f64));
+
+    let cmd = PwrCtrl { op_code: 1 }.to_msg();
+    let summary = send_cmd(cmd, &args.addr, &args.local_addr, retry)?;
+    let no_reply = summary.timed_out_targets();
+    if !no_reply.is_empty() {
+        println!("addrs {no_reply:?} not reply after {} attempts", args.max_attempts);
+        println!("{summary}");
+        std::process::exit(1);
     }
     eprintln!("all have replied");
     std::thread::sleep(Duration::from_secs(5));
 
-    let cmd = CtrlMsg::Query { msg_id: 0 };
-    let mut summary = send_cmd(
-        cmd,
-        &args.addr,
-        &args.local_addr,
-        Some(Duration::from_secs(args.timeout)),
-        debug_level,
-    );
-    if summary.normal_reply.len() != args.addr.len() {
+    let cmd = Query.to_msg();
+    let mut summary = send_cmd(cmd, &args.addr, &args.local_addr, retry)?;
+    if !summary.all_ok() {
         println!("some one abnormal, please check");
-        println!("{summary:?}");
+        println!("{summary}");
         std::process::exit(1);
     }
     loop {
         let addr = summary
-            .normal_reply
-            .iter()
-            .filter(|&(_a, m)| {
-                if let CtrlMsg::QueryReply {
-                    msg_id: _,
-                    fm_ver: _,
-                    tick_cnt1: _,
-                    tick_cnt2: _,
-                    trans_state: _,
-                    locked,
-                    health: _,
-                } = m
-                {
-                    !(*locked == 0x3f || *locked == 0x2f)
-                } else {
-                    false
-                }
-            })
-            .map(|&(a, _)| a)
+            .replies_of::<QueryReply>()
+            .into_iter()
+            .filter(|(_a, r)| !DeviceStatus::from(r).lock_ok(&criteria))
+            .map(|(a, _)| a)
             .collect::<Vec<_>>();
 
         if addr.is_empty() {
             break;
         }
         std::thread::sleep(Duration::from_secs(1));
-        let cmd = CtrlMsg::Query { msg_id: 0 };
-        summary = send_cmd(
-            cmd,
-            &addr,
-            &args.local_addr,
-            Some(Duration::from_secs(args.timeout)),
-            debug_level,
-        );
+        let cmd = Query.to_msg();
+        summary = send_cmd(cmd, &addr, &args.local_addr, retry)?;
+        let no_reply = summary.timed_out_targets();
+        if !no_reply.is_empty() {
+            println!("addrs {no_reply:?} not reply while waiting for lock");
+            println!("{summary}");
+            std::process::exit(1);
+        }
     }
 
-    let cmd = CtrlMsg::Query { msg_id: 0 };
-    let _ = send_cmd(
-        cmd,
-        &args.addr,
-        &args.local_addr,
-        Some(Duration::from_secs(args.timeout)),
-        debug_level,
-    );
+    let cmd = Query.to_msg();
+    send_cmd(cmd, &args.addr, &args.local_addr, retry)?;
+    Ok(())
 }
@@ -1,12 +1,183 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    slice::from_raw_parts_mut,
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_void},
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    ptr::{null, null_mut},
+    slice::{from_raw_parts, from_raw_parts_mut},
     time::Duration,
 };
 
-use crate::ctrl_msg::{bcast_cmd, send_cmd, CtrlMsg};
+use tracing::error;
 
+use crate::{
+    client::Client,
+    ctrl_msg::{
+        CmdReplySummary, CtrlError, CtrlMsg, Health, XGbeCfg, bcast_cmd, find_devices_each,
+    },
+    health::{HealthReport, HealthThresholds},
+    retry::RetryPolicy,
+    status::{DeviceInfo, DeviceStatus, FirmwareVersion, StatusCriteria},
+    typed_msg::QueryReply,
+};
+
+/// Port the devices listen on for control messages
+pub const DEFAULT_REMOTE_PORT: u16 = 3000;
+
+/// Where and how the `_cfg` functions talk to the devices
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SdaaConfig {
+    /// "ip:port" to bind, null for an ephemeral port on any address of the
+    /// device's address family
+    pub local_addr: *const c_char,
+    /// 0 for `DEFAULT_REMOTE_PORT`
+    pub remote_port: u16,
+    /// how long each attempt waits for the reply, 0 for 1 s
+    pub timeout_ms: u32,
+    /// 0 counts as 1, broadcasts are sent once
+    pub max_attempts: u32,
+}
+
+/// Default config: port 3000, any local address, 1 s timeout, a single attempt
+#[unsafe(no_mangle)]
+pub extern "C" fn sdaa_config_default() -> SdaaConfig {
+    SdaaConfig {
+        local_addr: null(),
+        remote_port: DEFAULT_REMOTE_PORT,
+        timeout_ms: 1000,
+        max_attempts: 1,
+    }
+}
+
+/// `SdaaConfig` checked and converted
+struct Settings {
+    local_addr: Option<String>,
+    remote_port: u16,
+    timeout: Duration,
+    max_attempts: u32,
+}
+
+impl Settings {
+    /// What the functions taking only a local port always used
+    fn legacy(local_port: u16, timeout: Duration) -> Self {
+        Settings {
+            local_addr: Some(format!("0.0.0.0:{local_port}")),
+            remote_port: DEFAULT_REMOTE_PORT,
+            timeout,
+            max_attempts: 1,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `cfg` is null or readable, its `local_addr` null or a null-terminated string.
+    unsafe fn from_c(cmd: &str, cfg: *const SdaaConfig) -> Result<Self, SdaaStatus> {
+        let cfg = unsafe { cfg.as_ref() }
+            .copied()
+            .unwrap_or_else(|| sdaa_config_default());
+        let local_addr = match cfg.local_addr.is_null() {
+            true => None,
+            false => match unsafe { c_str(cfg.local_addr) } {
+                Some(a) => Some(a.to_string()),
+                None => return Err(invalid(cmd, "malformed local address")),
+            },
+        };
+        Ok(Settings {
+            local_addr,
+            remote_port: match cfg.remote_port {
+                0 => DEFAULT_REMOTE_PORT,
+                port => port,
+            },
+            timeout: match cfg.timeout_ms {
+                0 => Duration::from_secs(1),
+                ms => Duration::from_millis(ms as u64),
+            },
+            max_attempts: cfg.max_attempts.max(1),
+        })
+    }
+
+    fn remote(&self, ip: IpAddr) -> SocketAddr {
+        SocketAddr::new(ip, self.remote_port)
+    }
+
+    /// The local address to bind for talking to `remote`
+    fn local(&self, remote: SocketAddr) -> String {
+        match (&self.local_addr, remote) {
+            (Some(a), _) => a.clone(),
+            (None, SocketAddr::V4(_)) => "0.0.0.0:0".to_string(),
+            (None, SocketAddr::V6(_)) => "[::]:0".to_string(),
+        }
+    }
 
+    fn client(&self, remote: SocketAddr) -> Result<Client, CtrlError> {
+        let retry = RetryPolicy::new(self.max_attempts, Some(self.timeout));
+        Ok(Client::new(self.local(remote))?.with_retry(retry))
+    }
+
+    /// Queries every device answering a broadcast to `baddr`
+    fn discover(&self, baddr: IpAddr) -> Result<CmdReplySummary, SdaaStatus> {
+        let baddr = self.remote(baddr);
+        let query = CtrlMsg::Query { msg_id: 0 };
+        bcast_cmd(query, baddr, self.local(baddr), Some(self.timeout))
+            .map_err(|e| fail("Query", e))
+    }
+
+    /// Like `discover`, handing each device to `on_device` as soon as it answers
+    fn discover_each(
+        &self,
+        baddr: IpAddr,
+        on_device: impl FnMut(DeviceInfo) -> bool,
+    ) -> Result<(), SdaaStatus> {
+        let baddr = self.remote(baddr);
+        find_devices_each(baddr, self.local(baddr), Some(self.timeout), on_device)
+            .map(|_| ())
+            .map_err(|e| fail("Query", e))
+    }
+
+    /// Inits then syncs the device at `ip`, the last error names the step that failed
+    fn make_device(&self, ip: IpAddr) -> Result<(), SdaaStatus> {
+        let addr = self.remote(ip);
+        let client = self.client(addr).map_err(|e| fail("Init", e))?;
+        client.init(addr).map_err(|e| fail("Init", e))?;
+        client.sync(addr).map_err(|e| fail("Sync", e))
+    }
+
+    fn stream_stop(&self, ip: IpAddr) -> Result<(), SdaaStatus> {
+        let addr = self.remote(ip);
+        self.client(addr)
+            .and_then(|c| c.stream_stop(addr))
+            .map_err(|e| fail("StreamStop", e))
+    }
+
+    fn stream_start(&self, ip: IpAddr) -> Result<(), SdaaStatus> {
+        let addr = self.remote(ip);
+        self.client(addr)
+            .and_then(|c| c.stream_start(addr))
+            .map_err(|e| fail("StreamStart", e))
+    }
+}
+
+/// Parses the device address and config passed to a `_cfg` function and runs `f` with them
+///
+/// # Safety
+///
+/// `ip` is null or a null-terminated string, `cfg` is null or readable.
+unsafe fn with_settings<T>(
+    cmd: &str,
+    ip: *const c_char,
+    cfg: *const SdaaConfig,
+    f: impl FnOnce(&Settings, IpAddr) -> Result<T, SdaaStatus>,
+) -> Result<T, SdaaStatus> {
+    let ip = unsafe { c_str(ip) }
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| invalid(cmd, "null or malformed ip address"))?;
+    f(&unsafe { Settings::from_c(cmd, cfg) }?, ip)
+}
+
+/// Number of devices found, unlike the other functions it returns a count: 0
+/// is also what a failure returns, `sdaa_last_error` tells them apart
+///
 /// # Safety
 ///
 /// This function should not be called before the horsemen are ready.
@@ -17,23 +188,24 @@ pub unsafe extern "C" fn find_device(
     max_n: usize,
     local_port: u16,
 ) -> usize {
+    // a slice from a null pointer is undefined behaviour, also an empty one
+    if result.is_null() {
+        invalid("Query", "null output buffer");
+        return 0;
+    }
     let result = unsafe{from_raw_parts_mut(result, max_n)};
     let ip = Ipv4Addr::from(addr);
 
-    let addr = SocketAddrV4::new(ip, 3000);
-
-    let query = CtrlMsg::Query { msg_id: 0 };
-
-    let summary = bcast_cmd(
-        query,
-        addr,
-        format!("0.0.0.0:{local_port}"),
-        Some(Duration::from_secs(1)),
-        1,
-    );
+    let Ok(summary) = Settings::legacy(local_port, Duration::from_secs(1)).discover(ip.into())
+    else {
+        return 0;
+    };
 
     let mut nresult = 0;
-    for (a, _r) in summary.normal_reply {
+    for (a, _r) in summary.ok_replies() {
+        if nresult >= max_n {
+            break;
+        }
         if let SocketAddr::V4(x) = a {
             let ip = x.ip();
             let mut r: u32 = 0;
@@ -42,83 +214,1029 @@ pub unsafe extern "C" fn find_device(
                 r += (o as u32) << (8 * (3 - i));
             }
 
+            result[nresult] = r;
             nresult += 1;
-            if nresult >= max_n {
-                break;
-            }
-            result[nresult - 1] = r;
         }
     }
     nresult
 }
 
+/// Bytes of `SdaaDeviceInfo::addr`, enough for any "ip:port"
+pub const DEVICE_ADDR_LEN: usize = 64;
+
+/// Firmware version word as `status::FirmwareVersion` reads it
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SdaaFirmware {
+    pub raw: u32,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub revision: u8,
+    /// year, month and day make a valid date
+    pub has_date: bool,
+}
+
+impl From<&FirmwareVersion> for SdaaFirmware {
+    fn from(fw: &FirmwareVersion) -> Self {
+        SdaaFirmware {
+            raw: fw.raw,
+            year: fw.year,
+            month: fw.month,
+            day: fw.day,
+            revision: fw.revision,
+            has_date: fw.date().is_some(),
+        }
+    }
+}
+
+/// Status of a QueryReply as `status::DeviceStatus` decodes it, checked against
+/// the default `StatusCriteria` and `HealthThresholds`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SdaaDeviceStatus {
+    pub firmware: SdaaFirmware,
+    /// data is being transmitted
+    pub streaming: bool,
+    /// the device is not sleeping
+    pub awake: bool,
+    /// the raw lock word
+    pub locked: u32,
+    /// lock flag of each PLL, taken from the lowest byte of the lock word
+    pub pll_locked: [bool; 8],
+    /// 10 MHz ticks between the last two pps edges
+    pub pps_period: u32,
+    /// `pps_period` minus the nominal period
+    pub pps_deviation: i64,
+    /// every PLL the criteria require is locked
+    pub lock_ok: bool,
+    /// the pps period is within the criteria
+    pub pps_ok: bool,
+    /// every health reading is within its nominal range
+    pub health_ok: bool,
+}
+
+impl SdaaDeviceStatus {
+    fn new(status: &DeviceStatus, health: &Health) -> Self {
+        let criteria = StatusCriteria::default();
+        SdaaDeviceStatus {
+            firmware: SdaaFirmware::from(&status.firmware),
+            streaming: status.streaming,
+            awake: status.awake,
+            locked: status.locked,
+            pll_locked: status.pll_locked,
+            pps_period: status.pps_period,
+            pps_deviation: status.pps_deviation,
+            lock_ok: status.lock_ok(&criteria),
+            pps_ok: status.pps_ok(&criteria),
+            health_ok: HealthReport::new(health, &HealthThresholds::default()).all_in_range(),
+        }
+    }
+}
+
+/// A device answering `find_devices_ex`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SdaaDeviceInfo {
+    /// "ip:port", null-terminated
+    pub addr: [c_char; DEVICE_ADDR_LEN],
+    /// the IPv4 address in host byte order, 0 for an IPv6 device
+    pub ipv4: u32,
+    pub port: u16,
+    pub health_kind: HealthKind,
+    pub status: SdaaDeviceStatus,
+    /// round-trip time of the Query in microseconds
+    pub latency_us: u64,
+}
+
+impl From<&DeviceInfo> for SdaaDeviceInfo {
+    fn from(d: &DeviceInfo) -> Self {
+        let mut addr = [0; DEVICE_ADDR_LEN];
+        let text = d.addr.to_string();
+        // the last byte stays the terminating nul
+        for (dst, &src) in addr
+            .iter_mut()
+            .zip(&text.as_bytes()[..text.len().min(DEVICE_ADDR_LEN - 1)])
+        {
+            *dst = src as c_char;
+        }
+        SdaaDeviceInfo {
+            addr,
+            ipv4: match d.addr {
+                SocketAddr::V4(a) => u32::from(*a.ip()),
+                SocketAddr::V6(_) => 0,
+            },
+            port: d.addr.port(),
+            health_kind: HealthKind::from(&d.health),
+            status: SdaaDeviceStatus::new(&d.status, &d.health),
+            latency_us: d.latency.map_or(0, |l| l.as_micros() as u64),
+        }
+    }
+}
+
+/// Broadcasts a Query to `bcast_ip` and describes up to `capacity` of the devices
+/// answering, in address order. `nfilled` is set to the number written to `devices`,
+/// `total` to the number of devices that answered; if that is more, the status is
+/// `BufferTooSmall` like that of `find_device_cfg`.
+///
+/// # Safety
+///
+/// `bcast_ip` is a null-terminated string, `cfg` is null or readable, `devices`
+/// holds `capacity` entries, `nfilled` and `total` are null or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn find_devices_ex(
+    bcast_ip: *const c_char,
+    cfg: *const SdaaConfig,
+    devices: *mut SdaaDeviceInfo,
+    capacity: usize,
+    nfilled: *mut usize,
+    total: *mut usize,
+) -> SdaaStatus {
+    status(unsafe {
+        with_settings("Query", bcast_ip, cfg, |settings, baddr| {
+            let found = settings.discover(baddr)?.devices();
+            if let Some(total) = total.as_mut() {
+                *total = found.len();
+            }
+            let n = found.len().min(capacity);
+            if n > 0 && devices.is_null() {
+                return Err(invalid("Query", "null devices"));
+            }
+            for (i, d) in found[..n].iter().enumerate() {
+                devices.add(i).write(d.into());
+            }
+            if let Some(nfilled) = nfilled.as_mut() {
+                *nfilled = n;
+            }
+            if n < found.len() {
+                return Err(too_small("Query", capacity, found.len()));
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Broadcasts a Query to `bcast_ip` ("192.168.1.255" or an IPv6 address) and fills
+/// `result` with the IPv4 addresses of up to `max_n` devices answering,
+/// host byte order; `n` is set to the number filled in
+///
+/// # Safety
+///
+/// `bcast_ip` is a null-terminated string, `cfg` is null or readable, `result` holds
+/// `max_n` entries and `n` is null or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn find_device_cfg(
+    bcast_ip: *const c_char,
+    cfg: *const SdaaConfig,
+    result: *mut u32,
+    max_n: usize,
+    n: *mut usize,
+) -> SdaaStatus {
+    status(unsafe {
+        with_settings("Query", bcast_ip, cfg, |settings, baddr| {
+            let found = settings
+                .discover(baddr)?
+                .ok_replies()
+                .filter_map(|(a, _)| match a {
+                    SocketAddr::V4(a) => Some(u32::from(*a.ip())),
+                    SocketAddr::V6(_) => None,
+                })
+                .collect::<Vec<_>>();
+            copy_out("Query", &found, result, max_n, n)
+        })
+    })
+}
+
+/// True on success, `sdaa_last_error` tells what failed otherwise
+///
 /// # Safety
 ///
 /// This function should not be called before the horsemen are ready.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn make_device(ip: u32, local_port: u16) -> bool {
-    let ip = Ipv4Addr::from(ip);
-    let addr = SocketAddrV4::new(ip, 3000);
+    Settings::legacy(local_port, Duration::from_secs(5))
+        .make_device(Ipv4Addr::from(ip).into())
+        .is_ok()
+}
+
+/// Inits then syncs the device at `ip`, the last error names the step that failed
+///
+/// # Safety
+///
+/// `ip` is a null-terminated string, `cfg` is null or readable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn make_device_cfg(ip: *const c_char, cfg: *const SdaaConfig) -> SdaaStatus {
+    status(unsafe { with_settings("Init", ip, cfg, |settings, ip| settings.make_device(ip)) })
+}
+
+/// True on success, `sdaa_last_error` tells what failed otherwise
+///
+/// # Safety
+///
+/// This function should not be called before the horsemen are ready.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn unmake_device(ip: u32, local_port: u16) -> bool {
+    Settings::legacy(local_port, Duration::from_secs(5))
+        .stream_stop(Ipv4Addr::from(ip).into())
+        .is_ok()
+}
+
+/// # Safety
+///
+/// `ip` is a null-terminated string, `cfg` is null or readable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn unmake_device_cfg(
+    ip: *const c_char,
+    cfg: *const SdaaConfig,
+) -> SdaaStatus {
+    status(unsafe {
+        with_settings("StreamStop", ip, cfg, |settings, ip| {
+            settings.stream_stop(ip)
+        })
+    })
+}
+
+/// True on success, `sdaa_last_error` tells what failed otherwise
+///
+/// # Safety
+///
+/// This function should not be called before the horsemen are ready.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn start_stream(ip: u32, local_port: u16) -> bool {
+    Settings::legacy(local_port, Duration::from_secs(5))
+        .stream_start(Ipv4Addr::from(ip).into())
+        .is_ok()
+}
+
+/// # Safety
+///
+/// `ip` is a null-terminated string, `cfg` is null or readable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn start_stream_cfg(ip: *const c_char, cfg: *const SdaaConfig) -> SdaaStatus {
+    status(unsafe {
+        with_settings("StreamStart", ip, cfg, |settings, ip| {
+            settings.stream_start(ip)
+        })
+    })
+}
+
+/// Called by `find_devices_cb` with each device as soon as it has answered and
+/// the `user_data` passed along; returning false stops the discovery.
+/// `device` is only valid during the call.
+pub type SdaaDeviceCallback =
+    Option<extern "C" fn(device: *const SdaaDeviceInfo, user_data: *mut c_void) -> bool>;
+
+/// Broadcasts a Query to `bcast_ip` and reports the devices to `callback` as
+/// their replies arrive, instead of after the timeout; `nreported` is set to the
+/// number of calls made
+///
+/// # Safety
+///
+/// `bcast_ip` is a null-terminated string, `cfg` is null or readable, `nreported`
+/// is null or writable; `user_data` is only passed on to `callback`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn find_devices_cb(
+    bcast_ip: *const c_char,
+    cfg: *const SdaaConfig,
+    callback: SdaaDeviceCallback,
+    user_data: *mut c_void,
+    nreported: *mut usize,
+) -> SdaaStatus {
+    let Some(callback) = callback else {
+        return invalid("Query", "null callback");
+    };
+    let mut n = 0;
+    let result = unsafe {
+        with_settings("Query", bcast_ip, cfg, |settings, baddr| {
+            settings.discover_each(baddr, |d| {
+                n += 1;
+                callback(&SdaaDeviceInfo::from(&d), user_data)
+            })
+        })
+    };
+    if let Some(nreported) = unsafe { nreported.as_mut() } {
+        *nreported = n;
+    }
+    status(result)
+}
+
+/// What an exported function returns, details of a failure are in `sdaa_last_error`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SdaaStatus {
+    Ok = 0,
+    /// a null pointer or a malformed string was passed
+    InvalidArgument,
+    /// the output buffer cannot hold the whole result, it holds what fits
+    BufferTooSmall,
+    /// the local socket could not be bound
+    Bind,
+    /// an address could not be resolved
+    Resolve,
+    /// sending or receiving failed
+    Io,
+    /// the device did not reply in time
+    Timeout,
+    /// the device answered with an InvalidMsg, its code is in `sdaa_last_error`
+    DeviceError,
+    /// an I2C transaction failed, its code is in `sdaa_last_error`
+    I2CError,
+    /// the device answered with another reply than the cmd expects
+    UnexpectedReply,
+    /// the reply could not be decoded
+    Decode,
+    /// anything else, see the message of `sdaa_last_error`
+    Internal,
+}
+
+impl From<&CtrlError> for SdaaStatus {
+    fn from(e: &CtrlError) -> Self {
+        match e {
+            CtrlError::Bind(_) => SdaaStatus::Bind,
+            CtrlError::Resolve(_) => SdaaStatus::Resolve,
+            CtrlError::Io(_) | CtrlError::Send { .. } => SdaaStatus::Io,
+            CtrlError::Timeout { .. } => SdaaStatus::Timeout,
+            CtrlError::Device { .. } => SdaaStatus::DeviceError,
+            CtrlError::I2C { .. } => SdaaStatus::I2CError,
+            CtrlError::UnexpectedReply { .. } => SdaaStatus::UnexpectedReply,
+            CtrlError::Decode { .. } => SdaaStatus::Decode,
+            CtrlError::Encode(_)
+            | CtrlError::Inconsistent { .. }
+            | CtrlError::Yaml(_)
+            | CtrlError::IdInUse { .. } => SdaaStatus::Internal,
+        }
+    }
+}
+
+/// The last failure on the calling thread.
+///
+/// The strings are null when not known and stay valid until the next failure
+/// on the same thread.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SdaaError {
+    pub status: SdaaStatus,
+    /// the cmd that failed, e.g. "Init" or "Sync"
+    pub cmd: *const c_char,
+    /// "ip:port" of the device the failure is about
+    pub addr: *const c_char,
+    /// whether `device_err_code` holds the code of an InvalidMsg or I2C reply
+    pub has_device_err_code: bool,
+    pub device_err_code: u32,
+    pub message: *const c_char,
+}
+
+struct LastError {
+    status: SdaaStatus,
+    cmd: CString,
+    addr: Option<CString>,
+    device_err_code: Option<u32>,
+    message: CString,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+}
+
+fn c_string(s: String) -> CString {
+    CString::new(s.replace('\0', "")).expect("nul bytes removed")
+}
+
+fn set_last_error(
+    status: SdaaStatus,
+    cmd: &str,
+    addr: Option<SocketAddr>,
+    device_err_code: Option<u32>,
+    message: String,
+) {
+    LAST_ERROR.with_borrow_mut(|last| {
+        *last = Some(LastError {
+            status,
+            cmd: c_string(cmd.to_string()),
+            addr: addr.map(|a| c_string(a.to_string())),
+            device_err_code,
+            message: c_string(message),
+        })
+    });
+}
+
+/// Logs and records the failure of `cmd`, returning its status
+fn fail(cmd: &str, e: CtrlError) -> SdaaStatus {
+    let status = SdaaStatus::from(&e);
+    let device_err_code = match &e {
+        CtrlError::Device { err_code, .. } => Some(u32::from(*err_code)),
+        CtrlError::I2C { err_code, .. } => Some(*err_code),
+        _ => None,
+    };
+    error!(cmd, error = %e, "failed");
+    set_last_error(
+        status,
+        cmd,
+        e.addr(),
+        device_err_code,
+        format!("{cmd}: {e}"),
+    );
+    status
+}
+
+/// Logs and records an invalid argument passed to `cmd`
+fn invalid(cmd: &str, what: &str) -> SdaaStatus {
+    error!(cmd, "{what}");
+    set_last_error(
+        SdaaStatus::InvalidArgument,
+        cmd,
+        None,
+        None,
+        format!("{cmd}: {what}"),
+    );
+    SdaaStatus::InvalidArgument
+}
+
+/// Details of the last failure on the calling thread, `status` is `Ok` if
+/// nothing failed yet
+#[unsafe(no_mangle)]
+pub extern "C" fn sdaa_last_error() -> SdaaError {
+    LAST_ERROR.with_borrow(|last| match last {
+        Some(last) => SdaaError {
+            status: last.status,
+            cmd: last.cmd.as_ptr(),
+            addr: last.addr.as_ref().map_or(null(), |a| a.as_ptr()),
+            has_device_err_code: last.device_err_code.is_some(),
+            device_err_code: last.device_err_code.unwrap_or(0),
+            message: last.message.as_ptr(),
+        },
+        None => SdaaError {
+            status: SdaaStatus::Ok,
+            cmd: null(),
+            addr: null(),
+            has_device_err_code: false,
+            device_err_code: 0,
+            message: null(),
+        },
+    })
+}
+
+/// Words of `TEHealth` payload a `QueryResult` holds, the rest is cut off
+pub const TE_HEALTH_MAX: usize = 64;
 
-    let local_addr = format!("0.0.0.0:{local_port}");
+/// Opaque handle of the C API, talking to one device from one local port
+pub struct CtrlHandle {
+    client: Client,
+    remote: SocketAddr,
+}
+
+/// Which of the health fields of a `QueryResult` the device filled in
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HealthKind {
+    #[default]
+    Hl = 0,
+    Te = 1,
+    T510 = 2,
+}
+
+/// Reply to a Query
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct QueryResult {
+    pub fm_ver: u32,
+    pub tick_cnt1: u32,
+    pub tick_cnt2: u32,
+    pub trans_state: u32,
+    pub locked: u32,
+    pub health_kind: HealthKind,
+    /// number of health words, for `Hl` and `Te`
+    pub nhealth: u32,
+    /// `Hl` only
+    pub xgbe_state: [u32; 4],
+    pub pkt_sent: [u64; 4],
+    pub volt12_inner: u32,
+    pub volt12_input: u32,
+    pub vcc1v0: u32,
+    pub vcc1v8: u32,
+    pub mgtavtt1v2: u32,
+    pub mgtavtt1v0: u32,
+    pub temperatures: [u32; 2],
+    /// `Te` only, the first `min(nhealth, TE_HEALTH_MAX)` words
+    pub te_payload: [u32; TE_HEALTH_MAX],
+    /// `T510` only
+    pub rfdc_restart_cnt: u32,
+    pub temperature: i32,
+    /// the fields above decoded and checked
+    pub status: SdaaDeviceStatus,
+}
+
+impl From<&Health> for HealthKind {
+    fn from(health: &Health) -> Self {
+        match health {
+            Health::HLHealth { .. } => HealthKind::Hl,
+            Health::TEHealth { .. } => HealthKind::Te,
+            Health::T510Health { .. } => HealthKind::T510,
+        }
+    }
+}
+
+impl Default for QueryResult {
+    fn default() -> Self {
+        QueryResult {
+            fm_ver: 0,
+            tick_cnt1: 0,
+            tick_cnt2: 0,
+            trans_state: 0,
+            locked: 0,
+            health_kind: HealthKind::default(),
+            nhealth: 0,
+            xgbe_state: [0; 4],
+            pkt_sent: [0; 4],
+            volt12_inner: 0,
+            volt12_input: 0,
+            vcc1v0: 0,
+            vcc1v8: 0,
+            mgtavtt1v2: 0,
+            mgtavtt1v0: 0,
+            temperatures: [0; 2],
+            te_payload: [0; TE_HEALTH_MAX],
+            rfdc_restart_cnt: 0,
+            temperature: 0,
+            status: SdaaDeviceStatus::default(),
+        }
+    }
+}
+
+impl From<QueryReply> for QueryResult {
+    fn from(reply: QueryReply) -> Self {
+        let mut result = QueryResult {
+            fm_ver: reply.fm_ver,
+            tick_cnt1: reply.tick_cnt1,
+            tick_cnt2: reply.tick_cnt2,
+            trans_state: reply.trans_state,
+            locked: reply.locked,
+            status: SdaaDeviceStatus::new(&DeviceStatus::from(&reply), &reply.health),
+            ..Default::default()
+        };
+        match reply.health {
+            Health::HLHealth {
+                nhealth,
+                xgbe_state,
+                pkt_sent,
+                volt12_inner,
+                volt12_input,
+                vcc1v0,
+                vcc1v8,
+                mgtavtt1v2,
+                mgtavtt1v0,
+                temperatures,
+            } => {
+                result.health_kind = HealthKind::Hl;
+                result.nhealth = nhealth;
+                result.xgbe_state = xgbe_state;
+                result.pkt_sent = pkt_sent;
+                result.volt12_inner = volt12_inner;
+                result.volt12_input = volt12_input;
+                result.vcc1v0 = vcc1v0;
+                result.vcc1v8 = vcc1v8;
+                result.mgtavtt1v2 = mgtavtt1v2;
+                result.mgtavtt1v0 = mgtavtt1v0;
+                result.temperatures = temperatures;
+            }
+            Health::TEHealth { nhealth, payload } => {
+                result.health_kind = HealthKind::Te;
+                result.nhealth = nhealth;
+                let n = payload.len().min(TE_HEALTH_MAX);
+                result.te_payload[..n].copy_from_slice(&payload[..n]);
+            }
+            Health::T510Health {
+                rfdc_restart_cnt,
+                temperature,
+            } => {
+                result.health_kind = HealthKind::T510;
+                result.rfdc_restart_cnt = rfdc_restart_cnt;
+                result.temperature = temperature;
+            }
+        }
+        result
+    }
+}
 
-    let cmd = CtrlMsg::Init {
-        msg_id: 0,
-        reserved_zeros: 0,
+/// Runs `cmd` on the device of the handle, recording why it failed
+///
+/// # Safety
+///
+/// `handle` is null or was returned by `sdaa_ctrl_new` and not yet freed.
+unsafe fn request<T>(
+    handle: *const CtrlHandle,
+    cmd: &str,
+    f: impl FnOnce(&Client, SocketAddr) -> Result<T, CtrlError>,
+) -> Result<T, SdaaStatus> {
+    let Some(h) = (unsafe { handle.as_ref() }) else {
+        return Err(invalid(cmd, "null handle"));
     };
-    let summary = send_cmd(cmd, &[addr], &local_addr, Some(Duration::from_secs(5)), 1);
+    f(&h.client, h.remote).map_err(|e| fail(cmd, e))
+}
 
-    println!("{summary:?}");
+fn status(r: Result<(), SdaaStatus>) -> SdaaStatus {
+    r.err().unwrap_or(SdaaStatus::Ok)
+}
 
-    // if summary.normal_reply.len() != 1 {
-    //     return false;
-    // }
-    let cmd = CtrlMsg::Sync { msg_id: 0 };
-    let _summary = send_cmd(cmd, &[addr], local_addr, Some(Duration::from_secs(5)), 1);
+/// Copies `data` into the buffer of `capacity` elements at `out`, storing the number
+/// copied in `len`
+///
+/// # Safety
+///
+/// `out` points to `capacity` writable elements, `len` is null or writable.
+unsafe fn copy_out<T: Copy>(
+    cmd: &str,
+    data: &[T],
+    out: *mut T,
+    capacity: usize,
+    len: *mut usize,
+) -> Result<(), SdaaStatus> {
+    let n = data.len().min(capacity);
+    if n > 0 {
+        if out.is_null() {
+            return Err(invalid(cmd, "null output buffer"));
+        }
+        unsafe { from_raw_parts_mut(out, n) }.copy_from_slice(&data[..n]);
+    }
+    if let Some(len) = unsafe { len.as_mut() } {
+        *len = n;
+    }
+    if n < data.len() {
+        return Err(too_small(cmd, capacity, data.len()));
+    }
+    Ok(())
+}
 
-    // if summary.normal_reply.len() != 1 {
-    //     return false;
-    // }
+/// Logs and records that `needed` items of `cmd` did not fit in `capacity`
+fn too_small(cmd: &str, capacity: usize, needed: usize) -> SdaaStatus {
+    error!(cmd, capacity, needed, "output buffer too small");
+    set_last_error(
+        SdaaStatus::BufferTooSmall,
+        cmd,
+        None,
+        None,
+        format!("{cmd}: {needed} items do not fit in {capacity}"),
+    );
+    SdaaStatus::BufferTooSmall
+}
 
-    true
+/// Creates a handle talking to the device at `remote_addr` ("ip:port") from
+/// `local_addr` ("ip:port"), each attempt waiting `timeout_ms` (0 for 1 s) for
+/// the reply and making up to `max_attempts` attempts
+///
+/// # Safety
+///
+/// Both addresses are null-terminated strings, `handle` is writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_new(
+    local_addr: *const c_char,
+    remote_addr: *const c_char,
+    timeout_ms: u32,
+    max_attempts: u32,
+    handle: *mut *mut CtrlHandle,
+) -> SdaaStatus {
+    const CMD: &str = "sdaa_ctrl_new";
+    let Some(handle) = (unsafe { handle.as_mut() }) else {
+        return invalid(CMD, "null handle");
+    };
+    *handle = null_mut();
+    let (Some(local_addr), Some(remote_addr)) =
+        (unsafe { c_str(local_addr) }, unsafe { c_str(remote_addr) })
+    else {
+        return invalid(CMD, "null or malformed address string");
+    };
+    let remote = match remote_addr.to_socket_addrs().map(|mut a| a.next()) {
+        Ok(Some(remote)) => remote,
+        Ok(None) => {
+            let e =
+                std::io::Error::new(ErrorKind::NotFound, format!("no address for {remote_addr}"));
+            return fail(CMD, CtrlError::Resolve(e));
+        }
+        Err(e) => return fail(CMD, CtrlError::Resolve(e)),
+    };
+    let timeout = match timeout_ms {
+        0 => Duration::from_secs(1),
+        ms => Duration::from_millis(ms as u64),
+    };
+    match Client::new(local_addr) {
+        Ok(client) => {
+            *handle = Box::into_raw(Box::new(CtrlHandle {
+                client: client.with_retry(RetryPolicy::new(max_attempts.max(1), Some(timeout))),
+                remote,
+            }));
+            SdaaStatus::Ok
+        }
+        Err(e) => fail(CMD, e),
+    }
 }
 
+/// Releases the handle and its local port
+///
 /// # Safety
 ///
-/// This function should not be called before the horsemen are ready.
+/// `handle` is null or was returned by `sdaa_ctrl_new` and is not used afterwards.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn unmake_device(ip: u32, local_port: u16) -> bool {
-    let ip = Ipv4Addr::from(ip);
-    let addr = SocketAddrV4::new(ip, 3000);
+pub unsafe extern "C" fn sdaa_ctrl_free(handle: *mut CtrlHandle) {
+    if !handle.is_null() {
+        drop(unsafe { Box::from_raw(handle) });
+    }
+}
 
-    let local_addr = format!("0.0.0.0:{local_port}");
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `result` is writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_query(
+    handle: *const CtrlHandle,
+    result: *mut QueryResult,
+) -> SdaaStatus {
+    let Some(result) = (unsafe { result.as_mut() }) else {
+        return invalid("Query", "null result");
+    };
+    status(
+        unsafe { request(handle, "Query", |c, a| c.query(a)) }.map(|reply| *result = reply.into()),
+    )
+}
 
-    let cmd = CtrlMsg::StreamStop { msg_id: 0 };
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_sync(handle: *const CtrlHandle) -> SdaaStatus {
+    status(unsafe { request(handle, "Sync", |c, a| c.sync(a)) })
+}
 
-    let summary = send_cmd(cmd, &[addr], &local_addr, Some(Duration::from_secs(5)), 1);
-    if summary.normal_reply.len() != 1 {
-        return false;
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_init(handle: *const CtrlHandle) -> SdaaStatus {
+    status(unsafe { request(handle, "Init", |c, a| c.init(a)) })
+}
+
+/// Configures all 4 XGbe ports
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `cfg` points to 4 entries.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_xgbe_cfg(
+    handle: *const CtrlHandle,
+    cfg: *const XGbeCfg,
+) -> SdaaStatus {
+    if cfg.is_null() {
+        return invalid("XGbeCfg", "null cfg");
     }
+    let cfg: [XGbeCfg; 4] = unsafe { from_raw_parts(cfg, 4) }
+        .try_into()
+        .expect("4 entries");
+    status(unsafe { request(handle, "XGbeCfg", |c, a| c.xgbe_cfg(a, cfg)) })
+}
 
-    true
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `cfg` is readable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_xgbe_cfg_single(
+    handle: *const CtrlHandle,
+    port_id: u32,
+    cfg: *const XGbeCfg,
+) -> SdaaStatus {
+    let Some(&cfg) = (unsafe { cfg.as_ref() }) else {
+        return invalid("XGbeCfgSingle", "null cfg");
+    };
+    status(unsafe {
+        request(handle, "XGbeCfgSingle", |c, a| {
+            c.xgbe_cfg_single(a, port_id, cfg)
+        })
+    })
 }
 
+/// Reads the configuration of the XGbe ports into `cfg`, `nports` is set to the number of ports
+///
 /// # Safety
 ///
-/// This function should not be called before the horsemen are ready.
+/// `handle` comes from `sdaa_ctrl_new`, `cfg` holds `capacity` entries, `nports` is writable.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn start_stream(ip: u32, local_port: u16) -> bool {
-    let ip = Ipv4Addr::from(ip);
-    let addr = SocketAddrV4::new(ip, 3000);
+pub unsafe extern "C" fn sdaa_ctrl_xgbe_cfg_query(
+    handle: *const CtrlHandle,
+    cfg: *mut XGbeCfg,
+    capacity: usize,
+    nports: *mut usize,
+) -> SdaaStatus {
+    status(
+        unsafe { request(handle, "XGbeCfgQuery", |c, a| c.xgbe_cfg_query(a)) }
+            .and_then(|x| unsafe { copy_out("XGbeCfgQuery", &x, cfg, capacity, nports) }),
+    )
+}
 
-    let local_addr = format!("0.0.0.0:{local_port}");
+/// Addresses of the devices on the I2C bus, `ndev` is set to their number
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `devices` holds `capacity` bytes, `ndev` is writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_i2c_scan(
+    handle: *const CtrlHandle,
+    devices: *mut u8,
+    capacity: usize,
+    ndev: *mut usize,
+) -> SdaaStatus {
+    status(
+        unsafe { request(handle, "I2CScan", |c, a| c.i2c_scan(a)) }
+            .and_then(|x| unsafe { copy_out("I2CScan", &x, devices, capacity, ndev) }),
+    )
+}
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `data` holds `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_i2c_write(
+    handle: *const CtrlHandle,
+    dev_addr: u32,
+    data: *const u8,
+    len: usize,
+) -> SdaaStatus {
+    let Some(data) = (unsafe { c_slice(data, len) }) else {
+        return invalid("I2CWrite", "null data");
+    };
+    status(unsafe { request(handle, "I2CWrite", |c, a| c.i2c_write(a, dev_addr, data)) })
+}
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `data` holds `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_i2c_write_reg(
+    handle: *const CtrlHandle,
+    dev_addr: u32,
+    reg_addr: u32,
+    data: *const u8,
+    len: usize,
+) -> SdaaStatus {
+    let Some(data) = (unsafe { c_slice(data, len) }) else {
+        return invalid("I2CWriteReg", "null data");
+    };
+    status(unsafe {
+        request(handle, "I2CWriteReg", |c, a| {
+            c.i2c_write_reg(a, dev_addr, reg_addr, data)
+        })
+    })
+}
+
+/// Reads `nbytes` bytes into `buf`, `len` is set to the number the device returned
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `buf` holds `nbytes` bytes, `len` is null or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_i2c_read(
+    handle: *const CtrlHandle,
+    dev_addr: u32,
+    buf: *mut u8,
+    nbytes: usize,
+    len: *mut usize,
+) -> SdaaStatus {
+    status(
+        unsafe {
+            request(handle, "I2CRead", |c, a| {
+                c.i2c_read(a, dev_addr, nbytes as u32)
+            })
+        }
+        .and_then(|x| unsafe { copy_out("I2CRead", &x, buf, nbytes, len) }),
+    )
+}
+
+/// Reads `nbytes` bytes from register `reg_addr` into `buf`, `len` is set to the
+/// number the device returned
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `buf` holds `nbytes` bytes, `len` is null or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_i2c_read_reg(
+    handle: *const CtrlHandle,
+    dev_addr: u32,
+    reg_addr: u32,
+    buf: *mut u8,
+    nbytes: usize,
+    len: *mut usize,
+) -> SdaaStatus {
+    status(
+        unsafe {
+            request(handle, "I2CReadReg", |c, a| {
+                c.i2c_read_reg(a, dev_addr, reg_addr, nbytes as u32)
+            })
+        }
+        .and_then(|x| unsafe { copy_out("I2CReadReg", &x, buf, nbytes, len) }),
+    )
+}
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_stream_start(handle: *const CtrlHandle) -> SdaaStatus {
+    status(unsafe { request(handle, "StreamStart", |c, a| c.stream_start(a)) })
+}
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_stream_stop(handle: *const CtrlHandle) -> SdaaStatus {
+    status(unsafe { request(handle, "StreamStop", |c, a| c.stream_stop(a)) })
+}
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_bit_shift(
+    handle: *const CtrlHandle,
+    shift_bits: u32,
+) -> SdaaStatus {
+    status(unsafe { request(handle, "BitShift", |c, a| c.bit_shift(a, shift_bits)) })
+}
+
+/// op_code 1 wakes the device up, 0 puts it to sleep
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_pwr_ctrl(handle: *const CtrlHandle, op_code: u32) -> SdaaStatus {
+    status(unsafe { request(handle, "PwrCtrl", |c, a| c.pwr_ctrl(a, op_code)) })
+}
 
-    let cmd = CtrlMsg::StreamStart { msg_id: 0 };
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_sleep(handle: *const CtrlHandle) -> SdaaStatus {
+    unsafe { sdaa_ctrl_pwr_ctrl(handle, 0) }
+}
 
-    let summary = send_cmd(cmd, &[addr], &local_addr, Some(Duration::from_secs(5)), 1);
-    if summary.normal_reply.len() != 1 {
-        return false;
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_wakeup(handle: *const CtrlHandle) -> SdaaStatus {
+    unsafe { sdaa_ctrl_pwr_ctrl(handle, 1) }
+}
+
+/// Selects the clock and pps sources, `clk_state` is set to what the device reports
+///
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`, `clk_state` is null or writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_set_clk(
+    handle: *const CtrlHandle,
+    clk_src: u32,
+    pps_src: u32,
+    clk_state: *mut u32,
+) -> SdaaStatus {
+    status(
+        unsafe { request(handle, "SetClk", |c, a| c.set_clk(a, clk_src, pps_src)) }.map(|state| {
+            if let Some(out) = unsafe { clk_state.as_mut() } {
+                *out = state;
+            }
+        }),
+    )
+}
+
+/// # Safety
+///
+/// `handle` comes from `sdaa_ctrl_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sdaa_ctrl_mixer_set(
+    handle: *const CtrlHandle,
+    freq: f64,
+    phase: f64,
+    sync: u32,
+) -> SdaaStatus {
+    status(unsafe { request(handle, "MixerSet", |c, a| c.mixer_set(a, freq, phase, sync)) })
+}
+
+/// # Safety
+///
+/// `s` is null or a null-terminated string.
+unsafe fn c_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
     }
+    unsafe { CStr::from_ptr(s) }.to_str().ok()
+}
 
-    true
+/// # Safety
+///
+/// `data` holds `len` bytes, it may be null if `len` is 0.
+unsafe fn c_slice<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match len {
+        0 => Some(&[]),
+        _ if data.is_null() => None,
+        _ => Some(unsafe { from_raw_parts(data, len) }),
+    }
 }
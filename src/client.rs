@@ -0,0 +1,291 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    controller::Controller,
+    ctrl_msg::{CtrlError, CtrlMsg, XGbeCfg},
+    msg_id::MsgIdAllocator,
+    retry::RetryPolicy,
+    typed_msg::{
+        BitShift, Command, I2CRead, I2CReadReg, I2CScan, I2CWrite, I2CWriteReg, Init, MixerSet,
+        PwrCtrl, Query, QueryReply, Reply, SetClk, StreamStart, StreamStop, Sync, XGbeCfgAll,
+        XGbeCfgQuery, XGbeCfgSingle,
+    },
+};
+
+/// Typed reply or error of each target
+pub type TypedReplies<R> = Vec<(SocketAddr, Result<R, CtrlError>)>;
+
+/// Blocking client sending typed commands and returning typed replies
+#[derive(Clone, Debug)]
+pub struct Client {
+    /// shared by the clones of this client, together with its socket and msg_id allocator
+    ctrl: Arc<Controller>,
+    retry: RetryPolicy,
+}
+
+impl Client {
+    /// Binds `local_addr` for the lifetime of the client and its clones
+    pub fn new<B: ToSocketAddrs>(local_addr: B) -> Result<Self, CtrlError> {
+        Ok(Client::with_controller(Arc::new(Controller::bind(local_addr)?)))
+    }
+
+    /// A client issuing its requests through an existing controller
+    pub fn with_controller(ctrl: Arc<Controller>) -> Self {
+        Client {
+            ctrl,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.retry.attempt_timeout = timeout;
+        self
+    }
+
+    /// How unanswered targets are retried, a single attempt by default
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Replaces the allocator of the underlying controller, which all clones share
+    pub fn with_id_allocator<I: MsgIdAllocator + 'static>(self, ids: I) -> Self {
+        self.ctrl.set_id_allocator(ids);
+        self
+    }
+
+    /// Allocates the msg_id of the next cmd
+    pub fn next_id(&self) -> u32 {
+        self.ctrl.next_id()
+    }
+
+    pub fn controller(&self) -> &Arc<Controller> {
+        &self.ctrl
+    }
+
+    /// Sends `cmd` to every target, returning the typed reply or error of each
+    pub fn request_many<C, A>(
+        &self,
+        targets: &[A],
+        cmd: &C,
+    ) -> Result<TypedReplies<C::Reply>, CtrlError>
+    where
+        C: Command,
+        A: ToSocketAddrs,
+    {
+        let targets = targets
+            .iter()
+            .map(|a| (a, self.next_id()))
+            .collect::<Vec<_>>();
+        self.request_with_ids(&targets, cmd)
+    }
+
+    /// Like `request_many` with the msg_id of each target given,
+    /// e.g. to retry with the id of a timed out attempt
+    pub fn request_with_ids<C, A>(
+        &self,
+        targets: &[(A, u32)],
+        cmd: &C,
+    ) -> Result<TypedReplies<C::Reply>, CtrlError>
+    where
+        C: Command,
+        A: ToSocketAddrs,
+    {
+        let summary = self
+            .ctrl
+            .exchange(cmd.to_msg(), targets, &self.retry)?;
+        Ok(summary
+            .targets
+            .into_iter()
+            .map(|(addr, report)| {
+                (
+                    addr,
+                    typed_reply::<C::Reply>(addr, report.outcome.into_result(addr)),
+                )
+            })
+            .collect())
+    }
+
+    /// Sends `cmd` to a single target and waits for its typed reply
+    pub fn request<C, A>(&self, addr: A, cmd: &C) -> Result<C::Reply, CtrlError>
+    where
+        C: Command,
+        A: ToSocketAddrs,
+    {
+        self.request_many(&[addr], cmd)?
+            .pop()
+            .map(|(_, r)| r)
+            .expect("one target, one report")
+    }
+
+    pub fn query<A: ToSocketAddrs>(&self, addr: A) -> Result<QueryReply, CtrlError> {
+        self.request(addr, &Query)
+    }
+
+    pub fn sync<A: ToSocketAddrs>(&self, addr: A) -> Result<(), CtrlError> {
+        self.request(addr, &Sync).map(|_| ())
+    }
+
+    pub fn init<A: ToSocketAddrs>(&self, addr: A) -> Result<(), CtrlError> {
+        self.request(addr, &Init).map(|_| ())
+    }
+
+    pub fn xgbe_cfg<A: ToSocketAddrs>(&self, addr: A, cfg: [XGbeCfg; 4]) -> Result<(), CtrlError> {
+        self.request(addr, &XGbeCfgAll { cfg }).map(|_| ())
+    }
+
+    pub fn xgbe_cfg_single<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        port_id: u32,
+        cfg: XGbeCfg,
+    ) -> Result<(), CtrlError> {
+        self.request(addr, &XGbeCfgSingle { port_id, cfg })
+            .map(|_| ())
+    }
+
+    pub fn xgbe_cfg_query<A: ToSocketAddrs>(&self, addr: A) -> Result<Vec<XGbeCfg>, CtrlError> {
+        self.request(addr, &XGbeCfgQuery).map(|r| r.cfg)
+    }
+
+    /// Addresses of the devices found on the I2C bus
+    pub fn i2c_scan<A: ToSocketAddrs>(&self, addr: A) -> Result<Vec<u8>, CtrlError> {
+        self.request(addr, &I2CScan).map(|r| r.devices)
+    }
+
+    pub fn i2c_write<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        dev_addr: u32,
+        payload: &[u8],
+    ) -> Result<(), CtrlError> {
+        let (addr, reply) = self.request_resolved(
+            addr,
+            &I2CWrite {
+                dev_addr,
+                payload: payload.to_vec(),
+            },
+        )?;
+        i2c_checked(addr, reply.err_code, ())
+    }
+
+    pub fn i2c_write_reg<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        dev_addr: u32,
+        reg_addr: u32,
+        payload: &[u8],
+    ) -> Result<(), CtrlError> {
+        let (addr, reply) = self.request_resolved(
+            addr,
+            &I2CWriteReg {
+                dev_addr,
+                reg_addr,
+                payload: payload.to_vec(),
+            },
+        )?;
+        i2c_checked(addr, reply.err_code, ())
+    }
+
+    pub fn i2c_read<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        dev_addr: u32,
+        nbytes: u32,
+    ) -> Result<Vec<u8>, CtrlError> {
+        let (addr, reply) = self.request_resolved(addr, &I2CRead { dev_addr, nbytes })?;
+        i2c_checked(addr, reply.err_code, reply.payload)
+    }
+
+    pub fn i2c_read_reg<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        dev_addr: u32,
+        reg_addr: u32,
+        nbytes: u32,
+    ) -> Result<Vec<u8>, CtrlError> {
+        let (addr, reply) = self.request_resolved(
+            addr,
+            &I2CReadReg {
+                dev_addr,
+                reg_addr,
+                nbytes,
+            },
+        )?;
+        i2c_checked(addr, reply.err_code, reply.payload)
+    }
+
+    pub fn stream_start<A: ToSocketAddrs>(&self, addr: A) -> Result<(), CtrlError> {
+        self.request(addr, &StreamStart).map(|_| ())
+    }
+
+    pub fn stream_stop<A: ToSocketAddrs>(&self, addr: A) -> Result<(), CtrlError> {
+        self.request(addr, &StreamStop).map(|_| ())
+    }
+
+    pub fn bit_shift<A: ToSocketAddrs>(&self, addr: A, shift_bits: u32) -> Result<(), CtrlError> {
+        self.request(addr, &BitShift { shift_bits }).map(|_| ())
+    }
+
+    pub fn pwr_ctrl<A: ToSocketAddrs>(&self, addr: A, op_code: u32) -> Result<(), CtrlError> {
+        self.request(addr, &PwrCtrl { op_code }).map(|_| ())
+    }
+
+    /// Returns the clk_state reported by the device
+    pub fn set_clk<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        clk_src: u32,
+        pps_src: u32,
+    ) -> Result<u32, CtrlError> {
+        self.request(addr, &SetClk { clk_src, pps_src })
+            .map(|r| r.clk_state)
+    }
+
+    pub fn mixer_set<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        freq: f64,
+        phase: f64,
+        sync: u32,
+    ) -> Result<(), CtrlError> {
+        self.request(addr, &MixerSet { freq, phase, sync })
+            .map(|_| ())
+    }
+
+    /// Like `request`, also returning the address the reply came from
+    fn request_resolved<C, A>(&self, addr: A, cmd: &C) -> Result<(SocketAddr, C::Reply), CtrlError>
+    where
+        C: Command,
+        A: ToSocketAddrs,
+    {
+        let (addr, reply) = self
+            .request_many(&[addr], cmd)?
+            .pop()
+            .expect("one target, one report");
+        reply.map(|r| (addr, r))
+    }
+}
+
+pub(crate) fn typed_reply<R: Reply>(
+    addr: SocketAddr,
+    reply: Result<CtrlMsg, CtrlError>,
+) -> Result<R, CtrlError> {
+    let reply = reply?;
+    R::from_msg(&reply).ok_or_else(|| CtrlError::UnexpectedReply {
+        addr,
+        reply: Box::new(reply),
+    })
+}
+
+fn i2c_checked<T>(addr: SocketAddr, err_code: u32, value: T) -> Result<T, CtrlError> {
+    if err_code == 0 {
+        Ok(value)
+    } else {
+        Err(CtrlError::I2C { addr, err_code })
+    }
+}
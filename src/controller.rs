@@ -0,0 +1,476 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, channel},
+    },
+    time::{Duration, Instant},
+};
+
+use socket2::SockRef;
+use tracing::{debug, info, info_span, trace, warn};
+
+use crate::{
+    ctrl_msg::{
+        CmdReplySummary, CtrlError, CtrlMsg, TargetReport, dump_received, encode_cmd, outcome_of,
+        try_decode,
+    },
+    logging::HexDump,
+    msg_id::{MsgIdAllocator, SequentialIds, next_free_id},
+    reactor::{Datagram, Pump, Routes, wait},
+    retry::{Exchange, RetryPolicy, Step, wait_time},
+    transport::{Transport, UdpTransport},
+};
+
+/// Options applied to the socket when the controller binds it
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    /// allow sending to broadcast addresses
+    pub broadcast: bool,
+    /// SO_RCVBUF in bytes, `None` keeps the system default
+    pub recv_buf_size: Option<usize>,
+    /// SO_SNDBUF in bytes, `None` keeps the system default
+    pub send_buf_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            broadcast: true,
+            recv_buf_size: None,
+            send_buf_size: None,
+        }
+    }
+}
+
+/// Receive buffer reserved per device answering a broadcast at once,
+/// the kernel accounts far more than the payload of a small datagram
+const RECV_BUF_PER_DEVICE: usize = 2048;
+
+/// Smallest receive buffer of a socket sized for discovery
+const MIN_DISCOVERY_RECV_BUF: usize = 1 << 20;
+
+impl SocketOptions {
+    /// Default options with a receive buffer large enough for `devices` replies arriving at once
+    pub fn for_discovery(devices: usize) -> Self {
+        SocketOptions {
+            recv_buf_size: Some((devices * RECV_BUF_PER_DEVICE).max(MIN_DISCOVERY_RECV_BUF)),
+            ..Default::default()
+        }
+    }
+}
+
+/// When a broadcast stops collecting replies, besides the idle timeout of the controller
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CollectUntil {
+    /// number of devices expected to answer, the broadcast completes as soon as all have
+    pub expected: Option<usize>,
+    /// wall-clock limit counted from the send
+    pub deadline: Option<Duration>,
+}
+
+impl CollectUntil {
+    pub fn new(expected: Option<usize>, deadline: Option<Duration>) -> Self {
+        CollectUntil { expected, deadline }
+    }
+
+    pub(crate) fn is_complete(&self, replies: usize) -> bool {
+        self.expected.is_some_and(|n| replies >= n)
+    }
+}
+
+/// Long-lived session owning the local socket, or any other `Transport`.
+///
+/// The cmds go out from the caller's thread, an I/O thread hands every received
+/// datagram to the request waiting for its msg_id, so requests issued in quick
+/// succession or from several threads share the socket without racing for the
+/// local port.
+/// Late replies and other unroutable datagrams go to the newest request, whose
+/// summary reports them as stray or undecodable.
+#[derive(Debug)]
+pub struct Controller {
+    io: Pump,
+    routes: Routes,
+    ids: Mutex<Box<dyn MsgIdAllocator>>,
+    retry: RetryPolicy,
+}
+
+impl Controller {
+    /// Binds `local_addr` with the default socket options
+    pub fn bind<B: ToSocketAddrs>(local_addr: B) -> Result<Self, CtrlError> {
+        Controller::bind_with_options(local_addr, SocketOptions::default())
+    }
+
+    pub fn bind_with_options<B: ToSocketAddrs>(
+        local_addr: B,
+        options: SocketOptions,
+    ) -> Result<Self, CtrlError> {
+        let socket = UdpSocket::bind(local_addr).map_err(CtrlError::Bind)?;
+        socket
+            .set_broadcast(options.broadcast)
+            .map_err(CtrlError::Bind)?;
+        let sock_ref = SockRef::from(&socket);
+        if let Some(size) = options.recv_buf_size {
+            sock_ref
+                .set_recv_buffer_size(size)
+                .map_err(CtrlError::Bind)?;
+            // silently capped at net.core.rmem_max
+            let granted = sock_ref.recv_buffer_size().map_err(CtrlError::Bind)?;
+            if granted < size {
+                warn!(
+                    requested = size,
+                    granted, "SO_RCVBUF capped, raise net.core.rmem_max"
+                );
+            }
+        }
+        if let Some(size) = options.send_buf_size {
+            sock_ref
+                .set_send_buffer_size(size)
+                .map_err(CtrlError::Bind)?;
+        }
+
+        Controller::with_transport(Arc::new(UdpTransport::from(socket)))
+    }
+
+    /// Runs over any transport instead of a UDP socket of its own,
+    /// e.g. a `Loopback` to a simulated device
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> Result<Self, CtrlError> {
+        Controller::with_transport(Arc::new(transport))
+    }
+
+    fn with_transport(transport: Arc<dyn Transport>) -> Result<Self, CtrlError> {
+        let routes = Routes::default();
+        let io = Pump::spawn(transport, routes.clone()).map_err(CtrlError::Bind)?;
+        Ok(Controller {
+            io,
+            routes,
+            ids: Mutex::new(Box::new(SequentialIds::with_random_prefix())),
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// How long to wait for the next reply before giving up, `None` waits forever
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.retry.attempt_timeout = timeout;
+        self
+    }
+
+    /// How the targets of `send` and `send_many` are retried, a single attempt by default
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Replaces the default allocator, sequential ids under a random session prefix
+    pub fn with_id_allocator<I: MsgIdAllocator + 'static>(self, ids: I) -> Self {
+        self.set_id_allocator(ids);
+        self
+    }
+
+    pub fn set_id_allocator<I: MsgIdAllocator + 'static>(&self, ids: I) {
+        *self.ids.lock().expect("msg_id allocator poisoned") = Box::new(ids);
+    }
+
+    /// Allocates the msg_id of the next cmd, skipping the ids still in flight
+    pub fn next_id(&self) -> u32 {
+        let mut ids = self.ids.lock().expect("msg_id allocator poisoned");
+        let routes = self.routes.lock().expect("routes poisoned");
+        next_free_id(&mut **ids, |id| routes.contains(id))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.io.local_addr()
+    }
+
+    /// Datagrams the kernel dropped on the socket since it was bound because its
+    /// receive buffer was full, `None` where the platform does not report it
+    pub fn kernel_drops(&self) -> Option<u64> {
+        self.io.kernel_drops()
+    }
+
+    /// Sends `cmd` to a single target and waits for its reply
+    pub fn send<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        target: A,
+    ) -> Result<CmdReplySummary, CtrlError> {
+        self.send_many(cmd, &[target])
+    }
+
+    /// Sends `cmd` to every target, each with a fresh msg_id, and waits for their replies
+    pub fn send_many<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        targets: &[A],
+    ) -> Result<CmdReplySummary, CtrlError> {
+        let targets = targets
+            .iter()
+            .map(|a| (a, self.next_id()))
+            .collect::<Vec<_>>();
+        self.send_many_with_ids(cmd, &targets)
+    }
+
+    /// Like `send_many` with the msg_id of each target given,
+    /// e.g. to retry with the id of a timed out attempt
+    pub fn send_many_with_ids<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        targets: &[(A, u32)],
+    ) -> Result<CmdReplySummary, CtrlError> {
+        self.exchange(cmd, targets, &self.retry)
+    }
+
+    /// Sends `cmd` to a broadcast address and collects the replies of all responders
+    pub fn broadcast<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        baddr: A,
+    ) -> Result<CmdReplySummary, CtrlError> {
+        self.broadcast_until(cmd, baddr, CollectUntil::default())
+    }
+
+    /// Like `broadcast`, stopping early as `until` says
+    pub fn broadcast_until<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        baddr: A,
+        until: CollectUntil,
+    ) -> Result<CmdReplySummary, CtrlError> {
+        self.broadcast_with_id(cmd, baddr, self.next_id(), until)
+    }
+
+    /// Broadcasts `cmd` to each of `slices` in turn, e.g. the broadcast addresses
+    /// of the subnets of a large array, so that fewer devices answer at once.
+    /// The deadline of `until` applies to each slice, the expected count to all of
+    /// them together; `gap` is the pause between two slices.
+    pub fn discover<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        slices: &[A],
+        until: CollectUntil,
+        gap: Duration,
+    ) -> Result<CmdReplySummary, CtrlError> {
+        let mut summary = CmdReplySummary::default();
+        for (i, baddr) in slices.iter().enumerate() {
+            if until.is_complete(summary.targets.len()) {
+                break;
+            }
+            if i > 0 {
+                std::thread::sleep(gap);
+            }
+            let until = CollectUntil {
+                expected: until.expected.map(|n| n - summary.targets.len()),
+                ..until
+            };
+            summary.merge(self.broadcast_until(cmd.clone(), baddr, until)?);
+        }
+        Ok(summary)
+    }
+
+    /// Like `broadcast_until`, handing every responder to `on_reply` as soon as
+    /// its reply is in; returning false stops collecting
+    pub fn broadcast_each<A, F>(
+        &self,
+        cmd: CtrlMsg,
+        baddr: A,
+        until: CollectUntil,
+        on_reply: F,
+    ) -> Result<CmdReplySummary, CtrlError>
+    where
+        A: ToSocketAddrs,
+        F: FnMut(SocketAddr, &TargetReport) -> bool,
+    {
+        self.broadcast_with_id_each(cmd, baddr, self.next_id(), until, on_reply)
+    }
+
+    pub fn broadcast_with_id<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        baddr: A,
+        msg_id: u32,
+        until: CollectUntil,
+    ) -> Result<CmdReplySummary, CtrlError> {
+        self.broadcast_with_id_each(cmd, baddr, msg_id, until, |_, _| true)
+    }
+
+    pub fn broadcast_with_id_each<A, F>(
+        &self,
+        mut cmd: CtrlMsg,
+        baddr: A,
+        msg_id: u32,
+        until: CollectUntil,
+        mut on_reply: F,
+    ) -> Result<CmdReplySummary, CtrlError>
+    where
+        A: ToSocketAddrs,
+        F: FnMut(SocketAddr, &TargetReport) -> bool,
+    {
+        cmd.validate()?;
+        cmd.set_msg_id(msg_id);
+        let baddr = baddr
+            .to_socket_addrs()
+            .map_err(CtrlError::Resolve)?
+            .collect::<Vec<_>>();
+        let out = encode_cmd(&cmd)?;
+
+        let Some(&target) = baddr.first() else {
+            return Err(CtrlError::Resolve(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "broadcast address resolved to no address",
+            )));
+        };
+
+        let span = info_span!("broadcast", cmd = cmd.name(), msg_id, addr = %target);
+        let _span = span.enter();
+        let route = self.register(&[msg_id])?;
+        let drops_before = self.kernel_drops();
+        debug!("{cmd}");
+        trace!("\n{}", HexDump(&out));
+        let sent = self.io.send(vec![(out, target)]);
+        let sent_at = sent
+            .into_iter()
+            .next()
+            .expect("one datagram, one result")
+            .map_err(|source| CtrlError::Send {
+                addr: target,
+                source,
+            })?;
+        info!("sent");
+
+        let end = until.deadline.map(|d| sent_at + d);
+        let mut reply_summary = CmdReplySummary::default();
+        while !until.is_complete(reply_summary.targets.len()) {
+            let Some((data, a)) = route.recv(wait_time(self.retry.attempt_timeout, end)) else {
+                break;
+            };
+            dump_received(&data, a);
+            let reply = match try_decode(&data, a) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    reply_summary.undecodable.push((a, data.to_vec(), e));
+                    continue;
+                }
+            };
+            // late replies to earlier cmds and repeated replies are stray
+            if reply.get_msg_id() != msg_id || reply_summary.targets.contains_key(&a) {
+                debug!(addr = %a, msg_id = reply.get_msg_id(), "stray reply");
+                reply_summary.push_stray(a, reply);
+                continue;
+            }
+            let latency = sent_at.elapsed();
+            info!(addr = %a, ?latency, "replied");
+            debug!("{reply}");
+            let report = TargetReport {
+                msg_id,
+                outcome: outcome_of(&cmd, reply),
+                latency: Some(latency),
+                attempts: 1,
+            };
+            let go_on = on_reply(a, &report);
+            reply_summary.targets.insert(a, report);
+            if !go_on {
+                debug!("stopped by the caller");
+                break;
+            }
+        }
+        reply_summary.kernel_drops = self
+            .kernel_drops()
+            .zip(drops_before)
+            .map(|(after, before)| after.saturating_sub(before));
+        if let Some(n) = reply_summary.kernel_drops.filter(|&n| n > 0) {
+            warn!(
+                drops = n,
+                "datagrams dropped by the kernel, the receive buffer is too small"
+            );
+        }
+        info!(replies = reply_summary.targets.len(), "done");
+        Ok(reply_summary)
+    }
+
+    /// Sends `cmd` to the targets and matches the replies on (target, msg_id),
+    /// resending to the unanswered targets as `retry` allows
+    pub(crate) fn exchange<A: ToSocketAddrs>(
+        &self,
+        cmd: CtrlMsg,
+        targets: &[(A, u32)],
+        retry: &RetryPolicy,
+    ) -> Result<CmdReplySummary, CtrlError> {
+        let span = info_span!("cmd", cmd = cmd.name());
+        let _span = span.enter();
+        let mut exchange = Exchange::new(cmd, *retry)?;
+        for (addr, msg_id) in targets.iter() {
+            let resolved = addr
+                .to_socket_addrs()
+                .map_err(CtrlError::Resolve)?
+                .collect::<Vec<_>>();
+            if resolved.is_empty() {
+                return Err(CtrlError::Resolve(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "target resolved to no address",
+                )));
+            }
+            exchange.add_target(resolved, *msg_id);
+        }
+
+        // registered before sending, so that no early reply is missed
+        let route = self.register(&exchange.ids())?;
+        loop {
+            match exchange.step()? {
+                Step::Send(datagrams) => exchange.sent(self.io.send(datagrams)),
+                Step::Wait(timeout) => match route.recv(timeout) {
+                    Some((data, a)) => exchange.received(&data, a),
+                    None => exchange.timed_out(),
+                },
+                Step::Done => break,
+            }
+        }
+        Ok(exchange.finish())
+    }
+
+    /// Ends the request waiting for the replies carrying `msg_id` as if no more
+    /// came, or the next one issued with it if there is none yet
+    pub(crate) fn cancel(&self, msg_id: u32) {
+        self.routes.lock().expect("routes poisoned").cancel(msg_id);
+    }
+
+    /// Routes the replies carrying any of `ids`, and the datagrams no other request
+    /// is waiting for, to the returned channel until it is dropped
+    fn register(&self, ids: &[u32]) -> Result<Route<'_>, CtrlError> {
+        let (tx, rx) = channel();
+        let key = self
+            .routes
+            .lock()
+            .expect("routes poisoned")
+            .register(ids, tx, true)?;
+        Ok(Route {
+            routes: &self.routes,
+            key,
+            ids: ids.to_vec(),
+            rx,
+        })
+    }
+}
+
+/// Replies routed to one request, unregistered on drop
+struct Route<'a> {
+    routes: &'a Routes,
+    key: u64,
+    ids: Vec<u32>,
+    rx: Receiver<Datagram>,
+}
+
+impl Route<'_> {
+    /// Waits at most `timeout` for the next reply, forever if `None`
+    fn recv(&self, timeout: Option<Duration>) -> Option<Datagram> {
+        wait(&self.rx, timeout.map(|t| Instant::now() + t))
+    }
+}
+
+impl Drop for Route<'_> {
+    fn drop(&mut self) {
+        self.routes
+            .lock()
+            .expect("routes poisoned")
+            .unregister(self.key, &self.ids);
+    }
+}
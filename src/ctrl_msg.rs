@@ -1,20 +1,34 @@
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, btree_map::Entry},
     fmt::Display,
-    io::Cursor,
-    net::{SocketAddr, ToSocketAddrs, UdpSocket},
-    time::Duration,
+    io::{Cursor, Read},
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        Arc,
+        mpsc::{Receiver, channel},
+    },
+    time::{Duration, Instant},
 };
 
-use binrw::{binrw, BinRead, BinWrite};
-use chrono::Local;
+use binrw::{binrw, helpers::until_eof, BinRead, BinWrite};
+use tracing::{Span, debug, info, trace, warn};
 use serde::{Deserialize, Serialize};
 
-use rand::{rng, Rng};
+use crate::{
+    controller::{CollectUntil, Controller, SocketOptions},
+    health::{HealthReport, HealthThresholds},
+    logging::HexDump,
+    msg_id::next_session_ids,
+    retry::RetryPolicy,
+    status::{DeviceInfo, DeviceStatus, StatusCriteria},
+    typed_msg::{QueryReply, Reply},
+};
 
+/// Addresses of one XGbe port, `#[repr(C)]` for the C API
 #[derive(Clone, Copy, Serialize, Deserialize, Debug)]
 #[binrw]
 #[brw(little)]
+#[repr(C)]
 pub struct XGbeCfg {
     #[brw(pad_after(2))]
     pub dst_mac: [u8; 6],
@@ -87,6 +101,22 @@ pub enum Health {
     }
 }
 
+impl Health {
+    pub fn te_health(payload: Vec<u32>) -> Self {
+        Health::TEHealth {
+            nhealth: payload.len() as u32,
+            payload,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), CtrlError> {
+        match self {
+            Health::TEHealth { nhealth, payload } => check_len("TEHealth", "nhealth", *nhealth, payload.len()),
+            Health::HLHealth { .. } | Health::T510Health { .. } => Ok(()),
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 #[binrw]
 #[brw(little)]
@@ -239,8 +269,73 @@ pub enum CtrlMsg {
     #[brw(magic(0xff_00_00_0d_u32))]
     MixerSetReply{
         msg_id: u32,
-    }
+    },
+
+    /// Fallback for datagrams whose magic is none of the above,
+    /// `raw` holds everything after the magic (msg_id included)
+    Unknown {
+        #[br(assert(!KNOWN_MAGICS.contains(&magic)))]
+        magic: u32,
+        #[br(parse_with = until_eof)]
+        raw: Vec<u8>,
+    },
+}
+
+/// Defines `KNOWN_MAGICS` and `CtrlMsg::magic` from one table, a variant
+/// missing from it does not compile
+macro_rules! magics {
+    ($($variant:ident => $magic:expr,)*) => {
+        /// Magics of all the variants of `CtrlMsg` except `Unknown`
+        pub const KNOWN_MAGICS: &[u32] = &[$($magic),*];
+
+        impl CtrlMsg {
+            /// The magic that identifies the message type on the wire
+            pub fn magic(&self) -> u32 {
+                match self {
+                    $(CtrlMsg::$variant { .. } => $magic,)*
+                    CtrlMsg::Unknown { magic, .. } => *magic,
+                }
+            }
+        }
+    };
+}
 
+magics! {
+    InvalidMsg => 0xff_ff_ff_ff,
+    Query => 0x01,
+    QueryReply => 0xff_00_00_01,
+    Sync => 0x02,
+    SyncReply => 0xff_00_00_02,
+    XGbeCfg => 0x03,
+    XgbeCfgReply => 0xff_00_00_03,
+    I2CScan => 0x04,
+    I2CScanReply => 0xff_00_00_04,
+    I2CWrite => 0x01_04,
+    I2CWriteReply => 0xff_00_01_04,
+    I2CWriteReg => 0x02_04,
+    I2CWriteRegReply => 0xff_00_02_04,
+    I2CRead => 0x03_04,
+    I2CReadReply => 0xff_00_03_04,
+    I2CReadReg => 0x04_04,
+    I2CReadRegReply => 0xff_00_04_04,
+    StreamStart => 0x01_05,
+    StreamStartReply => 0xff_00_01_05,
+    StreamStop => 0x02_05,
+    StreamStopReply => 0xff_00_02_05,
+    BitShift => 0x06,
+    BitShiftReply => 0xff_00_00_06,
+    PwrCtrl => 0x07,
+    PwrCtrlReply => 0xff_00_00_07,
+    Init => 0x08,
+    InitReply => 0xff_00_00_08,
+    XGbeCfgSingle => 0x0a,
+    XGbeCfgSingleReply => 0xff_00_00_0a,
+    XGbeCfgQuery => 0x0b,
+    XGbeCfgQueryReply => 0xff_00_00_0b,
+    SetClk => 0x0c,
+    SetClkReply => 0xff_00_00_0c,
+    MixerSet => 0x0d,
+    MixerSetReply => 0xff_00_00_0d,
 }
 
 impl Display for CtrlMsg {
@@ -253,7 +348,8 @@ impl Display for CtrlMsg {
                 len: _,
                 description,
             } => {
-                let desc = String::from_utf8(description.clone()).expect("failed to convert to utf8 str");
+                let desc = String::from_utf8_lossy(description);
+                let err_code = DeviceErrorCode::from(*err_code);
                 writeln!(
                     f,
                     "InvalidMsg:{{ msg_id: {msg_id}, err_code: {err_code}, desc: {desc} }}"
@@ -271,12 +367,16 @@ impl Display for CtrlMsg {
                 locked,
                 health,
             } => {
-                write!(f, "QueryReply{{msg_id: {msg_id}, fm_ver: 0x{fm_ver:x}, tick_cnt1: {tick_cnt1}, tick_cnt2: {tick_cnt2}, trans_state: 0x{trans_state:x}, locked: 0x{locked:x}, Health: {health:?}")?;
+                write!(f, "QueryReply{{msg_id: {msg_id}, fm_ver: 0x{fm_ver:x}, tick_cnt1: {tick_cnt1}, tick_cnt2: {tick_cnt2}, trans_state: 0x{trans_state:x}, locked: 0x{locked:x}")?;
                 writeln!(f, "}}")?;
-                if *locked & 0x00_00_00_0f!=0x0f{
+                write!(f, "{}", HealthReport::new(health, &HealthThresholds::default()))?;
+                let status = DeviceStatus::new(*fm_ver, *tick_cnt1, *tick_cnt2, *trans_state, *locked);
+                let criteria = StatusCriteria::default();
+                writeln!(f, "{status}")?;
+                if !status.lock_ok(&criteria) {
                     writeln!(f, "Lock stat abnormal!")?;
                 }
-                if tick_cnt2-tick_cnt1!=10_000_000{
+                if !status.pps_ok(&criteria) {
                     writeln!(f, "Warning, tick cnt diff != 10M ")
                 }else{
                     writeln!(f, "tick cnt diff OK")
@@ -474,12 +574,123 @@ impl Display for CtrlMsg {
             CtrlMsg::MixerSetReply { msg_id }=>{
                 writeln!(f, "MixerSetReply {{msg_id: {msg_id}}}")
             }
+            CtrlMsg::Unknown { magic, raw }=>{
+                write!(f, "Unknown {{magic: 0x{magic:08x}, raw:")?;
+                for &x in raw {
+                    write!(f, " {x:02x}")?;
+                }
+                writeln!(f, "}}")
+            }
         }?;
         writeln!(f, "=====================")
     }
 }
 
+fn check_len(msg: &'static str, field: &'static str, declared: u32, actual: usize) -> Result<(), CtrlError> {
+    if declared as usize == actual {
+        Ok(())
+    } else {
+        Err(CtrlError::Inconsistent {
+            msg,
+            field,
+            declared,
+            actual,
+        })
+    }
+}
+
 impl CtrlMsg {
+    pub fn invalid_msg(msg_id: u32, err_code: DeviceErrorCode, description: &str) -> Self {
+        let description = description.as_bytes().to_vec();
+        CtrlMsg::InvalidMsg {
+            msg_id,
+            err_code: err_code.into(),
+            len: description.len() as u32,
+            description,
+        }
+    }
+
+    pub fn i2c_scan_reply(msg_id: u32, devices: Vec<u8>) -> Self {
+        CtrlMsg::I2CScanReply {
+            msg_id,
+            ndev: devices.len() as u32,
+            payload: devices,
+        }
+    }
+
+    pub fn i2c_write(dev_addr: u32, payload: Vec<u8>) -> Self {
+        CtrlMsg::I2CWrite {
+            msg_id: 0,
+            dev_addr,
+            len: payload.len() as u32,
+            payload,
+        }
+    }
+
+    pub fn i2c_write_reg(dev_addr: u32, reg_addr: u32, payload: Vec<u8>) -> Self {
+        CtrlMsg::I2CWriteReg {
+            msg_id: 0,
+            dev_addr,
+            reg_addr,
+            len: payload.len() as u32,
+            payload,
+        }
+    }
+
+    pub fn i2c_read_reply(msg_id: u32, err_code: u32, payload: Vec<u8>) -> Self {
+        CtrlMsg::I2CReadReply {
+            msg_id,
+            err_code,
+            len: payload.len() as u32,
+            payload,
+        }
+    }
+
+    pub fn i2c_read_reg_reply(msg_id: u32, err_code: u32, payload: Vec<u8>) -> Self {
+        CtrlMsg::I2CReadRegReply {
+            msg_id,
+            err_code,
+            len: payload.len() as u32,
+            payload,
+        }
+    }
+
+    pub fn xgbe_cfg_query_reply(msg_id: u32, cfg: Vec<XGbeCfg>) -> Self {
+        CtrlMsg::XGbeCfgQueryReply {
+            msg_id,
+            nports: cfg.len() as u32,
+            cfg,
+        }
+    }
+
+    /// Checks the redundant length fields against the payloads they describe
+    pub fn validate(&self) -> Result<(), CtrlError> {
+        use CtrlMsg::*;
+        match self {
+            InvalidMsg {
+                len, description, ..
+            } => check_len("InvalidMsg", "len", *len, description.len()),
+            QueryReply { health, .. } => health.validate(),
+            I2CScanReply { ndev, payload, .. } => {
+                check_len("I2CScanReply", "ndev", *ndev, payload.len())
+            }
+            I2CWrite { len, payload, .. } => check_len("I2CWrite", "len", *len, payload.len()),
+            I2CWriteReg { len, payload, .. } => {
+                check_len("I2CWriteReg", "len", *len, payload.len())
+            }
+            I2CReadReply { len, payload, .. } => {
+                check_len("I2CReadReply", "len", *len, payload.len())
+            }
+            I2CReadRegReply { len, payload, .. } => {
+                check_len("I2CReadRegReply", "len", *len, payload.len())
+            }
+            XGbeCfgQueryReply { nports, cfg, .. } => {
+                check_len("XGbeCfgQueryReply", "nports", *nports, cfg.len())
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn set_msg_id(&mut self, mid: u32) {
         use CtrlMsg::*;
         match self {
@@ -518,9 +729,106 @@ impl CtrlMsg {
             SetClkReply{ msg_id, .. }=>*msg_id=mid,
             MixerSet{ msg_id, .. }=>*msg_id=mid,
             MixerSetReply{ msg_id }=>*msg_id=mid,
+            Unknown { raw, .. } => {
+                if raw.len() >= 4 {
+                    raw[..4].copy_from_slice(&mid.to_le_bytes());
+                }
+            }
         }
     }
 
+    /// Name of the variant, for logs
+    pub fn name(&self) -> &'static str {
+        use CtrlMsg::*;
+        match self {
+            InvalidMsg { .. } => "InvalidMsg",
+            Query { .. } => "Query",
+            QueryReply { .. } => "QueryReply",
+            Sync { .. } => "Sync",
+            SyncReply { .. } => "SyncReply",
+            XGbeCfg { .. } => "XGbeCfg",
+            XgbeCfgReply { .. } => "XgbeCfgReply",
+            I2CScan { .. } => "I2CScan",
+            I2CScanReply { .. } => "I2CScanReply",
+            I2CWrite { .. } => "I2CWrite",
+            I2CWriteReply { .. } => "I2CWriteReply",
+            I2CWriteReg { .. } => "I2CWriteReg",
+            I2CWriteRegReply { .. } => "I2CWriteRegReply",
+            I2CRead { .. } => "I2CRead",
+            I2CReadReply { .. } => "I2CReadReply",
+            I2CReadReg { .. } => "I2CReadReg",
+            I2CReadRegReply { .. } => "I2CReadRegReply",
+            StreamStart { .. } => "StreamStart",
+            StreamStartReply { .. } => "StreamStartReply",
+            StreamStop { .. } => "StreamStop",
+            StreamStopReply { .. } => "StreamStopReply",
+            BitShift { .. } => "BitShift",
+            BitShiftReply { .. } => "BitShiftReply",
+            PwrCtrl { .. } => "PwrCtrl",
+            PwrCtrlReply { .. } => "PwrCtrlReply",
+            Init { .. } => "Init",
+            InitReply { .. } => "InitReply",
+            XGbeCfgSingle { .. } => "XGbeCfgSingle",
+            XGbeCfgSingleReply { .. } => "XGbeCfgSingleReply",
+            XGbeCfgQuery { .. } => "XGbeCfgQuery",
+            XGbeCfgQueryReply { .. } => "XGbeCfgQueryReply",
+            SetClk { .. } => "SetClk",
+            SetClkReply { .. } => "SetClkReply",
+            MixerSet { .. } => "MixerSet",
+            MixerSetReply { .. } => "MixerSetReply",
+            Unknown { .. } => "Unknown",
+        }
+    }
+
+    /// Magic of the reply expected for a downlink cmd,
+    /// `None` for uplink messages and `Unknown`
+    pub fn reply_magic(&self) -> Option<u32> {
+        use CtrlMsg::*;
+        match self {
+            Query { .. } => Some(0xff_00_00_01),
+            Sync { .. } => Some(0xff_00_00_02),
+            XGbeCfg { .. } => Some(0xff_00_00_03),
+            I2CScan { .. } => Some(0xff_00_00_04),
+            I2CWrite { .. } => Some(0xff_00_01_04),
+            I2CWriteReg { .. } => Some(0xff_00_02_04),
+            I2CRead { .. } => Some(0xff_00_03_04),
+            I2CReadReg { .. } => Some(0xff_00_04_04),
+            StreamStart { .. } => Some(0xff_00_01_05),
+            StreamStop { .. } => Some(0xff_00_02_05),
+            BitShift { .. } => Some(0xff_00_00_06),
+            PwrCtrl { .. } => Some(0xff_00_00_07),
+            Init { .. } => Some(0xff_00_00_08),
+            XGbeCfgSingle { .. } => Some(0xff_00_00_0a),
+            XGbeCfgQuery { .. } => Some(0xff_00_00_0b),
+            SetClk { .. } => Some(0xff_00_00_0c),
+            MixerSet { .. } => Some(0xff_00_00_0d),
+            InvalidMsg { .. }
+            | QueryReply { .. }
+            | SyncReply { .. }
+            | XgbeCfgReply { .. }
+            | I2CScanReply { .. }
+            | I2CWriteReply { .. }
+            | I2CWriteRegReply { .. }
+            | I2CReadReply { .. }
+            | I2CReadRegReply { .. }
+            | StreamStartReply { .. }
+            | StreamStopReply { .. }
+            | BitShiftReply { .. }
+            | PwrCtrlReply { .. }
+            | InitReply { .. }
+            | XGbeCfgSingleReply { .. }
+            | XGbeCfgQueryReply { .. }
+            | SetClkReply { .. }
+            | MixerSetReply { .. }
+            | Unknown { .. } => None,
+        }
+    }
+
+    /// Whether `self` is the reply type expected for `cmd`, e.g. a QueryReply for a Query
+    pub fn is_reply_to(&self, cmd: &CtrlMsg) -> bool {
+        cmd.reply_magic() == Some(self.magic())
+    }
+
     pub fn get_msg_id(&self) -> u32 {
         use CtrlMsg::*;
         match self {
@@ -563,290 +871,1093 @@ impl CtrlMsg {
             SetClkReply{ msg_id, .. }=>*msg_id,
             MixerSet{ msg_id, .. }=>*msg_id,
             MixerSetReply{ msg_id }=>*msg_id,
+            Unknown { raw, .. } => raw
+                .get(..4)
+                .map_or(0, |x| u32::from_le_bytes([x[0], x[1], x[2], x[3]])),
         }
     }
 }
 
+/// Loads a list of cmds from YAML, rejecting the ones with inconsistent length fields
+pub fn load_cmds<R: Read>(reader: R) -> Result<Vec<CtrlMsg>, CtrlError> {
+    let cmds: Vec<CtrlMsg> = serde_yaml::from_reader(reader).map_err(CtrlError::Yaml)?;
+    for c in &cmds {
+        c.validate()?;
+    }
+    Ok(cmds)
+}
+
+/// Hex dump of `x` at trace level
 pub fn print_bytes(x: &[u8]) {
-    for (i, w) in x.chunks(4).enumerate() {
-        for &b in w {
-            print!("{b:02x} ");
+    trace!("\n{}", HexDump(x));
+}
+
+/// Error codes carried by `CtrlMsg::InvalidMsg`, see `doc/general_def.typ`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceErrorCode {
+    UnknownCmd,
+    SyncFailure,
+    InitFailure,
+    XGbePortOutOfRange,
+    Other(u32),
+}
+
+impl From<u32> for DeviceErrorCode {
+    fn from(x: u32) -> Self {
+        match x {
+            0 => DeviceErrorCode::UnknownCmd,
+            1 => DeviceErrorCode::SyncFailure,
+            2 => DeviceErrorCode::InitFailure,
+            3 => DeviceErrorCode::XGbePortOutOfRange,
+            x => DeviceErrorCode::Other(x),
+        }
+    }
+}
+
+impl From<DeviceErrorCode> for u32 {
+    fn from(x: DeviceErrorCode) -> Self {
+        match x {
+            DeviceErrorCode::UnknownCmd => 0,
+            DeviceErrorCode::SyncFailure => 1,
+            DeviceErrorCode::InitFailure => 2,
+            DeviceErrorCode::XGbePortOutOfRange => 3,
+            DeviceErrorCode::Other(x) => x,
+        }
+    }
+}
+
+impl Display for DeviceErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceErrorCode::UnknownCmd => write!(f, "unknown cmd"),
+            DeviceErrorCode::SyncFailure => write!(f, "sync failure"),
+            DeviceErrorCode::InitFailure => write!(f, "init failure"),
+            DeviceErrorCode::XGbePortOutOfRange => write!(f, "XGbe port index out of range"),
+            DeviceErrorCode::Other(x) => write!(f, "error code {x}"),
+        }
+    }
+}
+
+impl DeviceErrorCode {
+    /// What most likely went wrong when `cmd` was answered with this code
+    pub fn hint(&self, cmd: &CtrlMsg) -> Option<&'static str> {
+        match (self, cmd) {
+            (DeviceErrorCode::UnknownCmd, _) => Some("the firmware does not support this cmd"),
+            (DeviceErrorCode::SyncFailure, CtrlMsg::Sync { .. }) => {
+                Some("device is sleeping or sees no pps, wake it up with PwrCtrl first")
+            }
+            (DeviceErrorCode::InitFailure, CtrlMsg::Init { .. }) => {
+                Some("device is sleeping or its clock is not locked")
+            }
+            (DeviceErrorCode::XGbePortOutOfRange, CtrlMsg::XGbeCfgSingle { .. }) => {
+                Some("port_id is beyond the ports of the device")
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CtrlError {
+    /// binding the local socket or setting its options failed
+    Bind(std::io::Error),
+    /// a target or broadcast address could not be resolved
+    Resolve(std::io::Error),
+    /// sending or receiving a datagram failed
+    Io(std::io::Error),
+    /// the datagram to a target could not be sent, e.g. there is no route to it
+    Send {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+    /// the command could not be serialized
+    Encode(binrw::Error),
+    /// a datagram could not be decoded as a `CtrlMsg`
+    Decode {
+        addr: SocketAddr,
+        raw: Vec<u8>,
+        source: binrw::Error,
+    },
+    /// a reply of another type than the one the cmd expects
+    UnexpectedReply { addr: SocketAddr, reply: Box<CtrlMsg> },
+    /// the target did not reply in time
+    Timeout { addr: SocketAddr },
+    /// a length field does not match the payload it counts
+    Inconsistent {
+        msg: &'static str,
+        field: &'static str,
+        declared: u32,
+        actual: usize,
+    },
+    /// a cmd file could not be parsed
+    Yaml(serde_yaml::Error),
+    /// the device answered with an InvalidMsg
+    Device {
+        addr: SocketAddr,
+        err_code: DeviceErrorCode,
+        description: String,
+        hint: Option<&'static str>,
+    },
+    /// an I2C transaction was answered with a non-zero err_code
+    I2C { addr: SocketAddr, err_code: u32 },
+    /// another request on the same controller is still waiting for this msg_id
+    IdInUse { msg_id: u32 },
+}
+
+impl Display for CtrlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CtrlError::Bind(e) => write!(f, "failed to bind local socket: {e}"),
+            CtrlError::Resolve(e) => write!(f, "failed to resolve address: {e}"),
+            CtrlError::Io(e) => write!(f, "socket I/O error: {e}"),
+            CtrlError::Send { addr, source } => write!(f, "failed to send to {addr}: {source}"),
+            CtrlError::Encode(e) => write!(f, "failed to encode cmd: {e}"),
+            CtrlError::Decode { addr, raw, source } => write!(
+                f,
+                "failed to decode {} bytes from {addr}: {source}",
+                raw.len()
+            ),
+            CtrlError::UnexpectedReply { addr, reply } => write!(
+                f,
+                "unexpected reply {} with msg_id={} from {addr}",
+                reply.name(),
+                reply.get_msg_id()
+            ),
+            CtrlError::Timeout { addr } => write!(f, "{addr} did not reply in time"),
+            CtrlError::Inconsistent {
+                msg,
+                field,
+                declared,
+                actual,
+            } => write!(
+                f,
+                "{msg}.{field} is {declared} but the payload holds {actual} items"
+            ),
+            CtrlError::Yaml(e) => write!(f, "failed to load cmd: {e}"),
+            CtrlError::Device {
+                addr,
+                err_code,
+                description,
+                hint,
+            } => {
+                write!(f, "{addr} reported {err_code}: {description}")?;
+                match hint {
+                    Some(hint) => write!(f, " ({hint})"),
+                    None => Ok(()),
+                }
+            }
+            CtrlError::I2C { addr, err_code } => {
+                write!(f, "I2C transaction on {addr} failed with err_code 0x{err_code:x}")
+            }
+            CtrlError::IdInUse { msg_id } => write!(f, "msg_id {msg_id} is already in flight"),
+        }
+    }
+}
+
+impl CtrlError {
+    /// The code reported by the device, if this is a device error
+    pub fn device_error_code(&self) -> Option<DeviceErrorCode> {
+        match self {
+            CtrlError::Device { err_code, .. } => Some(*err_code),
+            _ => None,
+        }
+    }
+
+    /// The device the error is about, if it is about one
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            CtrlError::Send { addr, .. }
+            | CtrlError::Decode { addr, .. }
+            | CtrlError::UnexpectedReply { addr, .. }
+            | CtrlError::Timeout { addr }
+            | CtrlError::Device { addr, .. }
+            | CtrlError::I2C { addr, .. } => Some(*addr),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for CtrlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CtrlError::Bind(e) | CtrlError::Resolve(e) | CtrlError::Io(e) => Some(e),
+            CtrlError::Send { source, .. } => Some(source),
+            CtrlError::Encode(e) | CtrlError::Decode { source: e, .. } => Some(e),
+            CtrlError::Yaml(e) => Some(e),
+            CtrlError::UnexpectedReply { .. }
+            | CtrlError::Inconsistent { .. }
+            | CtrlError::Timeout { .. }
+            | CtrlError::Device { .. }
+            | CtrlError::I2C { .. }
+            | CtrlError::IdInUse { .. } => None,
+        }
+    }
+}
+
+/// What became of a cmd sent to one target
+#[derive(Debug)]
+pub enum Outcome {
+    /// the expected reply type, e.g. a QueryReply to a Query
+    Ok(CtrlMsg),
+    /// the device answered with an InvalidMsg
+    DeviceError {
+        err_code: DeviceErrorCode,
+        description: String,
+        hint: Option<&'static str>,
+    },
+    /// nothing came back before the timeout
+    Timeout,
+    /// a reply of another type came back, e.g. a SyncReply to a Query
+    Mismatch(CtrlMsg),
+    /// the target sent a datagram that could not be decoded
+    Undecodable { raw: Vec<u8>, error: binrw::Error },
+    /// the cmd could not be sent to the target
+    SendFailed(std::io::Error),
+}
+
+impl Outcome {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Outcome::Ok(_))
+    }
+
+    /// The expected reply, or the failure as an error attributed to `addr`
+    pub fn into_result(self, addr: SocketAddr) -> Result<CtrlMsg, CtrlError> {
+        match self {
+            Outcome::Ok(reply) => Ok(reply),
+            Outcome::DeviceError {
+                err_code,
+                description,
+                hint,
+            } => Err(CtrlError::Device {
+                addr,
+                err_code,
+                description,
+                hint,
+            }),
+            Outcome::Timeout => Err(CtrlError::Timeout { addr }),
+            Outcome::Mismatch(reply) => Err(CtrlError::UnexpectedReply {
+                addr,
+                reply: Box::new(reply),
+            }),
+            Outcome::Undecodable { raw, error } => Err(CtrlError::Decode {
+                addr,
+                raw,
+                source: error,
+            }),
+            Outcome::SendFailed(source) => Err(CtrlError::Send { addr, source }),
         }
-        print!("| {i:02} {}:{}", i * 4, i * 4 + 3);
-        println!();
     }
 }
 
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Ok(reply) => write!(f, "Ok\n{reply}"),
+            Outcome::DeviceError {
+                err_code,
+                description,
+                hint,
+            } => {
+                write!(f, "device error, {err_code}: {description}")?;
+                match hint {
+                    Some(hint) => write!(f, " ({hint})"),
+                    None => Ok(()),
+                }
+            }
+            Outcome::Timeout => write!(f, "timeout"),
+            Outcome::Mismatch(reply) => write!(f, "mismatched reply\n{reply}"),
+            Outcome::Undecodable { raw, error } => {
+                write!(f, "undecodable reply of {} bytes: {error}", raw.len())
+            }
+            Outcome::SendFailed(e) => write!(f, "send failed: {e}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TargetReport {
+    /// msg_id the cmd was sent with
+    pub msg_id: u32,
+    pub outcome: Outcome,
+    /// round-trip time since the last attempt, `None` if nothing came back
+    pub latency: Option<Duration>,
+    /// number of times the cmd was sent
+    pub attempts: u32,
+}
+
 #[derive(Default, Debug)]
 pub struct CmdReplySummary {
-    pub no_reply: Vec<(Vec<SocketAddr>, u32)>,
-    pub invalid_reply: Vec<(SocketAddr, CtrlMsg)>,
-    pub normal_reply: Vec<(SocketAddr, CtrlMsg)>,
+    /// one report per target, for broadcasts one per responder
+    pub targets: BTreeMap<SocketAddr, TargetReport>,
+    /// datagrams that could not be decoded and came from none of the targets
+    pub undecodable: Vec<(SocketAddr, Vec<u8>, binrw::Error)>,
+    /// replies carrying a pending msg_id but coming from an address the cmd was not sent to
+    pub unexpected_source: Vec<(SocketAddr, CtrlMsg)>,
+    /// replies whose msg_id is unknown or has already been answered
+    pub stray_reply: Vec<(SocketAddr, CtrlMsg)>,
+    /// number of stray replies per source address
+    pub stray_count: BTreeMap<SocketAddr, usize>,
+    /// datagrams the kernel dropped on the local socket while a broadcast was collecting,
+    /// `None` where the platform does not report it
+    pub kernel_drops: Option<u64>,
 }
 
-pub fn send_cmd<A, B>(
-    mut cmd: CtrlMsg,
+impl CmdReplySummary {
+    pub(crate) fn push_stray(&mut self, addr: SocketAddr, reply: CtrlMsg) {
+        *self.stray_count.entry(addr).or_default() += 1;
+        self.stray_reply.push((addr, reply));
+    }
+
+    /// Adds the replies of another broadcast, a device answering both counts as stray
+    pub(crate) fn merge(&mut self, other: CmdReplySummary) {
+        for (addr, report) in other.targets {
+            match self.targets.entry(addr) {
+                Entry::Vacant(e) => {
+                    e.insert(report);
+                }
+                Entry::Occupied(_) => match report.outcome {
+                    Outcome::Ok(reply) | Outcome::Mismatch(reply) => self.push_stray(addr, reply),
+                    _ => *self.stray_count.entry(addr).or_default() += 1,
+                },
+            }
+        }
+        self.undecodable.extend(other.undecodable);
+        self.unexpected_source.extend(other.unexpected_source);
+        self.stray_reply.extend(other.stray_reply);
+        for (addr, n) in other.stray_count {
+            *self.stray_count.entry(addr).or_default() += n;
+        }
+        self.kernel_drops = match (self.kernel_drops, other.kernel_drops) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+    }
+
+    /// True if every target answered with the expected reply
+    pub fn all_ok(&self) -> bool {
+        self.targets.values().all(|r| r.outcome.is_ok())
+    }
+
+    /// Targets that did not answer with the expected reply
+    pub fn failed_targets(&self) -> Vec<SocketAddr> {
+        self.targets
+            .iter()
+            .filter(|(_, r)| !r.outcome.is_ok())
+            .map(|(&a, _)| a)
+            .collect()
+    }
+
+    /// Targets that did not answer at all
+    pub fn timed_out_targets(&self) -> Vec<SocketAddr> {
+        self.targets
+            .iter()
+            .filter(|(_, r)| matches!(r.outcome, Outcome::Timeout))
+            .map(|(&a, _)| a)
+            .collect()
+    }
+
+    /// Timed out targets with the msg_id they were sent, to be retried by `send_cmd_with_ids`
+    /// so that a late reply to the first attempt still completes the request
+    pub fn retry_targets(&self) -> Vec<(SocketAddr, u32)> {
+        self.targets
+            .iter()
+            .filter(|(_, r)| matches!(r.outcome, Outcome::Timeout))
+            .map(|(&a, r)| (a, r.msg_id))
+            .collect()
+    }
+
+    /// Expected replies as raw messages
+    pub fn ok_replies(&self) -> impl Iterator<Item = (SocketAddr, &CtrlMsg)> {
+        self.targets.iter().filter_map(|(&a, r)| match &r.outcome {
+            Outcome::Ok(reply) => Some((a, reply)),
+            _ => None,
+        })
+    }
+
+    /// The devices that answered a Query, in address order
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        self.targets
+            .iter()
+            .filter_map(|(&a, r)| match &r.outcome {
+                Outcome::Ok(reply) => {
+                    QueryReply::from_msg(reply).map(|q| DeviceInfo::new(a, &q, r.latency))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Expected replies decoded as `R`, e.g. `replies_of::<QueryReply>()`
+    pub fn replies_of<R: Reply>(&self) -> Vec<(SocketAddr, R)> {
+        self.ok_replies()
+            .filter_map(|(a, reply)| R::from_msg(reply).map(|r| (a, r)))
+            .collect()
+    }
+}
+
+impl Display for CmdReplySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (addr, r) in &self.targets {
+            let latency = r
+                .latency
+                .map_or("-".to_string(), |l| format!("{:.3} ms", l.as_secs_f64() * 1e3));
+            writeln!(
+                f,
+                "{addr} msg_id={} attempts={} latency={latency}: {}",
+                r.msg_id, r.attempts, r.outcome
+            )?;
+        }
+        for (addr, raw, e) in &self.undecodable {
+            writeln!(f, "{addr} undecodable {} bytes: {e}", raw.len())?;
+        }
+        for (addr, reply) in &self.unexpected_source {
+            writeln!(f, "{addr} unexpected source\n{reply}")?;
+        }
+        for (addr, n) in &self.stray_count {
+            writeln!(f, "{addr} {n} stray replies")?;
+        }
+        if let Some(n) = self.kernel_drops.filter(|&n| n > 0) {
+            writeln!(f, "{n} datagrams dropped by the kernel, the receive buffer is too small")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn encode_cmd(cmd: &CtrlMsg) -> Result<Vec<u8>, CtrlError> {
+    let mut buf = Cursor::new(Vec::new());
+    cmd.write(&mut buf).map_err(CtrlError::Encode)?;
+    Ok(buf.into_inner())
+}
+
+pub fn decode_msg(buf: &[u8], addr: SocketAddr) -> Result<CtrlMsg, CtrlError> {
+    let mut cursor = Cursor::new(buf);
+    CtrlMsg::read(&mut cursor).map_err(|source| CtrlError::Decode {
+        addr,
+        raw: buf.to_vec(),
+        source,
+    })
+}
+
+pub(crate) fn try_decode(buf: &[u8], addr: SocketAddr) -> Result<CtrlMsg, binrw::Error> {
+    CtrlMsg::read(&mut Cursor::new(buf))
+        .inspect_err(|e| warn!(%addr, error = %e, "undecodable reply"))
+}
+
+/// Hex dump of a received datagram at trace level
+pub(crate) fn dump_received(buf: &[u8], addr: SocketAddr) {
+    trace!(%addr, len = buf.len(), "received\n{}", HexDump(buf));
+}
+
+/// Compares two endpoints, treating v4-mapped v6 addresses as their v4 counterpart
+pub fn same_endpoint(a: &SocketAddr, b: &SocketAddr) -> bool {
+    a.ip().to_canonical() == b.ip().to_canonical() && a.port() == b.port()
+}
+
+/// A cmd waiting for its reply
+pub(crate) struct Pending {
+    /// key of the target in the summary
+    pub(crate) target: SocketAddr,
+    /// all addresses the target resolved to
+    pub(crate) resolved: Vec<SocketAddr>,
+    pub(crate) sent_at: Instant,
+    /// span of the target, the events about its replies are recorded within
+    pub(crate) span: Span,
+}
+
+/// Turns a reply into the outcome it means for a target that sent `cmd`
+pub(crate) fn outcome_of(cmd: &CtrlMsg, reply: CtrlMsg) -> Outcome {
+    let msg_id = reply.get_msg_id();
+    match reply {
+        CtrlMsg::InvalidMsg {
+            err_code,
+            description,
+            ..
+        } => {
+            let err_code = DeviceErrorCode::from(err_code);
+            warn!(msg_id, %err_code, "device rejected the cmd");
+            Outcome::DeviceError {
+                err_code,
+                description: String::from_utf8_lossy(&description).into_owned(),
+                hint: err_code.hint(cmd),
+            }
+        }
+        reply if reply.is_reply_to(cmd) => Outcome::Ok(reply),
+        reply => {
+            warn!(msg_id, reply = reply.name(), "not a reply to {}", cmd.name());
+            Outcome::Mismatch(reply)
+        }
+    }
+}
+
+/// Cmds waiting for their replies, keyed by msg_id; several targets may share one id
+pub(crate) type PendingMap = BTreeMap<u32, Vec<Pending>>;
+
+pub(crate) fn handle_reply(
+    summary: &mut CmdReplySummary,
+    pending: &mut PendingMap,
+    cmd: &CtrlMsg,
+    data: &[u8],
+    addr: SocketAddr,
+) {
+    dump_received(data, addr);
+    let from_addr = |p: &Pending| p.resolved.iter().any(|x| same_endpoint(x, &addr));
+    let reply = match try_decode(data, addr) {
+        Ok(reply) => reply,
+        Err(error) => {
+            // garbage from a target still counts against it, but a later valid reply wins
+            match pending.values().flatten().find(|p| from_addr(p)) {
+                Some(p) => {
+                    let _span = p.span.enter();
+                    warn!("reply of the target is undecodable");
+                    let report = summary.targets.get_mut(&p.target).expect("target not registered");
+                    report.outcome = Outcome::Undecodable {
+                        raw: data.to_vec(),
+                        error,
+                    };
+                    report.latency = Some(p.sent_at.elapsed());
+                }
+                None => summary.undecodable.push((addr, data.to_vec(), error)),
+            }
+            return;
+        }
+    };
+    let msg_id = reply.get_msg_id();
+    let Some(waiting) = pending.get_mut(&msg_id) else {
+        debug!(msg_id, %addr, "stray reply");
+        summary.push_stray(addr, reply);
+        return;
+    };
+    // replies are matched on the (target, msg_id) pair
+    let Some(i) = waiting.iter().position(from_addr) else {
+        warn!(msg_id, %addr, "reply from unexpected source");
+        summary.unexpected_source.push((addr, reply));
+        return;
+    };
+    let p = waiting.swap_remove(i);
+    if waiting.is_empty() {
+        pending.remove(&msg_id);
+    }
+    let _span = p.span.enter();
+    let latency = p.sent_at.elapsed();
+    info!(?latency, "replied");
+    debug!("{reply}");
+    let report = summary.targets.get_mut(&p.target).expect("target not registered");
+    report.outcome = outcome_of(cmd, reply);
+    report.latency = Some(latency);
+}
+
+/// Sends `cmd` to every target, `retry` is either a `RetryPolicy`
+/// or a bare timeout for a single attempt.
+/// What gets logged is up to the installed subscriber, see `logging::init`.
+pub fn send_cmd<A, B, R>(
+    cmd: CtrlMsg,
     targets: &[A],
     local_addr: B,
+    retry: R,
+) -> Result<CmdReplySummary, CtrlError>
+where
+    A: ToSocketAddrs,
+    B: ToSocketAddrs,
+    R: Into<RetryPolicy>,
+{
+    let targets = targets
+        .iter()
+        .zip(next_session_ids(targets.len()))
+        .collect::<Vec<_>>();
+    send_cmd_with_ids(cmd, &targets, local_addr, retry)
+}
+
+/// Like `send_cmd`, but with the msg_id of each target given by the caller,
+/// so that a retry can reuse the id of the first attempt
+pub fn send_cmd_with_ids<A, B, R>(
+    cmd: CtrlMsg,
+    targets: &[(A, u32)],
+    local_addr: B,
+    retry: R,
+) -> Result<CmdReplySummary, CtrlError>
+where
+    A: ToSocketAddrs,
+    B: ToSocketAddrs,
+    R: Into<RetryPolicy>,
+{
+    Controller::bind(local_addr)?
+        .with_retry(retry.into())
+        .send_many_with_ids(cmd, targets)
+}
+
+pub fn bcast_cmd<A, B>(
+    cmd: CtrlMsg,
+    baddr: A,
+    local_addr: B,
     timeout: Option<Duration>,
-    debug_level: u32,
-) -> CmdReplySummary
+) -> Result<CmdReplySummary, CtrlError>
 where
     A: ToSocketAddrs,
     B: ToSocketAddrs,
 {
-    let socket = UdpSocket::bind(local_addr).expect("faild to bind addr");
-    socket.set_broadcast(true).expect("broadcast set failed");
-    socket
-        .set_nonblocking(true)
-        .expect("nonblocking set failed");
-
-    socket
-        .set_read_timeout(timeout)
-        .expect("failed to set timeout");
-
-    let mut rng1 = rng();
-    let mut msg_set = BTreeSet::new();
-    let mut addr_msg_id_map = BTreeMap::<u32, Vec<SocketAddr>>::new();
-    let mut reply_summary = CmdReplySummary::default();
-    for addr in targets.iter() {
-        let msg_id: u32 = rng1.random();
-        cmd.set_msg_id(msg_id);
-        msg_set.insert(msg_id);
-        addr_msg_id_map.insert(msg_id, addr.to_socket_addrs().expect("faild to get socket addr").collect::<Vec<_>>());
-        let mut buf = Cursor::new(Vec::new());
-        cmd.write(&mut buf).expect("failed to write cmd to buf");
-        let buf = buf.into_inner();
-        socket.send_to(&buf, addr).expect("send error");
-
-        println!(
-            "{} msg with id={} sent",
-            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-            msg_id,
-        );
-        print_bytes(&buf);
-
-        println!("{cmd}");
-
-        let mut buf = vec![0_u8; 9000];
-        while let Ok((l, a)) = socket.recv_from(&mut buf) {
-            //let (_s, _a)=socket.recv_from(&mut buf).unwrap();
-            if debug_level >= 1 {
-                println!(
-                    "{} received {} bytes, {} words from {:?}:",
-                    Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                    l,
-                    l / 4,
-                    a
-                );
-                print_bytes(&buf[..l]);
-            }
-            let buf1 = std::mem::replace(&mut buf, vec![0_u8; 9000]);
-            let mut cursor = Cursor::new(buf1);
-            let reply = CtrlMsg::read(&mut cursor).expect("failed to read reply");
-
-            let msg_id = reply.get_msg_id();
-            if let CtrlMsg::InvalidMsg { .. } = reply {
-                println!(
-                    "{} Invalid msg {:?}",
-                    Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                    reply
-                );
-                reply_summary.invalid_reply.push((a, reply));
-            } else {
-                reply_summary.normal_reply.push((a, reply));
-            }
-
-            println!(
-                "{} msg with id={} replied from {:?}",
-                Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                msg_id,
-                a
-            );
-            let x = msg_set.remove(&msg_id);
-            assert!(x);
-        }
-    }
+    bcast_cmd_until(
+        cmd,
+        baddr,
+        local_addr,
+        timeout,
+        CollectUntil::default(),
+    )
+}
 
-    println!("==waiting for the rest replies==");
-    socket
-        .set_nonblocking(false)
-        .expect("nonblocking set failed");
+/// Broadcasts a Query and describes every device answering, in address order
+pub fn find_devices_ex<A, B>(
+    baddr: A,
+    local_addr: B,
+    timeout: Option<Duration>,
+) -> Result<Vec<DeviceInfo>, CtrlError>
+where
+    A: ToSocketAddrs,
+    B: ToSocketAddrs,
+{
+    bcast_cmd(CtrlMsg::Query { msg_id: 0 }, baddr, local_addr, timeout)
+        .map(|summary| summary.devices())
+}
 
-    let mut buf = vec![0_u8; 9000];
+/// Like `bcast_cmd`, handing every responder to `on_reply` as soon as it has
+/// answered; returning false stops collecting
+pub fn bcast_cmd_each<A, B, F>(
+    cmd: CtrlMsg,
+    baddr: A,
+    local_addr: B,
+    timeout: Option<Duration>,
+    on_reply: F,
+) -> Result<CmdReplySummary, CtrlError>
+where
+    A: ToSocketAddrs,
+    B: ToSocketAddrs,
+    F: FnMut(SocketAddr, &TargetReport) -> bool,
+{
+    Controller::bind_with_options(local_addr, SocketOptions::for_discovery(0))?
+        .with_timeout(timeout)
+        .broadcast_with_id_each(
+            cmd,
+            baddr,
+            next_session_ids(1)[0],
+            CollectUntil::default(),
+            on_reply,
+        )
+}
 
-    if !msg_set.is_empty() {
-        while let Ok((l, a)) = socket.recv_from(&mut buf) {
-            if debug_level >= 1 {
-                println!(
-                    "{} received {} bytes, {} words from {:?}:",
-                    Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                    l,
-                    l / 4,
-                    a
-                );
-                print_bytes(&buf[..l]);
-            }
+/// Hands the device of a QueryReply to `on_device`, whatever else came back is passed over
+fn device_of<F: FnMut(DeviceInfo) -> bool>(
+    on_device: &mut F,
+) -> impl FnMut(SocketAddr, &TargetReport) -> bool {
+    move |a, r| match &r.outcome {
+        Outcome::Ok(reply) => QueryReply::from_msg(reply)
+            .is_none_or(|q| on_device(DeviceInfo::new(a, &q, r.latency))),
+        _ => true,
+    }
+}
 
-            let mut cursor = Cursor::new(buf.clone());
-            let reply = CtrlMsg::read(&mut cursor).expect("failed to read reply");
-            println!(
-                "{} \n{}",
-                Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                reply
-            );
+/// Like `find_devices_ex`, handing each device to `on_device` as soon as its
+/// QueryReply is in; returning false stops early
+pub fn find_devices_each<A, B, F>(
+    baddr: A,
+    local_addr: B,
+    timeout: Option<Duration>,
+    mut on_device: F,
+) -> Result<CmdReplySummary, CtrlError>
+where
+    A: ToSocketAddrs,
+    B: ToSocketAddrs,
+    F: FnMut(DeviceInfo) -> bool,
+{
+    bcast_cmd_each(
+        CtrlMsg::Query { msg_id: 0 },
+        baddr,
+        local_addr,
+        timeout,
+        device_of(&mut on_device),
+    )
+}
 
-            let msg_id = reply.get_msg_id();
+/// Devices answering a discovery broadcast, in the order their replies arrive.
+///
+/// The broadcast runs in a thread of its own; dropping the iterator stops it
+/// at once, also without a timeout.
+#[derive(Debug)]
+pub struct DeviceStream {
+    rx: Receiver<Result<DeviceInfo, CtrlError>>,
+    ctrl: Arc<Controller>,
+    msg_id: u32,
+}
 
-            if let CtrlMsg::InvalidMsg { .. } = reply {
-                println!("Invalid msg received");
-                reply_summary.invalid_reply.push((a, reply));
-            } else {
-                reply_summary.normal_reply.push((a, reply));
-            }
+impl Drop for DeviceStream {
+    fn drop(&mut self) {
+        self.ctrl.cancel(self.msg_id);
+    }
+}
+
+impl Iterator for DeviceStream {
+    type Item = Result<DeviceInfo, CtrlError>;
 
-            println!(
-                "{} msg with id={} replied from {:?}",
-                Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+/// Like `find_devices_each` as an iterator, a failing broadcast ends it with the error
+pub fn find_devices_iter<A, B>(
+    baddr: A,
+    local_addr: B,
+    timeout: Option<Duration>,
+) -> Result<DeviceStream, CtrlError>
+where
+    A: ToSocketAddrs,
+    B: ToSocketAddrs,
+{
+    // bind and resolve here so that these fail before any thread is started
+    let ctrl = Controller::bind_with_options(local_addr, SocketOptions::for_discovery(0))?
+        .with_timeout(timeout);
+    let ctrl = Arc::new(ctrl);
+    let baddr = baddr
+        .to_socket_addrs()
+        .map_err(CtrlError::Resolve)?
+        .collect::<Vec<_>>();
+    let msg_id = next_session_ids(1)[0];
+    let (tx, rx) = channel();
+    {
+        let ctrl = Arc::clone(&ctrl);
+        std::thread::spawn(move || {
+            let mut on_device = |d| tx.send(Ok(d)).is_ok();
+            let result = ctrl.broadcast_with_id_each(
+                CtrlMsg::Query { msg_id: 0 },
+                &baddr[..],
                 msg_id,
-                a
+                CollectUntil::default(),
+                device_of(&mut on_device),
             );
-            let x = msg_set.remove(&msg_id);
-            assert!(x);
-            if msg_set.is_empty() {
-                break;
+            if let Err(e) = result {
+                let _ = tx.send(Err(e));
             }
-        }
+        });
     }
-    //reply_summary.no_reply = msg_set.into_iter().map(|i| i as usize).collect();
-    reply_summary.no_reply = addr_msg_id_map
-        .iter()
-        .filter(|&(k, _v)| msg_set.contains(k))
-        .map(|(&k, v)| (v.clone(), k))
-        .collect();
-    reply_summary
+    Ok(DeviceStream { rx, ctrl, msg_id })
 }
 
-pub fn bcast_cmd<A, B>(
-    mut cmd: CtrlMsg,
+/// Like `bcast_cmd`, finishing as soon as the expected number of devices
+/// have answered or the deadline of `until` has passed
+pub fn bcast_cmd_until<A, B>(
+    cmd: CtrlMsg,
     baddr: A,
     local_addr: B,
     timeout: Option<Duration>,
-    debug_level: u32,
-) -> CmdReplySummary
+    until: CollectUntil,
+) -> Result<CmdReplySummary, CtrlError>
 where
     A: ToSocketAddrs,
     B: ToSocketAddrs,
 {
-    let mut rng1=rng();
-    let socket = UdpSocket::bind(local_addr).expect("failed to bind");
-    socket.set_broadcast(true).expect("broadcast set failed");
-    socket
-        .set_nonblocking(true)
-        .expect("nonblocking set failed");
+    Controller::bind_with_options(
+        local_addr,
+        SocketOptions::for_discovery(until.expected.unwrap_or(0)),
+    )?
+    .with_timeout(timeout)
+    .broadcast_with_id(cmd, baddr, next_session_ids(1)[0], until)
+}
 
-    socket
-        .set_read_timeout(timeout)
-        .expect("failed to set timeout");
+/// Like `bcast_cmd_until`, broadcasting to each of `slices` in turn with a pause of `gap`,
+/// so that a large array does not answer all at once
+pub fn discover_cmd<A, B>(
+    cmd: CtrlMsg,
+    slices: &[A],
+    local_addr: B,
+    timeout: Option<Duration>,
+    until: CollectUntil,
+    gap: Duration,
+) -> Result<CmdReplySummary, CtrlError>
+where
+    A: ToSocketAddrs,
+    B: ToSocketAddrs,
+{
+    Controller::bind_with_options(
+        local_addr,
+        SocketOptions::for_discovery(until.expected.unwrap_or(0)),
+    )?
+    .with_timeout(timeout)
+    .discover(cmd, slices, until, gap)
+}
 
-    let mut reply_summary = CmdReplySummary::default();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let msg_id: u32 = rng1.random();
-    cmd.set_msg_id(msg_id);
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 3000))
+    }
 
-    let mut buf = Cursor::new(Vec::new());
-    cmd.write(&mut buf).expect("failed to write cmd to buf");
-    let buf = buf.into_inner();
-    socket.send_to(&buf, baddr).expect("send error");
-
-    println!(
-        "{} msg with id={} sent",
-        Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-        0,
-    );
-    print_bytes(&buf);
-
-    println!("{cmd:?}");
-
-    let mut buf = vec![0_u8; 9000];
-    while let Ok((l, a)) = socket.recv_from(&mut buf) {
-        //let (_s, _a)=socket.recv_from(&mut buf).unwrap();
-        if debug_level >= 1 {
-            println!(
-                "{} received {} bytes, {} words from {:?}:",
-                Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                l,
-                l / 4,
-                a
-            );
-            print_bytes(&buf[..l]);
-        }
-        let buf1 = std::mem::replace(&mut buf, vec![0_u8; 9000]);
-        let mut cursor = Cursor::new(buf1);
-        let reply = CtrlMsg::read(&mut cursor).expect("failed to read reply");
-
-        let msg_id = reply.get_msg_id();
-        if let CtrlMsg::InvalidMsg { .. } = reply {
-            println!(
-                "{} Invalid msg {:?}",
-                Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                reply
-            );
-            reply_summary.invalid_reply.push((a, reply));
-        } else {
-            reply_summary.normal_reply.push((a, reply));
+    fn xgbe_cfg() -> XGbeCfg {
+        XGbeCfg {
+            dst_mac: [0; 6],
+            src_mac: [0; 6],
+            dst_ip: [0; 4],
+            src_ip: [0; 4],
+            dst_port: 0,
+            src_port: 0,
         }
+    }
 
-        println!(
-            "{} msg with id={} replied from {:?}",
-            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-            msg_id,
-            a
-        );
+    #[test]
+    fn every_known_magic_decodes_to_its_variant() {
+        let mut names = BTreeMap::new();
+        for &magic in KNOWN_MAGICS {
+            // msg_id and every other field 0, lists empty
+            let mut data = magic.to_le_bytes().to_vec();
+            data.resize(512, 0);
+            if magic == 0xff_00_00_01 {
+                // the health report after the 6 words of a QueryReply has a magic of its own
+                data[28..32].copy_from_slice(&0x00_00_01_fe_u32.to_le_bytes());
+            }
+            let msg = CtrlMsg::read(&mut Cursor::new(&data)).unwrap();
+            assert!(!matches!(msg, CtrlMsg::Unknown { .. }), "0x{magic:08x}");
+            assert_eq!(msg.magic(), magic, "{}", msg.name());
+            assert_eq!(names.insert(msg.name(), magic), None, "{}", msg.name());
+        }
+        let unknown = CtrlMsg::read(&mut Cursor::new([0x0e, 0, 0, 0, 1, 0, 0, 0])).unwrap();
+        assert!(matches!(unknown, CtrlMsg::Unknown { magic: 0x0e, .. }));
     }
 
-    println!("==waiting for the rest replies==");
-    socket
-        .set_nonblocking(false)
-        .expect("nonblocking set failed");
+    #[test]
+    fn error_code_round_trip() {
+        for raw in [0, 1, 2, 3, 4, 0xffff_ffff] {
+            assert_eq!(u32::from(DeviceErrorCode::from(raw)), raw);
+        }
+        assert_eq!(DeviceErrorCode::from(3), DeviceErrorCode::XGbePortOutOfRange);
+        assert_eq!(DeviceErrorCode::from(7), DeviceErrorCode::Other(7));
+    }
 
-    let mut buf = vec![0_u8; 9000];
+    #[test]
+    fn hint_of_the_matching_cmd() {
+        let sync = CtrlMsg::Sync { msg_id: 0 };
+        let init = CtrlMsg::Init {
+            msg_id: 0,
+            reserved_zeros: 0,
+        };
+        let xgbe = CtrlMsg::XGbeCfgSingle {
+            msg_id: 0,
+            port_id: 5,
+            cfg: xgbe_cfg(),
+        };
+        let query = CtrlMsg::Query { msg_id: 0 };
+        let cases = [
+            (DeviceErrorCode::SyncFailure, &sync),
+            (DeviceErrorCode::InitFailure, &init),
+            (DeviceErrorCode::XGbePortOutOfRange, &xgbe),
+        ];
+        for (code, cmd) in cases {
+            assert!(code.hint(cmd).is_some(), "{code} for {}", cmd.name());
+            // the same code is not explained for any other cmd
+            for other in [&sync, &init, &xgbe, &query] {
+                if other.magic() != cmd.magic() {
+                    assert_eq!(code.hint(other), None, "{code} for {}", other.name());
+                }
+            }
+        }
+        assert_eq!(
+            DeviceErrorCode::XGbePortOutOfRange.hint(&xgbe),
+            Some("port_id is beyond the ports of the device")
+        );
+    }
 
-    while let Ok((l, a)) = socket.recv_from(&mut buf) {
-        if debug_level >= 1 {
-            println!(
-                "{} received {} bytes, {} words from {:?}:",
-                Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                l,
-                l / 4,
-                a
+    #[test]
+    fn hint_of_unknown_cmd_and_other_codes() {
+        let cmds = [
+            CtrlMsg::Query { msg_id: 0 },
+            CtrlMsg::Sync { msg_id: 0 },
+            CtrlMsg::StreamStart { msg_id: 0 },
+        ];
+        for cmd in cmds {
+            assert_eq!(
+                DeviceErrorCode::UnknownCmd.hint(&cmd),
+                Some("the firmware does not support this cmd")
             );
-            print_bytes(&buf[..l]);
+            assert_eq!(DeviceErrorCode::Other(42).hint(&cmd), None);
         }
+    }
 
-        let mut cursor = Cursor::new(buf.clone());
-        let reply = CtrlMsg::read(&mut cursor).expect("failed to read reply");
-        println!(
-            "{} \n{}",
-            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-            reply
-        );
+    #[test]
+    fn invalid_msg_outcome() {
+        let cmd = CtrlMsg::Sync { msg_id: 9 };
+        // not valid UTF-8, decoded lossily
+        let reply = CtrlMsg::InvalidMsg {
+            msg_id: 9,
+            err_code: 1,
+            len: 6,
+            description: b"no\xffpps".to_vec(),
+        };
+        let outcome = outcome_of(&cmd, reply);
+        let Outcome::DeviceError {
+            err_code,
+            description,
+            hint,
+        } = &outcome
+        else {
+            panic!("not a device error: {outcome:?}");
+        };
+        assert_eq!(*err_code, DeviceErrorCode::SyncFailure);
+        assert_eq!(description, "no\u{fffd}pps");
+        assert_eq!(*hint, DeviceErrorCode::SyncFailure.hint(&cmd));
+        match outcome.into_result(addr()) {
+            Err(e) => assert_eq!(e.device_error_code(), Some(DeviceErrorCode::SyncFailure)),
+            Ok(reply) => panic!("not an error: {reply:?}"),
+        }
+    }
 
-        let msg_id = reply.get_msg_id();
+    /// The fields of the `Inconsistent` error `r` fails with
+    fn inconsistent<T: std::fmt::Debug>(
+        r: Result<T, CtrlError>,
+    ) -> (&'static str, &'static str, u32, usize) {
+        match r {
+            Err(CtrlError::Inconsistent {
+                msg,
+                field,
+                declared,
+                actual,
+            }) => (msg, field, declared, actual),
+            r => panic!("not an inconsistency: {r:?}"),
+        }
+    }
 
-        if let CtrlMsg::InvalidMsg { .. } = reply {
-            println!("Invalid msg received");
-            reply_summary.invalid_reply.push((a, reply));
-        } else {
-            reply_summary.normal_reply.push((a, reply));
+    #[test]
+    fn validate_rejects_every_length_mismatch() {
+        let cases = [
+            (
+                CtrlMsg::InvalidMsg {
+                    msg_id: 0,
+                    err_code: 0,
+                    len: 2,
+                    description: vec![1],
+                },
+                ("InvalidMsg", "len", 2, 1),
+            ),
+            (
+                CtrlMsg::QueryReply {
+                    msg_id: 0,
+                    fm_ver: 0,
+                    tick_cnt1: 0,
+                    tick_cnt2: 0,
+                    trans_state: 0,
+                    locked: 0,
+                    health: Health::TEHealth {
+                        nhealth: 3,
+                        payload: vec![1, 2],
+                    },
+                },
+                ("TEHealth", "nhealth", 3, 2),
+            ),
+            (
+                CtrlMsg::I2CScanReply {
+                    msg_id: 0,
+                    ndev: 1,
+                    payload: vec![],
+                },
+                ("I2CScanReply", "ndev", 1, 0),
+            ),
+            (
+                CtrlMsg::I2CWrite {
+                    msg_id: 0,
+                    dev_addr: 0x1c,
+                    len: 4,
+                    payload: vec![0x11, 0x22],
+                },
+                ("I2CWrite", "len", 4, 2),
+            ),
+            (
+                CtrlMsg::I2CWriteReg {
+                    msg_id: 0,
+                    dev_addr: 0x1c,
+                    reg_addr: 1,
+                    len: 0,
+                    payload: vec![0x11],
+                },
+                ("I2CWriteReg", "len", 0, 1),
+            ),
+            (
+                CtrlMsg::I2CReadReply {
+                    msg_id: 0,
+                    err_code: 0,
+                    len: 5,
+                    payload: vec![0; 4],
+                },
+                ("I2CReadReply", "len", 5, 4),
+            ),
+            (
+                CtrlMsg::I2CReadRegReply {
+                    msg_id: 0,
+                    err_code: 0,
+                    len: 1,
+                    payload: vec![0; 2],
+                },
+                ("I2CReadRegReply", "len", 1, 2),
+            ),
+            (
+                CtrlMsg::XGbeCfgQueryReply {
+                    msg_id: 0,
+                    nports: 4,
+                    cfg: vec![xgbe_cfg()],
+                },
+                ("XGbeCfgQueryReply", "nports", 4, 1),
+            ),
+        ];
+        for (msg, expected) in cases {
+            assert_eq!(inconsistent(msg.validate()), expected);
         }
+    }
 
-        println!(
-            "{} msg with id={} replied from {:?}",
-            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-            msg_id,
-            a
-        );
+    #[test]
+    fn constructors_are_consistent() {
+        let msgs = [
+            CtrlMsg::invalid_msg(0, DeviceErrorCode::UnknownCmd, "unknown"),
+            CtrlMsg::i2c_scan_reply(0, vec![0x1c, 0x50]),
+            CtrlMsg::i2c_write(0x1c, vec![1, 2, 3]),
+            CtrlMsg::i2c_write_reg(0x1c, 2, vec![1]),
+            CtrlMsg::i2c_read_reply(0, 0, vec![1, 2]),
+            CtrlMsg::i2c_read_reg_reply(0, 0, vec![]),
+            CtrlMsg::xgbe_cfg_query_reply(0, vec![xgbe_cfg(); 4]),
+        ];
+        for msg in msgs {
+            assert!(msg.validate().is_ok(), "{}", msg.name());
+        }
+    }
+
+    #[test]
+    fn load_cmds_rejects_inconsistent_cmds() {
+        let yaml = "\
+- !Query
+  msg_id: 0
+- !I2CWrite
+  msg_id: 0
+  dev_addr: 0x1c
+  len: 4
+  payload: [0x11, 0x22, 0x33]
+";
+        assert_eq!(inconsistent(load_cmds(yaml.as_bytes())), ("I2CWrite", "len", 4, 3));
+    }
+
+    #[test]
+    fn load_cmds_rejects_malformed_yaml() {
+        let malformed = ["- !NoSuchCmd\n  msg_id: 0\n", "- !Query\n  id: 0\n", "[unclosed"];
+        for yaml in malformed {
+            match load_cmds(yaml.as_bytes()) {
+                Err(CtrlError::Yaml(_)) => {}
+                r => panic!("{yaml:?} not rejected as YAML: {r:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn load_cmds_accepts_the_shipped_files() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/cmd");
+        for entry in std::fs::read_dir(dir).expect("cmd dir") {
+            let path = entry.expect("dir entry").path();
+            let file = std::fs::File::open(&path).expect("readable cmd file");
+            let cmds = load_cmds(file).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+            assert!(!cmds.is_empty(), "{}", path.display());
+        }
     }
-    reply_summary
 }
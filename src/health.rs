@@ -0,0 +1,383 @@
+use std::{collections::BTreeMap, fmt::Display, io::Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ctrl_msg::Health;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Volt,
+    Celsius,
+    Count,
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Unit::Volt => write!(f, "V"),
+            Unit::Celsius => write!(f, "°C"),
+            Unit::Count => Ok(()),
+        }
+    }
+}
+
+/// Inclusive nominal range of a reading
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Range {
+    pub fn contains(&self, x: f64) -> bool {
+        self.min <= x && x <= self.max
+    }
+}
+
+/// Nominal range of each reading, keyed by reading name, e.g.
+/// ```yaml
+/// vcc1v8: {min: 1.71, max: 1.89}
+/// temperature0: {min: 0.0, max: 85.0}
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HealthThresholds(pub BTreeMap<String, Range>);
+
+impl HealthThresholds {
+    pub fn from_yaml<R: Read>(reader: R) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_reader(reader)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Range> {
+        self.0.get(name).copied()
+    }
+}
+
+impl Default for HealthThresholds {
+    /// ±10% for the 12 V rails, ±5% for the others, 0~85 °C for the temperatures
+    fn default() -> Self {
+        let mut ranges = BTreeMap::new();
+        for (name, nominal, tol) in [
+            ("volt12_inner", 12.0, 0.10),
+            ("volt12_input", 12.0, 0.10),
+            ("vcc1v0", 1.0, 0.05),
+            ("vcc1v8", 1.8, 0.05),
+            ("mgtavtt1v2", 1.2, 0.05),
+            ("mgtavtt1v0", 1.0, 0.05),
+        ] {
+            ranges.insert(
+                name.to_string(),
+                Range {
+                    min: nominal * (1.0 - tol),
+                    max: nominal * (1.0 + tol),
+                },
+            );
+        }
+        for name in ["temperature0", "temperature1", "temperature"] {
+            ranges.insert(
+                name.to_string(),
+                Range {
+                    min: 0.0,
+                    max: 85.0,
+                },
+            );
+        }
+        HealthThresholds(ranges)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Reading {
+    pub name: String,
+    pub value: f64,
+    pub unit: Unit,
+    /// nominal range, `None` if no threshold is configured for this reading
+    pub range: Option<Range>,
+}
+
+impl Reading {
+    pub fn in_range(&self) -> bool {
+        self.range.is_none_or(|r| r.contains(self.value))
+    }
+}
+
+impl Display for Reading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = if self.in_range() { "" } else { " out of range" };
+        match self.unit {
+            Unit::Count => write!(f, "{}{state}: {}", self.name, self.value),
+            unit => write!(f, "{}{state}: {:.3} {unit}", self.name, self.value),
+        }
+    }
+}
+
+/// Health readings of a QueryReply converted to physical units.
+///
+/// The HL voltages are reported in mV and its temperatures in m°C. No protocol
+/// document gives the unit of the T510 temperature, it is assumed to be m°C like
+/// the HL ones, signed so that it can go below 0 °C.
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+    /// HL, TE or T510
+    pub kind: &'static str,
+    pub readings: Vec<Reading>,
+}
+
+impl HealthReport {
+    pub fn new(health: &Health, thresholds: &HealthThresholds) -> Self {
+        let mut readings = Vec::new();
+        let mut push = |name: String, value: f64, unit: Unit| {
+            let range = thresholds.get(&name);
+            readings.push(Reading {
+                name,
+                value,
+                unit,
+                range,
+            })
+        };
+        let kind = match health {
+            Health::HLHealth {
+                nhealth: _,
+                xgbe_state,
+                pkt_sent,
+                volt12_inner,
+                volt12_input,
+                vcc1v0,
+                vcc1v8,
+                mgtavtt1v2,
+                mgtavtt1v0,
+                temperatures,
+            } => {
+                for (i, &x) in xgbe_state.iter().enumerate() {
+                    push(format!("xgbe_state{i}"), x as f64, Unit::Count);
+                }
+                for (i, &x) in pkt_sent.iter().enumerate() {
+                    push(format!("pkt_sent{i}"), x as f64, Unit::Count);
+                }
+                // voltages are reported in mV
+                for (name, &x) in [
+                    ("volt12_inner", volt12_inner),
+                    ("volt12_input", volt12_input),
+                    ("vcc1v0", vcc1v0),
+                    ("vcc1v8", vcc1v8),
+                    ("mgtavtt1v2", mgtavtt1v2),
+                    ("mgtavtt1v0", mgtavtt1v0),
+                ] {
+                    push(name.to_string(), x as f64 / 1000.0, Unit::Volt);
+                }
+                // temperatures are reported in m°C
+                for (i, &x) in temperatures.iter().enumerate() {
+                    push(format!("temperature{i}"), x as f64 / 1000.0, Unit::Celsius);
+                }
+                "HL"
+            }
+            Health::TEHealth {
+                nhealth: _,
+                payload,
+            } => {
+                for (i, &x) in payload.iter().enumerate() {
+                    push(format!("health{i}"), x as f64, Unit::Count);
+                }
+                "TE"
+            }
+            Health::T510Health {
+                rfdc_restart_cnt,
+                temperature,
+            } => {
+                push(
+                    "rfdc_restart_cnt".to_string(),
+                    *rfdc_restart_cnt as f64,
+                    Unit::Count,
+                );
+                // unit assumed, see the doc of `HealthReport`
+                push(
+                    "temperature".to_string(),
+                    *temperature as f64 / 1000.0,
+                    Unit::Celsius,
+                );
+                "T510"
+            }
+        };
+        HealthReport { kind, readings }
+    }
+
+    pub fn out_of_range(&self) -> impl Iterator<Item = &Reading> {
+        self.readings.iter().filter(|r| !r.in_range())
+    }
+
+    pub fn all_in_range(&self) -> bool {
+        self.out_of_range().next().is_none()
+    }
+}
+
+impl Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} health:", self.kind)?;
+        for r in &self.readings {
+            writeln!(f, "  {r}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOLTAGES: [&str; 6] = [
+        "volt12_inner",
+        "volt12_input",
+        "vcc1v0",
+        "vcc1v8",
+        "mgtavtt1v2",
+        "mgtavtt1v0",
+    ];
+
+    fn hl_health(millivolts: [u32; 6], temperatures: [u32; 2]) -> Health {
+        let [
+            volt12_inner,
+            volt12_input,
+            vcc1v0,
+            vcc1v8,
+            mgtavtt1v2,
+            mgtavtt1v0,
+        ] = millivolts;
+        Health::HLHealth {
+            nhealth: 0,
+            xgbe_state: [0; 4],
+            pkt_sent: [0; 4],
+            volt12_inner,
+            volt12_input,
+            vcc1v0,
+            vcc1v8,
+            mgtavtt1v2,
+            mgtavtt1v0,
+            temperatures,
+        }
+    }
+
+    /// Raw readings of a healthy HL device
+    const NOMINAL_MV: [u32; 6] = [12000, 12000, 1000, 1800, 1200, 1000];
+    const NOMINAL_MC: [u32; 2] = [40000, 30000];
+
+    fn reading(report: &HealthReport, name: &str) -> Reading {
+        report
+            .readings
+            .iter()
+            .find(|r| r.name == name)
+            .unwrap_or_else(|| panic!("no reading {name}"))
+            .clone()
+    }
+
+    /// The raw values just below, at and just above both ends of `range`,
+    /// with whether they are in range
+    fn boundaries(range: Range) -> [(i64, bool); 6] {
+        let min = (range.min * 1000.0).round() as i64;
+        let max = (range.max * 1000.0).round() as i64;
+        [
+            (min - 1, false),
+            (min, true),
+            (min + 1, true),
+            (max - 1, true),
+            (max, true),
+            (max + 1, false),
+        ]
+    }
+
+    fn check_boundaries(thresholds: &HealthThresholds) {
+        for (i, name) in VOLTAGES.iter().enumerate() {
+            let range = thresholds.get(name).expect("threshold of every voltage");
+            for (raw, in_range) in boundaries(range) {
+                let mut millivolts = NOMINAL_MV;
+                millivolts[i] = raw as u32;
+                let report = HealthReport::new(&hl_health(millivolts, NOMINAL_MC), thresholds);
+                let r = reading(&report, name);
+                assert_eq!(r.in_range(), in_range, "{name} at {raw} mV");
+                assert_eq!(report.all_in_range(), in_range, "{name} at {raw} mV");
+            }
+        }
+        for i in 0..2 {
+            let name = format!("temperature{i}");
+            let range = thresholds
+                .get(&name)
+                .expect("threshold of every temperature");
+            // below 0 °C does not fit the unsigned HL reading
+            for (raw, in_range) in boundaries(range).into_iter().filter(|(raw, _)| *raw >= 0) {
+                let mut temperatures = NOMINAL_MC;
+                temperatures[i] = raw as u32;
+                let report = HealthReport::new(&hl_health(NOMINAL_MV, temperatures), thresholds);
+                assert_eq!(
+                    reading(&report, &name).in_range(),
+                    in_range,
+                    "{name} at {raw} m°C"
+                );
+            }
+        }
+        let range = thresholds.get("temperature").expect("threshold of T510");
+        for (raw, in_range) in boundaries(range) {
+            let health = Health::T510Health {
+                rfdc_restart_cnt: 0,
+                temperature: raw as i32,
+            };
+            let report = HealthReport::new(&health, thresholds);
+            assert_eq!(
+                reading(&report, "temperature").in_range(),
+                in_range,
+                "T510 temperature at {raw} m°C"
+            );
+        }
+    }
+
+    #[test]
+    fn default_threshold_boundaries() {
+        check_boundaries(&HealthThresholds::default());
+    }
+
+    #[test]
+    fn yaml_threshold_boundaries() {
+        let yaml = include_str!("../doc/health_thresholds.yaml");
+        let thresholds = HealthThresholds::from_yaml(yaml.as_bytes()).expect("valid yaml");
+        check_boundaries(&thresholds);
+    }
+
+    #[test]
+    fn units() {
+        let report = HealthReport::new(
+            &hl_health(NOMINAL_MV, NOMINAL_MC),
+            &HealthThresholds::default(),
+        );
+        assert_eq!(report.kind, "HL");
+        assert!(report.all_in_range());
+        let r = reading(&report, "vcc1v8");
+        assert_eq!((r.value, r.unit), (1.8, Unit::Volt));
+        let r = reading(&report, "temperature0");
+        assert_eq!((r.value, r.unit), (40.0, Unit::Celsius));
+        assert_eq!(reading(&report, "pkt_sent0").unit, Unit::Count);
+
+        let health = Health::T510Health {
+            rfdc_restart_cnt: 3,
+            temperature: -1500,
+        };
+        let report = HealthReport::new(&health, &HealthThresholds::default());
+        assert_eq!(report.kind, "T510");
+        assert_eq!(reading(&report, "temperature").value, -1.5);
+        assert_eq!(reading(&report, "rfdc_restart_cnt").value, 3.0);
+        assert_eq!(
+            report
+                .out_of_range()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>(),
+            ["temperature out of range: -1.500 °C"]
+        );
+    }
+
+    #[test]
+    fn reading_without_threshold() {
+        let report = HealthReport::new(
+            &hl_health([0; 6], [0; 2]),
+            &HealthThresholds(BTreeMap::new()),
+        );
+        assert!(report.all_in_range());
+        assert!(reading(&report, "vcc1v8").range.is_none());
+    }
+}
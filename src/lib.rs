@@ -1,2 +1,15 @@
+#[cfg(feature = "async")]
+pub mod async_ctrl;
 pub mod c_interface;
+pub mod client;
+pub mod controller;
 pub mod ctrl_msg;
+pub mod health;
+pub mod logging;
+pub mod msg_id;
+pub(crate) mod reactor;
+pub mod retry;
+pub mod sim;
+pub mod status;
+pub mod transport;
+pub mod typed_msg;
@@ -0,0 +1,52 @@
+use std::{fmt::Display, io::IsTerminal};
+
+use chrono::Local;
+use tracing::Level;
+use tracing_subscriber::fmt::{format::Writer, time::FormatTime};
+
+/// Local time with milliseconds, what the tools always printed
+struct LocalTime;
+
+impl FormatTime for LocalTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        write!(w, "{}", Local::now().format("%Y-%m-%d %H:%M:%S%.3f"))
+    }
+}
+
+/// Level shown for the `-d` debug level of the binaries:
+/// 0 shows what is sent and replied, 1 adds the details, 2 the hex dumps
+pub fn level_of(debug_level: u32) -> Level {
+    match debug_level {
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    }
+}
+
+/// Installs a subscriber printing the events of the library to stdout.
+///
+/// Applications embedding the library install their own instead, without one
+/// the library is silent.
+pub fn init(debug_level: u32) {
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(level_of(debug_level))
+        .with_timer(LocalTime)
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal())
+        .try_init();
+}
+
+/// Renders a datagram as rows of 4 bytes, the way `print_bytes` does
+pub struct HexDump<'a>(pub &'a [u8]);
+
+impl Display for HexDump<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, w) in self.0.chunks(4).enumerate() {
+            for &b in w {
+                write!(f, "{b:02x} ")?;
+            }
+            writeln!(f, "| {i:02} {}:{}", i * 4, i * 4 + 3)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    fmt::Debug,
+    sync::{Mutex, OnceLock},
+};
+
+use rand::{rng, Rng};
+
+/// Hands out the msg_id of each cmd sent
+pub trait MsgIdAllocator: Send + Debug {
+    fn next_id(&mut self) -> u32;
+}
+
+/// Monotonic counter, optionally with a session prefix in the upper 16 bits
+/// so that ids of concurrent sessions on the same port do not collide
+#[derive(Clone, Debug)]
+pub struct SequentialIds {
+    prefix: Option<u16>,
+    counter: u32,
+}
+
+impl SequentialIds {
+    pub fn new() -> Self {
+        SequentialIds {
+            prefix: None,
+            counter: 0,
+        }
+    }
+
+    pub fn with_prefix(prefix: u16) -> Self {
+        SequentialIds {
+            prefix: Some(prefix),
+            counter: 0,
+        }
+    }
+
+    /// Sequential ids under a random session prefix
+    pub fn with_random_prefix() -> Self {
+        SequentialIds::with_prefix(rng().random())
+    }
+}
+
+impl Default for SequentialIds {
+    fn default() -> Self {
+        SequentialIds::new()
+    }
+}
+
+impl MsgIdAllocator for SequentialIds {
+    fn next_id(&mut self) -> u32 {
+        match self.prefix {
+            Some(prefix) => {
+                // 0 is skipped, it is what hand written cmd files carry
+                self.counter = (self.counter % 0xffff) + 1;
+                ((prefix as u32) << 16) | self.counter
+            }
+            None => {
+                self.counter = self.counter.wrapping_add(1).max(1);
+                self.counter
+            }
+        }
+    }
+}
+
+/// Ids tried before `next_free_id` gives up skipping
+const FREE_ID_TRIES: usize = 64;
+
+/// Allocates the next id of `ids` that is not `in_use`, e.g. still in flight after
+/// the counter wrapped around. Gives up after a few tries, the caller then sees the
+/// id rejected as in use.
+pub(crate) fn next_free_id(ids: &mut dyn MsgIdAllocator, in_use: impl Fn(u32) -> bool) -> u32 {
+    let mut id = ids.next_id();
+    for _ in 1..FREE_ID_TRIES {
+        if !in_use(id) {
+            break;
+        }
+        id = ids.next_id();
+    }
+    id
+}
+
+/// Independent random ids, what `send_cmd` used to do
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomIds;
+
+impl MsgIdAllocator for RandomIds {
+    fn next_id(&mut self) -> u32 {
+        rng().random()
+    }
+}
+
+/// Allocator shared by the free functions `send_cmd` and `bcast_cmd` within this process
+pub fn session_ids() -> &'static Mutex<SequentialIds> {
+    static IDS: OnceLock<Mutex<SequentialIds>> = OnceLock::new();
+    IDS.get_or_init(|| Mutex::new(SequentialIds::with_random_prefix()))
+}
+
+/// Allocates `n` ids from the session allocator
+pub fn next_session_ids(n: usize) -> Vec<u32> {
+    let mut ids = session_ids().lock().expect("msg_id allocator poisoned");
+    (0..n).map(|_| ids.next_id()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential_ids_skip_0() {
+        let mut ids = SequentialIds::new();
+        assert_eq!(ids.next_id(), 1);
+        assert_eq!(ids.next_id(), 2);
+        ids.counter = u32::MAX - 1;
+        assert_eq!(ids.next_id(), u32::MAX);
+        // wraps around past 0
+        assert_eq!(ids.next_id(), 1);
+    }
+
+    #[test]
+    fn prefixed_ids_wrap_within_the_prefix() {
+        let mut ids = SequentialIds::with_prefix(0xabcd);
+        assert_eq!(ids.next_id(), 0xabcd_0001);
+        ids.counter = 0xfffe;
+        assert_eq!(ids.next_id(), 0xabcd_ffff);
+        assert_eq!(ids.next_id(), 0xabcd_0001);
+        assert_eq!(ids.next_id(), 0xabcd_0002);
+    }
+
+    #[test]
+    fn prefixed_ids_cycle_through_all_low_halves() {
+        let mut ids = SequentialIds::with_prefix(7);
+        let seen = (0..0xffff)
+            .map(|_| ids.next_id())
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(seen.len(), 0xffff);
+        assert!(seen.iter().all(|id| id >> 16 == 7 && id & 0xffff != 0));
+        assert_eq!(ids.next_id(), 0x0007_0001);
+    }
+
+    #[test]
+    fn free_ids_skip_the_ones_in_flight() {
+        let mut ids = SequentialIds::with_prefix(1);
+        ids.counter = 0xfffe;
+        let in_flight = [0x0001_ffff, 0x0001_0001, 0x0001_0002];
+        let in_use = |id| in_flight.contains(&id);
+        assert_eq!(next_free_id(&mut ids, in_use), 0x0001_0003);
+        assert_eq!(next_free_id(&mut ids, in_use), 0x0001_0004);
+    }
+
+    #[test]
+    fn free_ids_give_up_when_all_are_in_use() {
+        let mut ids = SequentialIds::new();
+        let id = next_free_id(&mut ids, |_| true);
+        assert_eq!(id, FREE_ID_TRIES as u32);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, Sender},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use crate::{ctrl_msg::CtrlError, transport::Transport};
+
+/// Largest datagram a device sends, a jumbo frame
+const MAX_DATAGRAM: usize = 9000;
+
+/// A received datagram and its source
+pub(crate) type Datagram = (Vec<u8>, SocketAddr);
+
+/// Where the controller routes the received datagrams
+pub(crate) type Routes = Arc<Mutex<RouteTable<Sender<Datagram>>>>;
+
+/// Reply channel of each msg_id in flight.
+///
+/// A datagram carrying none of them, a late reply or one too short to hold
+/// a msg_id, goes to the newest request that takes the unrouted datagrams,
+/// which reports it in its summary.
+#[derive(Debug)]
+pub(crate) struct RouteTable<S> {
+    /// the key of the request and its channel
+    by_id: BTreeMap<u32, (u64, S)>,
+    /// requests taking the unrouted datagrams, newest last
+    unrouted: Vec<(u64, S)>,
+    /// msg_ids cancelled before their request registered
+    cancelled: BTreeSet<u32>,
+    next_key: u64,
+}
+
+impl<S> Default for RouteTable<S> {
+    fn default() -> Self {
+        RouteTable {
+            by_id: BTreeMap::new(),
+            unrouted: Vec::new(),
+            cancelled: BTreeSet::new(),
+            next_key: 0,
+        }
+    }
+}
+
+impl<S: Clone> RouteTable<S> {
+    pub(crate) fn contains(&self, msg_id: u32) -> bool {
+        self.by_id.contains_key(&msg_id) || self.cancelled.contains(&msg_id)
+    }
+
+    /// Routes `ids` to `tx`, and the unrouted datagrams as well if `unrouted`.
+    /// Returns the key to unregister them with.
+    pub(crate) fn register(
+        &mut self,
+        ids: &[u32],
+        tx: S,
+        unrouted: bool,
+    ) -> Result<u64, CtrlError> {
+        if let Some(&msg_id) = ids.iter().find(|&&id| self.contains(id)) {
+            return Err(CtrlError::IdInUse { msg_id });
+        }
+        let key = self.next_key;
+        self.next_key += 1;
+        let cancelled = ids
+            .iter()
+            .fold(false, |c, id| self.cancelled.remove(id) | c);
+        if cancelled {
+            // without a channel the request ends at once
+            return Ok(key);
+        }
+        for &id in ids {
+            self.by_id.insert(id, (key, tx.clone()));
+        }
+        if unrouted {
+            self.unrouted.push((key, tx));
+        }
+        Ok(key)
+    }
+
+    pub(crate) fn unregister(&mut self, key: u64, ids: &[u32]) {
+        for id in ids {
+            // a cancelled request no longer owns its ids
+            if self.by_id.get(id).is_some_and(|(k, _)| *k == key) {
+                self.by_id.remove(id);
+            }
+        }
+        self.unrouted.retain(|(k, _)| *k != key);
+    }
+
+    /// Drops the channel of the request waiting for `msg_id`, which then ends as
+    /// if nothing more came, or that of the request registering it next
+    pub(crate) fn cancel(&mut self, msg_id: u32) {
+        match self.by_id.get(&msg_id) {
+            Some(&(key, _)) => {
+                self.by_id.retain(|_, (k, _)| *k != key);
+                self.unrouted.retain(|(k, _)| *k != key);
+            }
+            None => {
+                self.cancelled.insert(msg_id);
+            }
+        }
+    }
+
+    /// Where a datagram carrying `msg_id` goes, `None` if no request takes it
+    pub(crate) fn route(&self, msg_id: Option<u32>) -> Option<&S> {
+        msg_id
+            .and_then(|id| self.by_id.get(&id).map(|(_, tx)| tx))
+            .or_else(|| self.unrouted.last().map(|(_, tx)| tx))
+    }
+}
+
+/// When a datagram went out, or why it could not
+pub(crate) type SendResult = std::io::Result<Instant>;
+
+/// How long the receive thread of a `Pump` waits before checking for shutdown
+const PUMP_INTERVAL: Duration = Duration::from_millis(50);
+
+/// I/O of a controller over any `Transport`, its UDP socket or e.g. a `Loopback`.
+///
+/// A thread blocks receiving into a buffer it reuses and routes each datagram
+/// to the request waiting for its msg_id, the callers send from their own threads.
+#[derive(Debug)]
+pub(crate) struct Pump {
+    transport: Arc<dyn Transport>,
+    local_addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Pump {
+    pub(crate) fn spawn(transport: Arc<dyn Transport>, routes: Routes) -> std::io::Result<Self> {
+        let local_addr = transport.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let transport = Arc::clone(&transport);
+            let shutdown = Arc::clone(&shutdown);
+            std::thread::Builder::new()
+                .name("sdaa_ctrl io".to_string())
+                .spawn(move || pump(&*transport, &routes, &shutdown))?
+        };
+        Ok(Pump {
+            transport,
+            local_addr,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Sends the datagrams in order, returning when each went out. A datagram
+    /// that fails does not keep the others from being sent.
+    pub(crate) fn send(&self, datagrams: Vec<(Vec<u8>, SocketAddr)>) -> Vec<SendResult> {
+        datagrams
+            .iter()
+            .map(|(data, addr)| {
+                // e.g. no route to that target, the others still go out
+                self.transport.send_to(data, *addr).inspect_err(|e| {
+                    warn!(%addr, error = %e, "send failed");
+                })?;
+                Ok(Instant::now())
+            })
+            .collect()
+    }
+
+    /// Datagrams dropped on the way in since the start, `None` if that is unknown
+    pub(crate) fn kernel_drops(&self) -> Option<u64> {
+        self.transport.kernel_drops()
+    }
+}
+
+impl Drop for Pump {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn pump(transport: &dyn Transport, routes: &Routes, shutdown: &AtomicBool) {
+    let mut buf = vec![0_u8; MAX_DATAGRAM];
+    while !shutdown.load(Ordering::Relaxed) {
+        match transport.recv_from(&mut buf, Some(Instant::now() + PUMP_INTERVAL)) {
+            Ok(Some((len, addr))) => dispatch(routes, &buf[..len], addr),
+            Ok(None) => {}
+            Err(e) => {
+                if !matches!(
+                    e.kind(),
+                    ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+                ) {
+                    warn!(error = %e, "receive failed");
+                    std::thread::sleep(PUMP_INTERVAL);
+                }
+            }
+        }
+    }
+}
+
+/// Waits until `end` for the next datagram routed to `rx`, forever if `None`
+pub(crate) fn wait(rx: &Receiver<Datagram>, end: Option<Instant>) -> Option<Datagram> {
+    match end {
+        Some(end) => rx
+            .recv_timeout(end.saturating_duration_since(Instant::now()))
+            .ok(),
+        None => rx.recv().ok(),
+    }
+}
+
+/// The msg_id follows the magic in every message
+pub(crate) fn peek_msg_id(data: &[u8]) -> Option<u32> {
+    data.get(4..8)
+        .map(|x| u32::from_le_bytes(x.try_into().expect("4 bytes")))
+}
+
+/// Hands a datagram to the request waiting for its msg_id
+fn dispatch(routes: &Routes, data: &[u8], addr: SocketAddr) {
+    let msg_id = peek_msg_id(data);
+    let routes = routes.lock().expect("routes poisoned");
+    // a reply to a request that has already given up is as late as a stray one
+    if routes
+        .route(msg_id)
+        .is_none_or(|tx| tx.send((data.to_vec(), addr)).is_err())
+    {
+        debug!(?msg_id, %addr, "stray datagram while no request is active");
+    }
+}
@@ -0,0 +1,624 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::{Rng, rng};
+use tracing::{debug, info, info_span, trace, warn};
+
+use crate::{
+    ctrl_msg::{
+        CmdReplySummary, CtrlError, CtrlMsg, Outcome, Pending, PendingMap, TargetReport,
+        encode_cmd, handle_reply,
+    },
+    logging::HexDump,
+    reactor::SendResult,
+};
+
+/// What `attempt_timeout` is measured against
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeoutMode {
+    /// time since the last datagram, every reply restarts the wait
+    #[default]
+    Idle,
+    /// time since the attempt was sent, however many replies trickle in
+    WallClock,
+}
+
+/// How unanswered targets are retried.
+///
+/// Every retry resends the cmd with the msg_id of the first attempt, so a late
+/// reply to an earlier attempt completes the request as well.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// attempts per target including the first one, at least 1
+    pub max_attempts: u32,
+    /// how long an attempt waits for replies, `None` waits forever
+    pub attempt_timeout: Option<Duration>,
+    pub timeout_mode: TimeoutMode,
+    /// pause before the first retry
+    pub initial_backoff: Duration,
+    /// upper bound of the pause between two attempts
+    pub max_backoff: Duration,
+    /// growth of the pause from one retry to the next, anything below 1
+    /// or NaN is taken as 1
+    pub multiplier: f64,
+    /// the pause is randomly scaled by a factor within `1 ± jitter`,
+    /// `jitter` being clamped to 0~1
+    pub jitter: f64,
+    /// overall time limit, counted from the first attempt
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// A single attempt waiting 1 s
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            attempt_timeout: Some(Duration::from_secs(1)),
+            timeout_mode: TimeoutMode::Idle,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            multiplier: 2.0,
+            jitter: 0.1,
+            deadline: None,
+        }
+    }
+}
+
+/// A single attempt with the given timeout, what a bare timeout used to mean
+impl From<Option<Duration>> for RetryPolicy {
+    fn from(attempt_timeout: Option<Duration>) -> Self {
+        RetryPolicy {
+            attempt_timeout,
+            ..Default::default()
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Up to `max_attempts` attempts of `attempt_timeout` each, with the default backoff
+    pub fn new(max_attempts: u32, attempt_timeout: Option<Duration>) -> Self {
+        RetryPolicy {
+            max_attempts,
+            attempt_timeout,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn with_timeout_mode(mut self, timeout_mode: TimeoutMode) -> Self {
+        self.timeout_mode = timeout_mode;
+        self
+    }
+
+    /// Pause before retry number `retry`, starting from 1, never above `max_backoff`
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let multiplier = if self.multiplier >= 1.0 {
+            self.multiplier
+        } else {
+            1.0
+        };
+        let exponent = i32::try_from(retry.saturating_sub(1)).unwrap_or(i32::MAX);
+        let base = self.initial_backoff.as_secs_f64() * multiplier.powi(exponent);
+        // 0 s times an infinite growth
+        let base = if base.is_nan() { 0.0 } else { base };
+        let base = base.min(self.max_backoff.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            rng().random_range(1.0 - jitter..=1.0 + jitter)
+        } else {
+            1.0
+        };
+        Duration::try_from_secs_f64(base * factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// The instant past which nothing is sent or waited for
+    pub(crate) fn deadline_from(&self, start: Instant) -> Option<Instant> {
+        self.deadline.map(|d| start + d)
+    }
+
+    /// When an attempt sent at `sent` stops waiting at the latest
+    pub(crate) fn attempt_end(&self, sent: Instant, deadline: Option<Instant>) -> Option<Instant> {
+        let end = match self.timeout_mode {
+            TimeoutMode::Idle => None,
+            TimeoutMode::WallClock => self.attempt_timeout.map(|t| sent + t),
+        };
+        earliest(end, deadline)
+    }
+
+    /// How long to wait for the next reply of an attempt ending at `end`
+    pub(crate) fn wait_time(&self, end: Option<Instant>) -> Option<Duration> {
+        match self.timeout_mode {
+            TimeoutMode::Idle => wait_time(self.attempt_timeout, end),
+            TimeoutMode::WallClock => wait_time(None, end),
+        }
+    }
+}
+
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// The idle timeout `idle`, shortened to what is left before `end`; `None` waits forever
+pub(crate) fn wait_time(idle: Option<Duration>, end: Option<Instant>) -> Option<Duration> {
+    let left = end.map(|e| e.saturating_duration_since(Instant::now()));
+    match (idle, left) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// What the driver of an `Exchange` has to do next
+pub(crate) enum Step {
+    /// send the datagrams in order and pass when each went out to `Exchange::sent`
+    Send(Vec<(Vec<u8>, SocketAddr)>),
+    /// wait for the next datagram for at most this long, forever if `None`, and pass
+    /// it to `Exchange::received`, or call `Exchange::timed_out` if none came
+    Wait(Option<Duration>),
+    /// every target has been answered or given up on, `Exchange::finish` has the result
+    Done,
+}
+
+enum Phase {
+    /// the next attempt goes out now
+    Send,
+    /// an attempt has been handed to the driver, `sent` is due
+    Sending,
+    /// collecting the replies to the attempt until `end`
+    Collect {
+        end: Option<Instant>,
+    },
+    /// pause before the next attempt, replies to the earlier ones are still accepted
+    Backoff {
+        resume: Instant,
+    },
+    Done,
+}
+
+/// Sends a cmd to its targets and matches the replies on (target, msg_id),
+/// resending to the unanswered targets as the `RetryPolicy` allows.
+///
+/// It does no I/O of its own, the blocking and the async controller drive it
+/// through the `Step`s it asks for.
+pub(crate) struct Exchange {
+    cmd: CtrlMsg,
+    retry: RetryPolicy,
+    pending: PendingMap,
+    summary: CmdReplySummary,
+    attempt: u32,
+    /// set once the first attempt goes out
+    deadline: Option<Instant>,
+    phase: Phase,
+}
+
+impl Exchange {
+    pub(crate) fn new(cmd: CtrlMsg, retry: RetryPolicy) -> Result<Self, CtrlError> {
+        cmd.validate()?;
+        Ok(Exchange {
+            cmd,
+            retry,
+            pending: PendingMap::new(),
+            summary: CmdReplySummary::default(),
+            attempt: 0,
+            deadline: None,
+            phase: Phase::Send,
+        })
+    }
+
+    /// Adds a target, `resolved` being all the addresses it resolved to
+    pub(crate) fn add_target(&mut self, resolved: Vec<SocketAddr>, msg_id: u32) {
+        let target = resolved[0];
+        self.summary.targets.insert(
+            target,
+            TargetReport {
+                msg_id,
+                outcome: Outcome::Timeout,
+                latency: None,
+                attempts: 0,
+            },
+        );
+        self.pending.entry(msg_id).or_default().push(Pending {
+            target,
+            resolved,
+            sent_at: Instant::now(),
+            span: info_span!("target", addr = %target, msg_id),
+        });
+    }
+
+    /// The msg_ids the replies carry, to be routed to this exchange before it sends
+    pub(crate) fn ids(&self) -> Vec<u32> {
+        self.pending.keys().copied().collect()
+    }
+
+    pub(crate) fn step(&mut self) -> Result<Step, CtrlError> {
+        loop {
+            match self.phase {
+                Phase::Send => return self.send().map(Step::Send),
+                Phase::Sending => unreachable!("attempt {} not reported as sent", self.attempt),
+                Phase::Collect { end } => {
+                    if self.pending.is_empty() {
+                        self.phase = Phase::Done;
+                        continue;
+                    }
+                    return Ok(Step::Wait(self.retry.wait_time(end)));
+                }
+                Phase::Backoff { resume } => {
+                    let left = resume.saturating_duration_since(Instant::now());
+                    if self.pending.is_empty() {
+                        self.phase = Phase::Done;
+                    } else if left.is_zero() {
+                        self.resume();
+                    } else {
+                        return Ok(Step::Wait(Some(left)));
+                    }
+                }
+                Phase::Done => return Ok(Step::Done),
+            }
+        }
+    }
+
+    /// The next attempt to the targets still pending
+    fn send(&mut self) -> Result<Vec<(Vec<u8>, SocketAddr)>, CtrlError> {
+        self.attempt += 1;
+        if self.attempt == 1 {
+            self.deadline = self.retry.deadline_from(Instant::now());
+            debug!("{}", self.cmd);
+        }
+        let mut datagrams = Vec::new();
+        for (&msg_id, waiting) in self.pending.iter() {
+            self.cmd.set_msg_id(msg_id);
+            let out = encode_cmd(&self.cmd)?;
+            for p in waiting.iter() {
+                trace!(parent: &p.span, "\n{}", HexDump(&out));
+                datagrams.push((out.clone(), p.resolved[0]));
+            }
+        }
+        self.phase = Phase::Sending;
+        Ok(datagrams)
+    }
+
+    /// When each datagram of the attempt went out, in the order of `Step::Send`.
+    /// A target whose datagram failed is given up on with the error.
+    pub(crate) fn sent(&mut self, sent: Vec<SendResult>) {
+        let attempt = self.attempt;
+        let summary = &mut self.summary;
+        let mut sent = sent.into_iter();
+        for waiting in self.pending.values_mut() {
+            waiting.retain_mut(|p| {
+                let Some(sent) = sent.next() else {
+                    return true;
+                };
+                let report = summary
+                    .targets
+                    .get_mut(&p.target)
+                    .expect("target not registered");
+                report.attempts = attempt;
+                match sent {
+                    Ok(sent_at) => {
+                        p.sent_at = sent_at;
+                        if attempt == 1 {
+                            info!(parent: &p.span, "sent");
+                        } else {
+                            info!(parent: &p.span, attempt, "resent");
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        warn!(parent: &p.span, error = %e, "send failed");
+                        report.outcome = Outcome::SendFailed(e);
+                        false
+                    }
+                }
+            });
+        }
+        self.pending.retain(|_, waiting| !waiting.is_empty());
+        let end = self.retry.attempt_end(Instant::now(), self.deadline);
+        self.phase = Phase::Collect { end };
+    }
+
+    pub(crate) fn received(&mut self, data: &[u8], addr: SocketAddr) {
+        handle_reply(&mut self.summary, &mut self.pending, &self.cmd, data, addr);
+    }
+
+    /// No datagram came within the wait of the last `Step::Wait`
+    pub(crate) fn timed_out(&mut self) {
+        match self.phase {
+            Phase::Collect { .. } => self.attempt_over(),
+            Phase::Backoff { .. } => self.resume(),
+            _ => {}
+        }
+    }
+
+    fn past_deadline(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    /// Pauses before the next attempt, or ends if none is left
+    fn attempt_over(&mut self) {
+        if self.attempt >= self.retry.max_attempts.max(1)
+            || self.pending.is_empty()
+            || self.past_deadline()
+        {
+            self.phase = Phase::Done;
+            return;
+        }
+        let resume = Instant::now() + self.retry.backoff(self.attempt);
+        let resume = self.deadline.map_or(resume, |d| resume.min(d));
+        self.phase = Phase::Backoff { resume };
+    }
+
+    /// Sends the next attempt once the pause is over, nothing goes out past the deadline
+    fn resume(&mut self) {
+        self.phase = if self.past_deadline() {
+            Phase::Done
+        } else {
+            Phase::Send
+        };
+    }
+
+    pub(crate) fn finish(self) -> CmdReplySummary {
+        info!(
+            unanswered = self.pending.values().map(Vec::len).sum::<usize>(),
+            "done"
+        );
+        self.summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(x: u64) -> Duration {
+        Duration::from_millis(x)
+    }
+
+    fn policy(multiplier: f64, jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: ms(100),
+            max_backoff: ms(1000),
+            multiplier,
+            jitter,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        let retry = policy(2.0, 0.0);
+        let pauses = (1..=6).map(|i| retry.backoff(i)).collect::<Vec<_>>();
+        assert_eq!(
+            pauses,
+            [ms(100), ms(200), ms(400), ms(800), ms(1000), ms(1000)]
+        );
+        assert_eq!(retry.backoff(0), ms(100));
+        assert_eq!(retry.backoff(u32::MAX), ms(1000));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let retry = policy(2.0, 0.25);
+        for _ in 0..1000 {
+            let pause = retry.backoff(2);
+            assert!(ms(150) <= pause && pause <= ms(250), "{pause:?}");
+            // the cap holds with the jitter as well
+            let pause = retry.backoff(5);
+            assert!(ms(750) <= pause && pause <= ms(1000), "{pause:?}");
+        }
+        // a jitter above 1 is clamped, the pause does not go negative
+        let retry = policy(1.0, 5.0);
+        for _ in 0..1000 {
+            assert!(retry.backoff(1) <= ms(200));
+        }
+    }
+
+    #[test]
+    fn odd_multipliers_do_not_panic() {
+        for multiplier in [-2.0, 0.0, 0.5, f64::NAN, f64::NEG_INFINITY] {
+            let retry = policy(multiplier, 0.0);
+            assert_eq!(retry.backoff(1), ms(100), "multiplier {multiplier}");
+            assert_eq!(retry.backoff(4), ms(100), "multiplier {multiplier}");
+        }
+        let retry = policy(f64::INFINITY, 0.0);
+        assert_eq!(retry.backoff(1), ms(100));
+        assert_eq!(retry.backoff(2), ms(1000));
+        let retry = policy(f64::MAX, 0.0);
+        assert_eq!(retry.backoff(3), ms(1000));
+        let retry = RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            ..policy(f64::INFINITY, 0.5)
+        };
+        assert_eq!(retry.backoff(3), Duration::ZERO);
+        let retry = policy(2.0, f64::NAN);
+        assert_eq!(retry.backoff(2), ms(200));
+    }
+
+    #[test]
+    fn attempt_ends_at_the_deadline() {
+        let now = Instant::now();
+        let retry = RetryPolicy::new(3, Some(ms(100))).with_timeout_mode(TimeoutMode::WallClock);
+        assert_eq!(retry.attempt_end(now, None), Some(now + ms(100)));
+        assert_eq!(
+            retry.attempt_end(now, Some(now + ms(30))),
+            Some(now + ms(30))
+        );
+        assert_eq!(
+            retry.attempt_end(now, Some(now + ms(300))),
+            Some(now + ms(100))
+        );
+
+        let retry = RetryPolicy::new(3, None).with_deadline(Some(ms(500)));
+        assert_eq!(retry.deadline_from(now), Some(now + ms(500)));
+        assert_eq!(
+            retry.attempt_end(now, retry.deadline_from(now)),
+            Some(now + ms(500))
+        );
+        assert_eq!(RetryPolicy::new(3, None).attempt_end(now, None), None);
+    }
+
+    #[test]
+    fn wait_is_cut_at_the_end() {
+        assert_eq!(wait_time(None, None), None);
+        assert_eq!(wait_time(Some(ms(100)), None), Some(ms(100)));
+        let end = Instant::now() + ms(50);
+        let left = wait_time(Some(ms(100)), Some(end)).unwrap();
+        assert!(left <= ms(50) && left > ms(40), "{left:?}");
+        assert!(wait_time(None, Some(end)).unwrap() <= ms(50));
+        // an end in the past means not waiting at all
+        let past = Instant::now() - ms(10);
+        assert_eq!(wait_time(Some(ms(100)), Some(past)), Some(Duration::ZERO));
+    }
+
+    /// Runs `exchange` against targets that never answer, returning the instants
+    /// of the attempts and when it finished
+    fn run_silent(mut exchange: Exchange) -> (Vec<Instant>, Instant, CmdReplySummary) {
+        let mut attempts = Vec::new();
+        loop {
+            match exchange.step().unwrap() {
+                Step::Send(datagrams) => {
+                    let now = Instant::now();
+                    attempts.push(now);
+                    exchange.sent(datagrams.iter().map(|_| Ok(now)).collect());
+                }
+                Step::Wait(wait) => {
+                    std::thread::sleep(wait.expect("bounded wait"));
+                    exchange.timed_out();
+                }
+                Step::Done => break,
+            }
+        }
+        (attempts, Instant::now(), exchange.finish())
+    }
+
+    fn exchange(retry: RetryPolicy) -> Exchange {
+        let mut exchange = Exchange::new(CtrlMsg::Query { msg_id: 0 }, retry).unwrap();
+        exchange.add_target(vec!["10.0.0.1:3000".parse().unwrap()], 1);
+        exchange
+    }
+
+    #[test]
+    fn retries_stop_at_the_deadline() {
+        let retry = RetryPolicy {
+            initial_backoff: ms(10),
+            jitter: 0.0,
+            ..RetryPolicy::new(100, Some(ms(40)))
+        }
+        .with_deadline(Some(ms(150)));
+        let (attempts, done, summary) = run_silent(exchange(retry));
+        let start = attempts[0];
+        // 40 ms waits with 10, 20, 40 ms pauses in between
+        assert!(
+            (2..=4).contains(&attempts.len()),
+            "{} attempts",
+            attempts.len()
+        );
+        assert!(attempts.iter().all(|&a| a < start + ms(150)));
+        assert!(
+            done >= start + ms(140) && done < start + ms(400),
+            "{:?}",
+            done - start
+        );
+        let report = &summary.targets[&"10.0.0.1:3000".parse().unwrap()];
+        assert_eq!(report.attempts as usize, attempts.len());
+        assert!(matches!(report.outcome, Outcome::Timeout));
+    }
+
+    #[test]
+    fn no_attempt_after_a_deadline_within_the_pause() {
+        let retry = RetryPolicy {
+            initial_backoff: ms(500),
+            jitter: 0.0,
+            ..RetryPolicy::new(3, Some(ms(20)))
+        }
+        .with_deadline(Some(ms(100)));
+        let (attempts, done, _) = run_silent(exchange(retry));
+        assert_eq!(attempts.len(), 1);
+        assert!(done < attempts[0] + ms(400), "{:?}", done - attempts[0]);
+    }
+
+    #[test]
+    fn attempts_are_capped() {
+        let retry = RetryPolicy {
+            initial_backoff: ms(1),
+            ..RetryPolicy::new(3, Some(ms(10)))
+        };
+        let (attempts, _, _) = run_silent(exchange(retry));
+        assert_eq!(attempts.len(), 3);
+        let retry = RetryPolicy::new(0, Some(ms(10)));
+        let (attempts, _, _) = run_silent(exchange(retry));
+        assert_eq!(attempts.len(), 1);
+    }
+
+    #[test]
+    fn wait_time_of_each_mode() {
+        let end = Some(Instant::now() + ms(500));
+        let idle = RetryPolicy::new(1, Some(ms(100)));
+        assert_eq!(idle.wait_time(end), Some(ms(100)));
+        assert_eq!(idle.wait_time(None), Some(ms(100)));
+        let wall_clock = idle.with_timeout_mode(TimeoutMode::WallClock);
+        let left = wall_clock.wait_time(end).unwrap();
+        assert!(left > ms(400) && left <= ms(500), "{left:?}");
+        // the end is what bounds a wall-clock attempt
+        assert_eq!(wall_clock.wait_time(None), None);
+    }
+
+    /// How long a single attempt lasts while a datagram from elsewhere trickles in
+    /// every 20 ms during the first 200 ms
+    fn attempt_with_trickle(mode: TimeoutMode) -> (Duration, CmdReplySummary) {
+        let retry = RetryPolicy::new(1, Some(ms(60))).with_timeout_mode(mode);
+        let mut exchange = exchange(retry);
+        let other = "10.0.0.9:3000".parse().unwrap();
+        let start = Instant::now();
+        loop {
+            match exchange.step().unwrap() {
+                Step::Send(datagrams) => {
+                    exchange.sent(datagrams.iter().map(|_| Ok(Instant::now())).collect())
+                }
+                Step::Wait(wait) => {
+                    let wait = wait.expect("bounded wait");
+                    if wait > ms(20) && start.elapsed() < ms(200) {
+                        std::thread::sleep(ms(20));
+                        exchange.received(&[0xff], other);
+                    } else {
+                        std::thread::sleep(wait);
+                        exchange.timed_out();
+                    }
+                }
+                Step::Done => break,
+            }
+        }
+        (start.elapsed(), exchange.finish())
+    }
+
+    #[test]
+    fn idle_timeout_restarts_with_every_datagram() {
+        let (took, summary) = attempt_with_trickle(TimeoutMode::Idle);
+        assert!(took >= ms(240), "{took:?}");
+        assert!(
+            summary.undecodable.len() >= 9,
+            "{}",
+            summary.undecodable.len()
+        );
+    }
+
+    #[test]
+    fn wall_clock_timeout_ignores_the_trickle() {
+        let (took, summary) = attempt_with_trickle(TimeoutMode::WallClock);
+        assert!(took >= ms(55) && took < ms(150), "{took:?}");
+        assert!(
+            summary.undecodable.len() <= 3,
+            "{}",
+            summary.undecodable.len()
+        );
+    }
+}
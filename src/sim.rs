@@ -0,0 +1,132 @@
+use std::{collections::BTreeMap, io::Cursor, net::SocketAddr};
+
+use binrw::BinRead;
+
+use crate::{
+    ctrl_msg::{
+        CtrlMsg::{self, *},
+        DeviceErrorCode, Health, encode_cmd,
+    },
+    transport::Device,
+};
+
+/// The reply of a healthy device, what `dummy_server` answers
+pub fn dummy_reply(msg: CtrlMsg) -> CtrlMsg {
+    match msg {
+        Query { msg_id } => QueryReply {
+            msg_id,
+            fm_ver: 0x24122420,
+            tick_cnt1: 10,
+            tick_cnt2: 10,
+            trans_state: 0,
+            locked: 0,
+            health: Health::HLHealth {
+                nhealth: 10,
+                xgbe_state: [10, 10, 10, 10],
+                pkt_sent: [0x1234, 0x1234, 0x1234, 0x1234],
+                volt12_inner: 12000,
+                volt12_input: 12000,
+                vcc1v0: 120000,
+                vcc1v8: 12000,
+                mgtavtt1v2: 12000,
+                mgtavtt1v0: 12000,
+                temperatures: [40000, 30000],
+            },
+        },
+        //QueryReply { msg_id } => *msg_id = mid,
+        Sync { msg_id } => SyncReply { msg_id },
+        //SyncReply { msg_id } => *msg_id = mid,
+        XGbeCfg { msg_id, .. } => XgbeCfgReply { msg_id },
+        //XgbeCfgReply { msg_id } => *msg_id = mid,
+        I2CScan { msg_id } => CtrlMsg::i2c_scan_reply(msg_id, vec![0x11, 0x22, 0x33, 0x44]),
+        //I2CScanReply { msg_id, .. } => *msg_id = mid,
+        I2CWrite { msg_id, .. } => I2CWriteReply {
+            msg_id,
+            err_code: 0,
+        },
+        //I2CWriteReply { msg_id, .. } => *msg_id = mid,
+        I2CWriteReg { msg_id, .. } => I2CWriteRegReply {
+            msg_id,
+            err_code: 0,
+        },
+        //I2CWriteRegReply { msg_id, .. } => *msg_id = mid,
+        I2CRead { msg_id, .. } => CtrlMsg::i2c_read_reply(msg_id, 0, vec![0; 10]),
+        //I2CReadReply { msg_id, .. } => *msg_id = mid,
+        I2CReadReg { msg_id, .. } => CtrlMsg::i2c_read_reg_reply(msg_id, 0, vec![0; 10]),
+        //I2CReadRegReply { msg_id, .. } => *msg_id = mid,
+        StreamStart { msg_id } => StreamStartReply { msg_id },
+        //StreamStartReply { msg_id } => *msg_id = mid,
+        StreamStop { msg_id } => StreamStopReply { msg_id },
+        //StreamStopReply { msg_id } => *msg_id = mid,
+        Init { msg_id, .. } => InitReply { msg_id },
+
+        PwrCtrl { msg_id, .. } => PwrCtrlReply { msg_id },
+
+        XGbeCfgSingle {
+            msg_id,
+            port_id: _,
+            cfg: _,
+        } => XGbeCfgSingleReply { msg_id },
+
+        XGbeCfgQuery { msg_id } => CtrlMsg::xgbe_cfg_query_reply(
+            msg_id,
+            vec![
+                crate::ctrl_msg::XGbeCfg {
+                    dst_ip: [192, 168, 4, 10],
+                    dst_mac: [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff],
+                    dst_port: 3000,
+                    src_ip: [192, 168, 10, 11],
+                    src_mac: [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xfe],
+                    src_port: 3000,
+                };
+                4
+            ],
+        ),
+
+        x => CtrlMsg::invalid_msg(x.get_msg_id(), DeviceErrorCode::UnknownCmd, "invalid"),
+    }
+}
+
+/// Devices answering like `dummy_server`, to put behind a `Loopback`
+#[derive(Clone, Debug, Default)]
+pub struct DummyDevices {
+    /// the devices answering what is sent to each address
+    responders: BTreeMap<SocketAddr, Vec<SocketAddr>>,
+}
+
+impl DummyDevices {
+    pub fn new() -> Self {
+        DummyDevices::default()
+    }
+
+    /// A device answering at `addr`
+    pub fn with_device(mut self, addr: SocketAddr) -> Self {
+        self.responders.entry(addr).or_default().push(addr);
+        self
+    }
+
+    /// Devices answering all at once what is sent to `baddr`
+    pub fn with_broadcast<I>(mut self, baddr: SocketAddr, devices: I) -> Self
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        self.responders.entry(baddr).or_default().extend(devices);
+        self
+    }
+}
+
+impl Device for DummyDevices {
+    fn handle(&mut self, addr: SocketAddr, data: &[u8]) -> Vec<(SocketAddr, Vec<u8>)> {
+        let Some(devices) = self.responders.get(&addr) else {
+            return Vec::new();
+        };
+        // like the real boards, an undecodable request goes unanswered
+        let Ok(msg) = CtrlMsg::read(&mut Cursor::new(data)) else {
+            return Vec::new();
+        };
+        let Ok(reply) = encode_cmd(&dummy_reply(msg)) else {
+            return Vec::new();
+        };
+        devices.iter().map(|&d| (d, reply.clone())).collect()
+    }
+}
@@ -0,0 +1,368 @@
+use std::{fmt::Display, io::Read, net::SocketAddr, time::Duration};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ctrl_msg::{CtrlMsg, Health},
+    typed_msg::{QueryReply, Reply},
+};
+
+/// Nominal number of 10 MHz ticks between two pps edges
+pub const NOMINAL_PPS_PERIOD: u32 = 10_000_000;
+
+/// Firmware version word, read as BCD `YY MM DD` followed by a revision byte,
+/// e.g. 0x24122420 is revision 0x20 of 2024-12-24.
+///
+/// `doc/query.typ` only says that bytes 8..11 of a QueryReply hold the firmware
+/// version. The date layout is an assumption, nothing in the protocol documents
+/// confirms it; `raw` keeps the word as sent and `date` is `None` for a word that
+/// does not fit the assumed layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub raw: u32,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub revision: u8,
+}
+
+fn from_bcd(x: u8) -> u8 {
+    (x >> 4) * 10 + (x & 0x0f)
+}
+
+impl From<u32> for FirmwareVersion {
+    fn from(raw: u32) -> Self {
+        let [revision, day, month, year] = raw.to_le_bytes();
+        FirmwareVersion {
+            raw,
+            year: 2000 + from_bcd(year) as u16,
+            month: from_bcd(month),
+            day: from_bcd(day),
+            revision,
+        }
+    }
+}
+
+impl FirmwareVersion {
+    /// The build date, `None` if the word does not hold a valid date
+    pub fn date(&self) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(self.year as i32, self.month as u32, self.day as u32)
+    }
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} rev 0x{:02x}",
+            self.year, self.month, self.day, self.revision
+        )
+    }
+}
+
+/// Thresholds deciding whether a device is healthy, shared by all the tools.
+/// Fields missing from a yaml file keep their default, e.g.
+/// ```yaml
+/// lock_mask: 0x2f
+/// max_pps_deviation: 2
+/// ```
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusCriteria {
+    /// bits of the lock word that have to be set
+    pub lock_mask: u32,
+    /// expected pps period in 10 MHz ticks
+    pub pps_period: u32,
+    /// tolerated deviation from `pps_period`, in ticks
+    pub max_pps_deviation: u32,
+    /// whether a sleeping device counts as unhealthy
+    pub require_awake: bool,
+}
+
+impl StatusCriteria {
+    pub fn from_yaml<R: Read>(reader: R) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_reader(reader)
+    }
+}
+
+impl Default for StatusCriteria {
+    fn default() -> Self {
+        StatusCriteria {
+            lock_mask: 0x0f,
+            pps_period: NOMINAL_PPS_PERIOD,
+            max_pps_deviation: 0,
+            require_awake: false,
+        }
+    }
+}
+
+/// Status fields of a QueryReply, decoded according to `doc/query.typ`
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceStatus {
+    pub firmware: FirmwareVersion,
+    /// data is being transmitted
+    pub streaming: bool,
+    /// the device is not sleeping
+    pub awake: bool,
+    /// the raw lock word
+    pub locked: u32,
+    /// lock flag of each PLL, taken from the lowest byte of the lock word
+    pub pll_locked: [bool; 8],
+    /// 10 MHz ticks between the last two pps edges
+    pub pps_period: u32,
+    /// `pps_period` minus the nominal period
+    pub pps_deviation: i64,
+}
+
+impl From<&QueryReply> for DeviceStatus {
+    fn from(r: &QueryReply) -> Self {
+        DeviceStatus::new(r.fm_ver, r.tick_cnt1, r.tick_cnt2, r.trans_state, r.locked)
+    }
+}
+
+impl DeviceStatus {
+    pub fn new(fm_ver: u32, tick_cnt1: u32, tick_cnt2: u32, trans_state: u32, locked: u32) -> Self {
+        let pps_period = tick_cnt2.wrapping_sub(tick_cnt1);
+        DeviceStatus {
+            firmware: FirmwareVersion::from(fm_ver),
+            streaming: trans_state & 0x01 != 0,
+            awake: trans_state & 0x02 != 0,
+            locked,
+            pll_locked: std::array::from_fn(|i| locked & (1 << i) != 0),
+            pps_period,
+            pps_deviation: pps_period as i64 - NOMINAL_PPS_PERIOD as i64,
+        }
+    }
+
+    /// Decodes the status of a QueryReply, `None` for other messages
+    pub fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        QueryReply::from_msg(msg).map(|r| DeviceStatus::from(&r))
+    }
+
+    pub fn lock_ok(&self, criteria: &StatusCriteria) -> bool {
+        self.locked & criteria.lock_mask == criteria.lock_mask
+    }
+
+    pub fn pps_ok(&self, criteria: &StatusCriteria) -> bool {
+        (self.pps_period as i64 - criteria.pps_period as i64).unsigned_abs()
+            <= criteria.max_pps_deviation as u64
+    }
+
+    pub fn is_healthy(&self, criteria: &StatusCriteria) -> bool {
+        self.problems(criteria).is_empty()
+    }
+
+    /// Human readable list of everything that violates `criteria`
+    pub fn problems(&self, criteria: &StatusCriteria) -> Vec<String> {
+        let mut result = Vec::new();
+        if !self.lock_ok(criteria) {
+            result.push(format!(
+                "lock state 0x{:x} misses 0x{:x}",
+                self.locked,
+                criteria.lock_mask & !self.locked
+            ));
+        }
+        if !self.pps_ok(criteria) {
+            result.push(format!(
+                "pps period {} ticks, expected {}±{}",
+                self.pps_period, criteria.pps_period, criteria.max_pps_deviation
+            ));
+        }
+        if criteria.require_awake && !self.awake {
+            result.push("device is sleeping".to_string());
+        }
+        result
+    }
+}
+
+impl Display for DeviceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "fw: {}, {}, {}, locked: 0x{:x}, pps period: {} ({:+})",
+            self.firmware,
+            if self.awake { "awake" } else { "sleeping" },
+            if self.streaming {
+                "streaming"
+            } else {
+                "not streaming"
+            },
+            self.locked,
+            self.pps_period,
+            self.pps_deviation
+        )
+    }
+}
+
+/// What discovery learned about a device answering a Query
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub addr: SocketAddr,
+    pub status: DeviceStatus,
+    pub health: Health,
+    /// round-trip time of the Query
+    pub latency: Option<Duration>,
+}
+
+impl DeviceInfo {
+    pub fn new(addr: SocketAddr, reply: &QueryReply, latency: Option<Duration>) -> Self {
+        DeviceInfo {
+            addr,
+            status: DeviceStatus::from(reply),
+            health: reply.health.clone(),
+            latency,
+        }
+    }
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.addr, self.status)?;
+        match self.latency {
+            Some(l) => write!(f, ", latency: {:.3} ms", l.as_secs_f64() * 1e3),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firmware_version_from_bcd() {
+        let fw = FirmwareVersion::from(0x24122420);
+        assert_eq!(
+            (fw.year, fw.month, fw.day, fw.revision),
+            (2024, 12, 24, 0x20)
+        );
+        assert_eq!(fw.date(), NaiveDate::from_ymd_opt(2024, 12, 24));
+        assert_eq!(fw.to_string(), "2024-12-24 rev 0x20");
+    }
+
+    #[test]
+    fn firmware_version_not_a_date() {
+        let fw = FirmwareVersion::from(0x25133201);
+        assert_eq!((fw.month, fw.day), (13, 32));
+        assert_eq!(fw.date(), None);
+        assert_eq!(fw.raw, 0x25133201);
+    }
+
+    #[test]
+    fn status_bits() {
+        let status = DeviceStatus::new(0x24122420, 100, 100 + NOMINAL_PPS_PERIOD, 0x01, 0x2f);
+        assert!(status.streaming);
+        assert!(!status.awake);
+        assert_eq!(
+            status.pll_locked,
+            [true, true, true, true, false, true, false, false]
+        );
+        assert_eq!(status.pps_period, NOMINAL_PPS_PERIOD);
+        assert_eq!(status.pps_deviation, 0);
+
+        let status = DeviceStatus::new(0, 0, 0, 0x02, 0);
+        assert!(!status.streaming);
+        assert!(status.awake);
+        assert_eq!(status.pll_locked, [false; 8]);
+    }
+
+    #[test]
+    fn pps_period_across_counter_wrap() {
+        let status = DeviceStatus::new(0, u32::MAX - 9, NOMINAL_PPS_PERIOD - 5, 0, 0);
+        assert_eq!(status.pps_period, NOMINAL_PPS_PERIOD + 5);
+        assert_eq!(status.pps_deviation, 5);
+
+        let status = DeviceStatus::new(0, 0, NOMINAL_PPS_PERIOD - 3, 0, 0);
+        assert_eq!(status.pps_deviation, -3);
+    }
+
+    #[test]
+    fn lock_criteria() {
+        let criteria = StatusCriteria::default();
+        for (locked, ok) in [
+            (0x0f, true),
+            (0x3f, true),
+            (0x2f, true),
+            (0x0e, false),
+            (0x07, false),
+        ] {
+            let status = DeviceStatus::new(0, 0, NOMINAL_PPS_PERIOD, 0x03, locked);
+            assert_eq!(status.lock_ok(&criteria), ok, "locked 0x{locked:x}");
+        }
+        let status = DeviceStatus::new(0, 0, NOMINAL_PPS_PERIOD, 0x03, 0x0b);
+        assert_eq!(status.problems(&criteria), ["lock state 0xb misses 0x4"]);
+    }
+
+    #[test]
+    fn pps_criteria() {
+        let criteria = StatusCriteria {
+            max_pps_deviation: 2,
+            ..Default::default()
+        };
+        for (period, ok) in [
+            (NOMINAL_PPS_PERIOD - 3, false),
+            (NOMINAL_PPS_PERIOD - 2, true),
+            (NOMINAL_PPS_PERIOD, true),
+            (NOMINAL_PPS_PERIOD + 2, true),
+            (NOMINAL_PPS_PERIOD + 3, false),
+        ] {
+            let status = DeviceStatus::new(0, 0, period, 0x03, 0x0f);
+            assert_eq!(status.pps_ok(&criteria), ok, "period {period}");
+            assert_eq!(status.is_healthy(&criteria), ok, "period {period}");
+        }
+        let status = DeviceStatus::new(0, 0, 5, 0x03, 0x0f);
+        assert_eq!(
+            status.problems(&StatusCriteria::default()),
+            ["pps period 5 ticks, expected 10000000±0"]
+        );
+    }
+
+    #[test]
+    fn awake_criteria() {
+        let sleeping = DeviceStatus::new(0, 0, NOMINAL_PPS_PERIOD, 0x01, 0x0f);
+        assert!(sleeping.is_healthy(&StatusCriteria::default()));
+        let criteria = StatusCriteria {
+            require_awake: true,
+            ..Default::default()
+        };
+        assert_eq!(sleeping.problems(&criteria), ["device is sleeping"]);
+        let awake = DeviceStatus::new(0, 0, NOMINAL_PPS_PERIOD, 0x02, 0x0f);
+        assert!(awake.is_healthy(&criteria));
+    }
+
+    #[test]
+    fn yaml_criteria() {
+        let yaml = include_str!("../doc/status_criteria.yaml");
+        let criteria = StatusCriteria::from_yaml(yaml.as_bytes()).expect("valid yaml");
+        let default = StatusCriteria::default();
+        assert_eq!(criteria.lock_mask, default.lock_mask);
+        assert_eq!(criteria.pps_period, default.pps_period);
+        assert_eq!(criteria.max_pps_deviation, default.max_pps_deviation);
+        assert_eq!(criteria.require_awake, default.require_awake);
+
+        let criteria = StatusCriteria::from_yaml("lock_mask: 0x2f".as_bytes()).expect("valid yaml");
+        assert_eq!(criteria.lock_mask, 0x2f);
+        assert_eq!(criteria.pps_period, NOMINAL_PPS_PERIOD);
+    }
+
+    #[test]
+    fn status_of_query_reply() {
+        let msg = CtrlMsg::QueryReply {
+            msg_id: 1,
+            fm_ver: 0x24122420,
+            tick_cnt1: 7,
+            tick_cnt2: 7 + NOMINAL_PPS_PERIOD,
+            trans_state: 0x03,
+            locked: 0x0f,
+            health: Health::T510Health {
+                rfdc_restart_cnt: 0,
+                temperature: 40_000,
+            },
+        };
+        let status = DeviceStatus::from_msg(&msg).expect("a QueryReply");
+        assert!(status.streaming && status.awake);
+        assert!(status.is_healthy(&StatusCriteria::default()));
+        assert!(DeviceStatus::from_msg(&CtrlMsg::Query { msg_id: 1 }).is_none());
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Datagram link between the controller and the devices
+pub trait Transport: Send + Sync + Debug {
+    /// Sends one datagram to `addr`
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> std::io::Result<()>;
+
+    /// Receives one datagram into `buf`, `Ok(None)` once `deadline` has passed
+    /// without any; `None` waits forever
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        deadline: Option<Instant>,
+    ) -> std::io::Result<Option<(usize, SocketAddr)>>;
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+
+    /// Datagrams dropped on the way in, `None` if the transport cannot tell
+    fn kernel_drops(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Plain blocking UDP socket
+#[derive(Debug)]
+pub struct UdpTransport {
+    socket: UdpSocket,
+    /// inode of the socket, to find it in `/proc/net/udp`
+    inode: Option<String>,
+}
+
+impl UdpTransport {
+    pub fn bind<A: ToSocketAddrs>(local_addr: A) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.set_broadcast(true)?;
+        Ok(UdpTransport::from(socket))
+    }
+}
+
+impl From<UdpSocket> for UdpTransport {
+    fn from(socket: UdpSocket) -> Self {
+        let inode = socket_inode(&socket);
+        UdpTransport { socket, inode }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        self.socket.send_to(data, addr).map(|_| ())
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        deadline: Option<Instant>,
+    ) -> std::io::Result<Option<(usize, SocketAddr)>> {
+        // a zero read timeout is rejected, a passed deadline still takes what is queued
+        let timeout = deadline.map(|d| {
+            d.saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1))
+        });
+        self.socket.set_read_timeout(timeout)?;
+        match self.socket.recv_from(buf) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn kernel_drops(&self) -> Option<u64> {
+        udp_drops(self.inode.as_deref()?)
+    }
+}
+
+/// What the devices behind a `Loopback` do with a datagram
+pub trait Device: Send {
+    /// Handles a datagram sent to `addr` and returns the replies, each with the
+    /// address it comes from; nothing simulates a device that is not there
+    fn handle(&mut self, addr: SocketAddr, data: &[u8]) -> Vec<(SocketAddr, Vec<u8>)>;
+}
+
+impl<F> Device for F
+where
+    F: FnMut(SocketAddr, &[u8]) -> Vec<(SocketAddr, Vec<u8>)> + Send,
+{
+    fn handle(&mut self, addr: SocketAddr, data: &[u8]) -> Vec<(SocketAddr, Vec<u8>)> {
+        self(addr, data)
+    }
+}
+
+/// In-memory transport handing every datagram straight to a simulated device.
+///
+/// The replies are queued before `send_to` returns, so a request/reply flow
+/// runs the same way every time without sockets or a running `dummy_server`.
+pub struct Loopback {
+    local_addr: SocketAddr,
+    device: Mutex<Box<dyn Device>>,
+    inbox: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+    ready: Condvar,
+}
+
+impl Debug for Loopback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Loopback")
+            .field("local_addr", &self.local_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Loopback {
+    /// `local_addr` is what the devices see as the source of the requests
+    pub fn new<D: Device + 'static>(local_addr: SocketAddr, device: D) -> Self {
+        Loopback {
+            local_addr,
+            device: Mutex::new(Box::new(device)),
+            inbox: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+        }
+    }
+}
+
+impl Transport for Loopback {
+    fn send_to(&self, data: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        let replies = self
+            .device
+            .lock()
+            .expect("device poisoned")
+            .handle(addr, data);
+        if !replies.is_empty() {
+            self.inbox
+                .lock()
+                .expect("inbox poisoned")
+                .extend(replies.into_iter().map(|(a, d)| (d, a)));
+            self.ready.notify_all();
+        }
+        Ok(())
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        deadline: Option<Instant>,
+    ) -> std::io::Result<Option<(usize, SocketAddr)>> {
+        let mut inbox = self.inbox.lock().expect("inbox poisoned");
+        loop {
+            if let Some((data, addr)) = inbox.pop_front() {
+                // truncated like a datagram larger than the receive buffer
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                return Ok(Some((len, addr)));
+            }
+            inbox = match deadline {
+                Some(d) => {
+                    let left = d.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Ok(None);
+                    }
+                    self.ready
+                        .wait_timeout(inbox, left)
+                        .expect("inbox poisoned")
+                        .0
+                }
+                None => self.ready.wait(inbox).expect("inbox poisoned"),
+            };
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn socket_inode<S: std::os::fd::AsRawFd>(socket: &S) -> Option<String> {
+    let link = std::fs::read_link(format!("/proc/self/fd/{}", socket.as_raw_fd())).ok()?;
+    let inode = link.to_str()?.strip_prefix("socket:[")?.strip_suffix(']')?;
+    Some(inode.to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn socket_inode<S>(_socket: &S) -> Option<String> {
+    None
+}
+
+/// Drop counter of the UDP socket with the given inode, from `/proc/net/udp`
+pub(crate) fn udp_drops(inode: &str) -> Option<u64> {
+    ["/proc/net/udp", "/proc/net/udp6"].iter().find_map(|path| {
+        let table = std::fs::read_to_string(path).ok()?;
+        table.lines().skip(1).find_map(|line| {
+            let cols = line.split_whitespace().collect::<Vec<_>>();
+            // the inode is the 10th column, the drop counter the last one
+            if cols.get(9) == Some(&inode) {
+                cols.last()?.parse().ok()
+            } else {
+                None
+            }
+        })
+    })
+}
@@ -0,0 +1,434 @@
+use crate::ctrl_msg::{CtrlMsg, Health, XGbeCfg};
+
+/// A reply message that can be extracted from its `CtrlMsg` wire representation
+pub trait Reply: Sized {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self>;
+}
+
+/// A downlink cmd together with the type of the reply it expects
+pub trait Command {
+    type Reply: Reply;
+
+    /// The wire representation, msg_id is left as 0 and set by the sender
+    fn to_msg(&self) -> CtrlMsg;
+}
+
+/// Replies that carry nothing but the msg_id
+macro_rules! empty_reply {
+    ($($name:ident),* $(,)?) => {
+        $(
+            #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+            pub struct $name;
+
+            impl Reply for $name {
+                fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+                    match msg {
+                        CtrlMsg::$name { .. } => Some($name),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+empty_reply!(
+    SyncReply,
+    XgbeCfgReply,
+    StreamStartReply,
+    StreamStopReply,
+    BitShiftReply,
+    PwrCtrlReply,
+    InitReply,
+    XGbeCfgSingleReply,
+    MixerSetReply,
+);
+
+#[derive(Clone, Debug)]
+pub struct QueryReply {
+    pub fm_ver: u32,
+    pub tick_cnt1: u32,
+    pub tick_cnt2: u32,
+    pub trans_state: u32,
+    pub locked: u32,
+    pub health: Health,
+}
+
+impl Reply for QueryReply {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        match msg {
+            CtrlMsg::QueryReply {
+                msg_id: _,
+                fm_ver,
+                tick_cnt1,
+                tick_cnt2,
+                trans_state,
+                locked,
+                health,
+            } => Some(QueryReply {
+                fm_ver: *fm_ver,
+                tick_cnt1: *tick_cnt1,
+                tick_cnt2: *tick_cnt2,
+                trans_state: *trans_state,
+                locked: *locked,
+                health: health.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct I2CScanReply {
+    /// addresses of the devices found on the bus
+    pub devices: Vec<u8>,
+}
+
+impl Reply for I2CScanReply {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        match msg {
+            CtrlMsg::I2CScanReply { payload, .. } => Some(I2CScanReply {
+                devices: payload.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct I2CWriteReply {
+    pub err_code: u32,
+}
+
+impl Reply for I2CWriteReply {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        match msg {
+            CtrlMsg::I2CWriteReply { err_code, .. } => Some(I2CWriteReply {
+                err_code: *err_code,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct I2CWriteRegReply {
+    pub err_code: u32,
+}
+
+impl Reply for I2CWriteRegReply {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        match msg {
+            CtrlMsg::I2CWriteRegReply { err_code, .. } => Some(I2CWriteRegReply {
+                err_code: *err_code,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct I2CReadReply {
+    pub err_code: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Reply for I2CReadReply {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        match msg {
+            CtrlMsg::I2CReadReply {
+                err_code, payload, ..
+            } => Some(I2CReadReply {
+                err_code: *err_code,
+                payload: payload.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct I2CReadRegReply {
+    pub err_code: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Reply for I2CReadRegReply {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        match msg {
+            CtrlMsg::I2CReadRegReply {
+                err_code, payload, ..
+            } => Some(I2CReadRegReply {
+                err_code: *err_code,
+                payload: payload.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct XGbeCfgQueryReply {
+    pub cfg: Vec<XGbeCfg>,
+}
+
+impl Reply for XGbeCfgQueryReply {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        match msg {
+            CtrlMsg::XGbeCfgQueryReply { cfg, .. } => Some(XGbeCfgQueryReply { cfg: cfg.clone() }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SetClkReply {
+    pub clk_state: u32,
+}
+
+impl Reply for SetClkReply {
+    fn from_msg(msg: &CtrlMsg) -> Option<Self> {
+        match msg {
+            CtrlMsg::SetClkReply { clk_state, .. } => Some(SetClkReply {
+                clk_state: *clk_state,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Query;
+
+impl Command for Query {
+    type Reply = QueryReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::Query { msg_id: 0 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sync;
+
+impl Command for Sync {
+    type Reply = SyncReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::Sync { msg_id: 0 }
+    }
+}
+
+/// Configures all 4 XGbe ports at once
+#[derive(Clone, Copy, Debug)]
+pub struct XGbeCfgAll {
+    pub cfg: [XGbeCfg; 4],
+}
+
+impl Command for XGbeCfgAll {
+    type Reply = XgbeCfgReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::XGbeCfg {
+            msg_id: 0,
+            cfg: self.cfg,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct XGbeCfgSingle {
+    pub port_id: u32,
+    pub cfg: XGbeCfg,
+}
+
+impl Command for XGbeCfgSingle {
+    type Reply = XGbeCfgSingleReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::XGbeCfgSingle {
+            msg_id: 0,
+            port_id: self.port_id,
+            cfg: self.cfg,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XGbeCfgQuery;
+
+impl Command for XGbeCfgQuery {
+    type Reply = XGbeCfgQueryReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::XGbeCfgQuery { msg_id: 0 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct I2CScan;
+
+impl Command for I2CScan {
+    type Reply = I2CScanReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::I2CScan { msg_id: 0 }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct I2CWrite {
+    pub dev_addr: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Command for I2CWrite {
+    type Reply = I2CWriteReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::i2c_write(self.dev_addr, self.payload.clone())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct I2CWriteReg {
+    pub dev_addr: u32,
+    pub reg_addr: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Command for I2CWriteReg {
+    type Reply = I2CWriteRegReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::i2c_write_reg(self.dev_addr, self.reg_addr, self.payload.clone())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct I2CRead {
+    pub dev_addr: u32,
+    pub nbytes: u32,
+}
+
+impl Command for I2CRead {
+    type Reply = I2CReadReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::I2CRead {
+            msg_id: 0,
+            dev_addr: self.dev_addr,
+            nbytes: self.nbytes,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct I2CReadReg {
+    pub dev_addr: u32,
+    pub reg_addr: u32,
+    pub nbytes: u32,
+}
+
+impl Command for I2CReadReg {
+    type Reply = I2CReadRegReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::I2CReadReg {
+            msg_id: 0,
+            dev_addr: self.dev_addr,
+            reg_addr: self.reg_addr,
+            nbytes: self.nbytes,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamStart;
+
+impl Command for StreamStart {
+    type Reply = StreamStartReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::StreamStart { msg_id: 0 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamStop;
+
+impl Command for StreamStop {
+    type Reply = StreamStopReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::StreamStop { msg_id: 0 }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BitShift {
+    pub shift_bits: u32,
+}
+
+impl Command for BitShift {
+    type Reply = BitShiftReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::BitShift {
+            msg_id: 0,
+            shift_bits: self.shift_bits,
+        }
+    }
+}
+
+/// op_code 1 wakes the device up, 0 puts it to sleep
+#[derive(Clone, Copy, Debug)]
+pub struct PwrCtrl {
+    pub op_code: u32,
+}
+
+impl Command for PwrCtrl {
+    type Reply = PwrCtrlReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::PwrCtrl {
+            msg_id: 0,
+            op_code: self.op_code,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Init;
+
+impl Command for Init {
+    type Reply = InitReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::Init {
+            msg_id: 0,
+            reserved_zeros: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SetClk {
+    pub clk_src: u32,
+    pub pps_src: u32,
+}
+
+impl Command for SetClk {
+    type Reply = SetClkReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::SetClk {
+            msg_id: 0,
+            clk_src: self.clk_src,
+            pps_src: self.pps_src,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MixerSet {
+    pub freq: f64,
+    pub phase: f64,
+    pub sync: u32,
+}
+
+impl Command for MixerSet {
+    type Reply = MixerSetReply;
+    fn to_msg(&self) -> CtrlMsg {
+        CtrlMsg::MixerSet {
+            msg_id: 0,
+            freq: self.freq,
+            phase: self.phase,
+            sync: self.sync,
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! The async controller against `DummyDevices` served on local UDP sockets,
+//! run with `cargo test --features async`.
+#![cfg(feature = "async")]
+
+mod common;
+
+use std::{future::Future, net::UdpSocket, time::Duration};
+
+use sdaa_ctrl::{
+    async_ctrl::Controller,
+    controller::CollectUntil,
+    ctrl_msg::{CtrlMsg, Outcome},
+    msg_id::MsgIdAllocator,
+    retry::RetryPolicy,
+};
+use tokio_stream::StreamExt;
+
+use common::Served;
+
+fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(f)
+}
+
+async fn controller() -> Controller {
+    Controller::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .with_timeout(Some(Duration::from_millis(200)))
+}
+
+#[test]
+fn query_is_answered() {
+    let served = Served::spawn(1);
+    let reply = block_on(async { controller().await.query(served.devices[0]).await }).unwrap();
+    assert_eq!(reply.fm_ver, 0x24122420);
+}
+
+#[test]
+fn send_many_reports_every_target() {
+    let served = Served::spawn(3);
+    // bound but never read, so nothing answers
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut targets = served.devices.clone();
+    targets.push(silent.local_addr().unwrap());
+
+    let summary = block_on(async {
+        controller()
+            .await
+            .send_many(CtrlMsg::Sync { msg_id: 0 }, &targets)
+            .await
+    })
+    .unwrap();
+    assert_eq!(summary.targets.len(), 4);
+    for d in &served.devices {
+        let report = &summary.targets[d];
+        assert!(
+            matches!(report.outcome, Outcome::Ok(CtrlMsg::SyncReply { .. })),
+            "{d}: {:?}",
+            report.outcome
+        );
+        assert_eq!(report.attempts, 1);
+    }
+    let report = &summary.targets[&silent.local_addr().unwrap()];
+    assert!(matches!(report.outcome, Outcome::Timeout));
+    assert!(!summary.all_ok());
+}
+
+#[test]
+fn send_many_retries_the_silent_targets() {
+    let served = Served::spawn(1);
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let targets = [served.devices[0], silent.local_addr().unwrap()];
+    let retry = RetryPolicy {
+        initial_backoff: Duration::from_millis(10),
+        ..RetryPolicy::new(3, Some(Duration::from_millis(50)))
+    };
+
+    let summary = block_on(async {
+        controller()
+            .await
+            .with_retry(retry)
+            .send_many(CtrlMsg::Query { msg_id: 0 }, &targets)
+            .await
+    })
+    .unwrap();
+    assert!(summary.targets[&targets[0]].outcome.is_ok());
+    assert_eq!(summary.targets[&targets[0]].attempts, 1);
+    assert!(matches!(
+        summary.targets[&targets[1]].outcome,
+        Outcome::Timeout
+    ));
+    assert_eq!(summary.targets[&targets[1]].attempts, 3);
+}
+
+#[test]
+fn broadcast_streams_every_responder() {
+    let served = Served::spawn(8);
+    let until = CollectUntil::new(Some(served.devices.len()), None);
+    let replies = block_on(async {
+        let ctrl = controller().await;
+        let stream = ctrl
+            .broadcast_until(CtrlMsg::Query { msg_id: 0 }, served.baddr, until)
+            .await
+            .unwrap();
+        stream.collect::<Vec<_>>().await
+    });
+    let mut responders = replies.iter().map(|(a, _)| *a).collect::<Vec<_>>();
+    responders.sort();
+    assert_eq!(responders, served.devices);
+    assert!(replies.iter().all(|(_, outcome)| outcome.is_ok()));
+}
+
+#[test]
+fn broadcast_stream_ends_on_timeout() {
+    let served = Served::spawn(2);
+    let replies = block_on(async {
+        let ctrl = controller().await;
+        let stream = ctrl
+            .broadcast(CtrlMsg::Query { msg_id: 0 }, served.baddr)
+            .await
+            .unwrap();
+        stream.collect::<Vec<_>>().await
+    });
+    assert_eq!(replies.len(), 2);
+}
+
+/// Hands out the same msg_id every time, which is only free again once the
+/// request that had it ended
+#[derive(Debug)]
+struct SameId(u32);
+
+impl MsgIdAllocator for SameId {
+    fn next_id(&mut self) -> u32 {
+        self.0
+    }
+}
+
+#[test]
+fn dropping_a_broadcast_stream_without_timeout_ends_it() {
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = silent.local_addr().unwrap();
+    block_on(async {
+        let ctrl = Controller::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_timeout(None)
+            .with_id_allocator(SameId(7));
+        let stream = ctrl
+            .broadcast(CtrlMsg::Query { msg_id: 0 }, addr)
+            .await
+            .unwrap();
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the id is still in use while the task waits for replies
+        assert!(
+            ctrl.broadcast(CtrlMsg::Query { msg_id: 0 }, addr)
+                .await
+                .is_ok()
+        );
+    });
+}
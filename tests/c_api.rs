@@ -0,0 +1,349 @@
+//! The handle based C API driving the dummy server, called the way C code does.
+
+mod common;
+
+use std::{
+    ffi::{CStr, CString, c_void},
+    io::Cursor,
+    mem::MaybeUninit,
+    net::UdpSocket,
+    ptr::{null, null_mut},
+    time::{Duration, Instant},
+};
+
+use binrw::{BinRead, BinWrite};
+use sdaa_ctrl::{
+    c_interface::{
+        HealthKind, QueryResult, SdaaConfig, SdaaDeviceInfo, SdaaStatus, find_device,
+        find_device_cfg, find_devices_cb, find_devices_ex, make_device_cfg, sdaa_config_default,
+        sdaa_ctrl_free, sdaa_ctrl_i2c_read, sdaa_ctrl_i2c_scan, sdaa_ctrl_new, sdaa_ctrl_query,
+        sdaa_ctrl_sync, sdaa_ctrl_xgbe_cfg_query, sdaa_last_error, start_stream_cfg,
+    },
+    ctrl_msg::{self, CtrlMsg, DeviceErrorCode, XGbeCfg},
+    sim::dummy_reply,
+};
+
+use common::Simulator;
+
+#[test]
+fn every_command_through_the_handle() {
+    let sim = Simulator::spawn(&["127.0.0.1:0"], 0);
+    let local = CString::new("127.0.0.1:0").unwrap();
+    let remote = CString::new(sim.addrs[0].to_string()).unwrap();
+    unsafe {
+        let mut h = null_mut();
+        assert_eq!(
+            sdaa_ctrl_new(local.as_ptr(), remote.as_ptr(), 500, 2, &mut h),
+            SdaaStatus::Ok
+        );
+        assert!(!h.is_null());
+
+        let mut q = QueryResult::default();
+        assert_eq!(sdaa_ctrl_query(h, &mut q), SdaaStatus::Ok);
+        assert_eq!(q.fm_ver, 0x24122420);
+        assert_eq!(q.health_kind, HealthKind::Hl);
+        assert_eq!(q.temperatures, [40000, 30000]);
+        let fw = q.status.firmware;
+        assert_eq!(
+            (fw.year, fw.month, fw.day, fw.revision),
+            (2024, 12, 24, 0x20)
+        );
+        assert!(fw.has_date);
+        // the dummy device reports no PLL locked, no pps and odd voltages
+        assert!(!q.status.lock_ok && !q.status.pps_ok && !q.status.health_ok);
+
+        assert_eq!(sdaa_ctrl_sync(h), SdaaStatus::Ok);
+
+        let mut cfg = [XGbeCfg {
+            dst_mac: [0; 6],
+            src_mac: [0; 6],
+            dst_ip: [0; 4],
+            src_ip: [0; 4],
+            dst_port: 0,
+            src_port: 0,
+        }; 4];
+        let mut nports = 0;
+        assert_eq!(
+            sdaa_ctrl_xgbe_cfg_query(h, cfg.as_mut_ptr(), cfg.len(), &mut nports),
+            SdaaStatus::Ok
+        );
+        assert_eq!(nports, 4);
+        assert_eq!(cfg[0].dst_ip, [192, 168, 4, 10]);
+        // a buffer too small is filled and reported
+        assert_eq!(
+            sdaa_ctrl_xgbe_cfg_query(h, cfg.as_mut_ptr(), 2, &mut nports),
+            SdaaStatus::BufferTooSmall
+        );
+        assert_eq!(nports, 2);
+
+        let mut devices = [0_u8; 8];
+        let mut ndev = 0;
+        assert_eq!(
+            sdaa_ctrl_i2c_scan(h, devices.as_mut_ptr(), 8, &mut ndev),
+            SdaaStatus::Ok
+        );
+        assert_eq!(&devices[..ndev], &[0x11, 0x22, 0x33, 0x44]);
+
+        let mut buf = [0xff_u8; 10];
+        let mut len = 0;
+        assert_eq!(
+            sdaa_ctrl_i2c_read(h, 0x50, buf.as_mut_ptr(), 10, &mut len),
+            SdaaStatus::Ok
+        );
+        assert_eq!(len, 10);
+        assert_eq!(buf, [0; 10]);
+
+        assert_eq!(sdaa_ctrl_query(h, null_mut()), SdaaStatus::InvalidArgument);
+        sdaa_ctrl_free(h);
+    }
+}
+
+#[test]
+fn invalid_arguments_give_no_handle() {
+    let local = CString::new("127.0.0.1:0").unwrap();
+    let bad = CString::new("not an address").unwrap();
+    let mut h = null_mut();
+    unsafe {
+        assert_ne!(
+            sdaa_ctrl_new(local.as_ptr(), bad.as_ptr(), 0, 1, &mut h),
+            SdaaStatus::Ok
+        );
+        assert!(h.is_null());
+        assert_eq!(
+            sdaa_ctrl_new(null(), local.as_ptr(), 0, 1, &mut h),
+            SdaaStatus::InvalidArgument
+        );
+        assert_eq!(sdaa_ctrl_sync(null()), SdaaStatus::InvalidArgument);
+        let e = sdaa_last_error();
+        assert_eq!(e.status, SdaaStatus::InvalidArgument);
+        assert_eq!(CStr::from_ptr(e.cmd).to_str().unwrap(), "Sync");
+        assert!(e.addr.is_null());
+    }
+}
+
+#[test]
+fn legacy_discovery_rejects_a_null_buffer() {
+    unsafe {
+        for max_n in [4, 0] {
+            assert_eq!(find_device(0x7f000001, null_mut(), max_n, 0), 0);
+            let e = sdaa_last_error();
+            assert_eq!(e.status, SdaaStatus::InvalidArgument);
+            assert_eq!(CStr::from_ptr(e.cmd).to_str().unwrap(), "Query");
+        }
+    }
+}
+
+/// A device accepting Init and refusing to Sync
+fn refuse_sync(socket: UdpSocket) {
+    let mut buf = [0_u8; 9000];
+    for _ in 0..2 {
+        let (n, addr) = socket.recv_from(&mut buf).unwrap();
+        let reply = match CtrlMsg::read(&mut Cursor::new(&buf[..n])).unwrap() {
+            CtrlMsg::Sync { msg_id } => {
+                CtrlMsg::invalid_msg(msg_id, DeviceErrorCode::SyncFailure, "no pps")
+            }
+            msg => dummy_reply(msg),
+        };
+        let mut out = Cursor::new(Vec::new());
+        reply.write(&mut out).unwrap();
+        socket.send_to(&out.into_inner(), addr).unwrap();
+    }
+}
+
+#[test]
+fn make_device_reports_the_failed_step() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let device = std::thread::spawn(move || refuse_sync(socket));
+    let cfg = SdaaConfig {
+        remote_port: port,
+        ..sdaa_config_default()
+    };
+    let ip = CString::new("127.0.0.1").unwrap();
+    unsafe {
+        assert_eq!(make_device_cfg(ip.as_ptr(), &cfg), SdaaStatus::DeviceError);
+        let e = sdaa_last_error();
+        assert_eq!(e.status, SdaaStatus::DeviceError);
+        assert_eq!(CStr::from_ptr(e.cmd).to_str().unwrap(), "Sync");
+        assert_eq!(
+            CStr::from_ptr(e.addr).to_str().unwrap(),
+            format!("127.0.0.1:{port}")
+        );
+        assert!(e.has_device_err_code);
+        assert_eq!(e.device_err_code, u32::from(DeviceErrorCode::SyncFailure));
+        assert!(
+            CStr::from_ptr(e.message)
+                .to_str()
+                .unwrap()
+                .contains("no pps")
+        );
+    }
+    device.join().unwrap();
+}
+
+#[test]
+fn config_selects_port_and_local_address() {
+    let sim = Simulator::spawn(&["127.0.0.1:0"], 0);
+    let local = CString::new("127.0.0.1:0").unwrap();
+    let ip = CString::new("127.0.0.1").unwrap();
+    let cfg = SdaaConfig {
+        local_addr: local.as_ptr(),
+        remote_port: sim.addrs[0].port(),
+        timeout_ms: 500,
+        max_attempts: 2,
+    };
+    unsafe {
+        assert_eq!(make_device_cfg(ip.as_ptr(), &cfg), SdaaStatus::Ok);
+        assert_eq!(start_stream_cfg(ip.as_ptr(), &cfg), SdaaStatus::Ok);
+
+        let mut found = [0_u32; 4];
+        let mut n = 0;
+        assert_eq!(
+            find_device_cfg(ip.as_ptr(), &cfg, found.as_mut_ptr(), found.len(), &mut n),
+            SdaaStatus::Ok
+        );
+        assert_eq!(&found[..n], &[0x7f000001]);
+
+        // nothing listens on the default port
+        let cfg = SdaaConfig {
+            timeout_ms: 100,
+            ..sdaa_config_default()
+        };
+        assert_eq!(start_stream_cfg(ip.as_ptr(), &cfg), SdaaStatus::Timeout);
+    }
+}
+
+#[test]
+fn devices_are_reached_over_ipv6() {
+    if UdpSocket::bind("[::1]:0").is_err() {
+        return;
+    }
+    let sim = Simulator::spawn(&["[::1]:0"], 0);
+    let ip = CString::new("::1").unwrap();
+    let cfg = SdaaConfig {
+        remote_port: sim.addrs[0].port(),
+        ..sdaa_config_default()
+    };
+    unsafe {
+        assert_eq!(make_device_cfg(ip.as_ptr(), &cfg), SdaaStatus::Ok);
+    }
+}
+
+#[test]
+fn discovery_fills_up_to_capacity_and_counts_all() {
+    let sim = Simulator::spawn(&["127.0.0.1:0"], 5);
+    let mut answering = sim.devices[0].clone();
+    answering.sort();
+    let ip = CString::new("127.0.0.1").unwrap();
+    let cfg = SdaaConfig {
+        remote_port: sim.addrs[0].port(),
+        timeout_ms: 300,
+        ..sdaa_config_default()
+    };
+    let mut devices = [MaybeUninit::<SdaaDeviceInfo>::uninit(); 3];
+    let (mut nfilled, mut total) = (0, 0);
+    unsafe {
+        assert_eq!(
+            find_devices_ex(
+                ip.as_ptr(),
+                &cfg,
+                devices.as_mut_ptr().cast(),
+                devices.len(),
+                &mut nfilled,
+                &mut total
+            ),
+            SdaaStatus::BufferTooSmall
+        );
+        assert_eq!(sdaa_last_error().status, SdaaStatus::BufferTooSmall);
+    }
+    assert_eq!((nfilled, total), (3, 5));
+    for (d, addr) in devices.iter().zip(&answering) {
+        let d = unsafe { d.assume_init() };
+        assert_eq!(
+            unsafe { CStr::from_ptr(d.addr.as_ptr()) }.to_str().unwrap(),
+            addr.to_string()
+        );
+        assert_eq!((d.ipv4, d.port), (0x7f000001, addr.port()));
+        assert_eq!(d.status.firmware.raw, 0x24122420);
+        assert_eq!(d.health_kind, HealthKind::Hl);
+        assert!(!d.status.streaming);
+    }
+
+    // the same from Rust
+    let found = ctrl_msg::find_devices_ex(
+        sim.addrs[0],
+        "127.0.0.1:0",
+        Some(Duration::from_millis(300)),
+    )
+    .unwrap();
+    assert_eq!(found.len(), 5);
+    assert!(found.iter().all(|d| d.latency.is_some()));
+}
+
+/// Collects the ports of the devices into the `Vec<u16>` behind `user_data`,
+/// stopping after the second one
+extern "C" fn first_two(device: *const SdaaDeviceInfo, user_data: *mut c_void) -> bool {
+    let ports = unsafe { &mut *user_data.cast::<Vec<u16>>() };
+    ports.push(unsafe { (*device).port });
+    ports.len() < 2
+}
+
+#[test]
+fn discovery_reports_devices_as_they_answer() {
+    let sim = Simulator::spawn(&["127.0.0.1:0"], 5);
+    let ip = CString::new("127.0.0.1").unwrap();
+    let cfg = SdaaConfig {
+        remote_port: sim.addrs[0].port(),
+        timeout_ms: 2000,
+        ..sdaa_config_default()
+    };
+    let mut ports = Vec::<u16>::new();
+    let mut n = 0;
+    let start = Instant::now();
+    let status = unsafe {
+        find_devices_cb(
+            ip.as_ptr(),
+            &cfg,
+            Some(first_two),
+            (&mut ports as *mut Vec<u16>).cast(),
+            &mut n,
+        )
+    };
+    assert_eq!(status, SdaaStatus::Ok);
+    // stopped early instead of waiting out the 2 s timeout
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(n, 2);
+    assert_eq!(ports.len(), 2);
+    assert!(
+        ports
+            .iter()
+            .all(|&p| sim.devices[0].iter().any(|d| d.port() == p))
+    );
+
+    unsafe {
+        assert_eq!(
+            find_devices_cb(ip.as_ptr(), &cfg, None, null_mut(), null_mut()),
+            SdaaStatus::InvalidArgument
+        );
+    }
+
+    // the same from Rust, as an iterator
+    let start = Instant::now();
+    let first =
+        ctrl_msg::find_devices_iter(sim.addrs[0], "127.0.0.1:0", Some(Duration::from_secs(2)))
+            .unwrap()
+            .take(3)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(first.len(), 3);
+
+    let all = ctrl_msg::find_devices_iter(
+        sim.addrs[0],
+        "127.0.0.1:0",
+        Some(Duration::from_millis(300)),
+    )
+    .unwrap()
+    .count();
+    assert_eq!(all, 5);
+}
@@ -0,0 +1,144 @@
+//! Helpers shared by the tests talking to devices over local UDP sockets
+// each test crate uses only some of them
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader},
+    net::{SocketAddr, UdpSocket},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use sdaa_ctrl::{sim::DummyDevices, transport::Device};
+
+/// `DummyDevices` answering on sockets of their own, each device replies from its address
+pub struct Served {
+    pub devices: Vec<SocketAddr>,
+    /// every device answers what is sent here
+    pub baddr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Served {
+    /// `n` devices, each on a socket bound to a free local port
+    pub fn spawn(n: usize) -> Self {
+        let bind = || {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(20)))
+                .unwrap();
+            Arc::new(socket)
+        };
+        let sockets = (0..=n)
+            .map(|_| {
+                let socket = bind();
+                (socket.local_addr().unwrap(), socket)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut addrs = sockets.keys().copied();
+        let baddr = addrs.next().unwrap();
+        let devices = addrs.collect::<Vec<_>>();
+        let sim = devices
+            .iter()
+            .fold(DummyDevices::new(), |sim, &d| sim.with_device(d))
+            .with_broadcast(baddr, devices.iter().copied());
+        let sim = Arc::new(Mutex::new(sim));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads = sockets
+            .iter()
+            .map(|(&addr, socket)| {
+                let (socket, sockets) = (Arc::clone(socket), sockets.clone());
+                let (sim, stop) = (Arc::clone(&sim), Arc::clone(&stop));
+                std::thread::spawn(move || {
+                    let mut buf = vec![0_u8; 9000];
+                    while !stop.load(Ordering::Relaxed) {
+                        let Ok((len, src)) = socket.recv_from(&mut buf) else {
+                            continue;
+                        };
+                        let replies = sim.lock().unwrap().handle(addr, &buf[..len]);
+                        for (from, reply) in replies {
+                            sockets[&from].send_to(&reply, src).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        Served {
+            devices,
+            baddr,
+            stop,
+            threads,
+        }
+    }
+}
+
+impl Drop for Served {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+/// The dummy server in a child process, killed when dropped
+pub struct Simulator {
+    child: Child,
+    /// kept open, the server fails writing to a closed pipe
+    _stdout: BufReader<ChildStdout>,
+    /// the bound address of each address it was given
+    pub addrs: Vec<SocketAddr>,
+    /// the devices answering behind each of `addrs`
+    pub devices: Vec<Vec<SocketAddr>>,
+}
+
+impl Simulator {
+    /// Serves each of `addrs`, answered by `fanout` devices if not 0. Port 0
+    /// binds free ports, the server prints where before serving.
+    pub fn spawn(addrs: &[&str], fanout: usize) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_dummy_server"))
+            .args(["-q", "-f", &fanout.to_string(), "-a"])
+            .args(addrs)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start dummy_server");
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let (mut bound, mut devices) = (Vec::new(), Vec::<Vec<_>>::new());
+        for _ in 0..addrs.len() * (1 + fanout) {
+            let mut line = String::new();
+            if stdout.read_line(&mut line).unwrap() == 0 {
+                panic!("dummy_server exited before binding every address");
+            }
+            let line = line.trim();
+            if let Some(addr) = line.strip_prefix("listening on ") {
+                bound.push(addr.parse().unwrap());
+                devices.push(Vec::new());
+            } else if let Some(addr) = line.strip_prefix("device ") {
+                devices.last_mut().unwrap().push(addr.parse().unwrap());
+            } else {
+                panic!("dummy_server printed {line:?}");
+            }
+        }
+        Simulator {
+            child,
+            _stdout: stdout,
+            addrs: bound,
+            devices,
+        }
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
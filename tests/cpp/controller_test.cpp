@@ -0,0 +1,110 @@
+// Drives the dummy server through sdaa::Controller, run by tests/cpp_wrapper.rs as
+// `controller_test <device addr> <fanout addr> <fanout> <silent addr>`.
+
+#include <cstdio>
+#include <cstdlib>
+#include <string>
+
+#include "sdaa_ctrl.hpp"
+
+#define CHECK(cond)                                                  \
+  do {                                                               \
+    if (!(cond)) {                                                   \
+      std::fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #cond); \
+      std::exit(1);                                                  \
+    }                                                                \
+  } while (0)
+
+using namespace std::chrono_literals;
+
+int main(int argc, char **argv) {
+  CHECK(argc == 5);
+  std::string device = argv[1];
+  std::string fanout_addr = argv[2];
+  size_t fanout = std::stoul(argv[3]);
+  std::string silent = argv[4];
+
+  {
+    sdaa::Controller ctrl("127.0.0.1:0", device, 500ms, 2);
+    sdaa::QueryStatus q = ctrl.query();
+    CHECK(q.status.firmware.year == 2024);
+    CHECK(q.status.firmware.month == 12);
+    CHECK(q.status.firmware.day == 24);
+    CHECK(q.status.firmware.revision == 0x20);
+    CHECK(q.status.firmware.has_date);
+    CHECK(!q.status.streaming);
+    // the dummy device reports no PLL locked, no pps and odd voltages
+    CHECK(!q.status.lock_ok);
+    CHECK(!q.status.pps_ok);
+    CHECK(!q.status.health_ok);
+    const auto *hl = std::get_if<sdaa::HlHealth>(&q.health);
+    CHECK(hl != nullptr);
+    CHECK(hl->temperatures[0] == 40000);
+
+    ctrl.init();
+    ctrl.sync();
+    CHECK(ctrl.xgbe_cfg_query().size() == 4);
+    CHECK((ctrl.i2c_scan() == std::vector<uint8_t>{0x11, 0x22, 0x33, 0x44}));
+    CHECK(ctrl.i2c_read(0x50, 10).size() == 10);
+    ctrl.stream_start();
+    ctrl.stream_stop();
+
+    // moving hands the handle over
+    sdaa::Controller moved = std::move(ctrl);
+    CHECK(moved.try_query().has_value());
+  }
+
+  {
+    sdaa::Controller ctrl("127.0.0.1:0", silent, 100ms);
+    CHECK(!ctrl.try_query().has_value());
+    bool thrown = false;
+    try {
+      ctrl.sync();
+    } catch (const sdaa::Error &e) {
+      thrown = true;
+      CHECK(e.status() == sdaa::sdaa_status::Timeout);
+      CHECK(e.cmd() == "Sync");
+      CHECK(e.addr() == silent);
+    }
+    CHECK(thrown);
+  }
+
+  bool bad_addr = false;
+  try {
+    sdaa::Controller ctrl("127.0.0.1:0", "not an address");
+  } catch (const sdaa::Error &e) {
+    bad_addr = true;
+  }
+  CHECK(bad_addr);
+
+  std::string ip = fanout_addr.substr(0, fanout_addr.rfind(':'));
+  sdaa::Config cfg;
+  cfg.remote_port = uint16_t(std::stoul(fanout_addr.substr(fanout_addr.rfind(':') + 1)));
+  cfg.timeout = 300ms;
+  std::vector<sdaa::Device> devices = sdaa::find_devices(ip, cfg);
+  CHECK(devices.size() == fanout);
+  for (const auto &d : devices) {
+    CHECK(d.ipv4 == 0x7f000001);
+    CHECK(d.status.firmware.year == 2024);
+    CHECK(d.health_kind == sdaa::HealthKind::Hl);
+  }
+
+  size_t seen = 0;
+  size_t calls = sdaa::find_devices_each(ip, cfg, [&](const sdaa::Device &) { return ++seen < 2; });
+  CHECK(calls == 2);
+  CHECK(seen == 2);
+
+  // an exception in the callback comes back out of find_devices_each
+  bool rethrown = false;
+  try {
+    sdaa::find_devices_each(ip, cfg, [](const sdaa::Device &) -> bool {
+      throw std::runtime_error("enough");
+    });
+  } catch (const std::runtime_error &e) {
+    rethrown = std::string(e.what()) == "enough";
+  }
+  CHECK(rethrown);
+
+  std::puts("ok");
+  return 0;
+}
@@ -0,0 +1,66 @@
+//! Compiles `tests/cpp/controller_test.cpp` against `include/sdaa_ctrl.hpp` and
+//! the static library, then runs it against the dummy server.
+
+mod common;
+
+use std::{
+    net::UdpSocket,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use common::Simulator;
+
+/// The static library built along with this test. Cargo builds the library
+/// with every crate type of Cargo.toml into `deps/` before running the tests,
+/// while `<profile>/libsdaa_ctrl.a` is only refreshed by `cargo build`.
+fn static_lib() -> PathBuf {
+    let lib = Path::new(env!("CARGO_BIN_EXE_dummy_server"))
+        .parent()
+        .expect("binary has a directory")
+        .join("deps/libsdaa_ctrl.a");
+    assert!(lib.exists(), "{} was not built", lib.display());
+    lib
+}
+
+#[test]
+fn cpp_wrapper_drives_the_dummy_server() {
+    let cxx = std::env::var("CXX").unwrap_or_else(|_| "c++".to_string());
+    if Command::new(&cxx).arg("--version").output().is_err() {
+        eprintln!("no C++ compiler ({cxx}), skipped");
+        return;
+    }
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let exe = Path::new(env!("CARGO_TARGET_TMPDIR")).join("controller_test");
+    let status = Command::new(&cxx)
+        .arg("-std=c++17")
+        .args(["-Wall", "-Wextra", "-Werror"])
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg(root.join("tests/cpp/controller_test.cpp"))
+        .arg(static_lib())
+        .args(["-lpthread", "-ldl", "-lm"])
+        .arg("-o")
+        .arg(&exe)
+        .status()
+        .expect("failed to run the C++ compiler");
+    assert!(status.success(), "controller_test.cpp did not compile");
+
+    let device = Simulator::spawn(&["127.0.0.1:0"], 0);
+    let array = Simulator::spawn(&["127.0.0.1:0"], 4);
+    // bound but never read, so nothing answers
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let output = Command::new(&exe)
+        .arg(device.addrs[0].to_string())
+        .arg(array.addrs[0].to_string())
+        .arg("4")
+        .arg(silent.local_addr().unwrap().to_string())
+        .output()
+        .expect("failed to run controller_test");
+    assert!(
+        output.status.success(),
+        "controller_test failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "ok");
+}
@@ -0,0 +1,64 @@
+//! Discovery of a large array simulated by the dummy server, every device of a
+//! fanout address answers the same request at once.
+
+mod common;
+
+use std::{collections::BTreeSet, time::Duration};
+
+use sdaa_ctrl::{
+    controller::{CollectUntil, Controller, SocketOptions},
+    ctrl_msg::CtrlMsg,
+};
+
+use common::Simulator;
+
+const DEVICES: usize = 500;
+
+fn controller() -> Controller {
+    Controller::bind_with_options("127.0.0.1:0", SocketOptions::for_discovery(DEVICES))
+        .expect("failed to bind")
+        .with_timeout(Some(Duration::from_secs(1)))
+}
+
+#[test]
+fn discovers_every_device_of_a_burst() {
+    let sim = Simulator::spawn(&["127.0.0.1:0"], DEVICES);
+    let ctrl = controller();
+    let until = CollectUntil::new(Some(DEVICES), Some(Duration::from_secs(5)));
+    for round in 0..5 {
+        let summary = ctrl
+            .broadcast_until(CtrlMsg::Query { msg_id: 0 }, sim.addrs[0], until)
+            .expect("broadcast failed");
+        assert_eq!(
+            summary.targets.len(),
+            DEVICES,
+            "round {round}, kernel drops {:?}",
+            summary.kernel_drops
+        );
+        assert!(summary.kernel_drops.is_none_or(|n| n == 0));
+    }
+}
+
+#[test]
+fn discovers_across_slices() {
+    let sim = Simulator::spawn(&["127.0.0.1:0", "127.0.0.1:0"], DEVICES / 2);
+    let ctrl = controller();
+    let until = CollectUntil::new(Some(DEVICES), Some(Duration::from_secs(5)));
+    let summary = ctrl
+        .discover(
+            CtrlMsg::Query { msg_id: 0 },
+            &sim.addrs,
+            until,
+            Duration::from_millis(10),
+        )
+        .expect("discovery failed");
+    assert_eq!(summary.targets.len(), DEVICES);
+    assert!(summary.stray_count.is_empty());
+    let devices = sim
+        .devices
+        .iter()
+        .flatten()
+        .copied()
+        .collect::<BTreeSet<_>>();
+    assert!(summary.targets.keys().eq(devices.iter()));
+}
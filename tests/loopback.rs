@@ -0,0 +1,243 @@
+//! Request/reply flow over the in-memory transport, no sockets involved.
+
+use std::{
+    io::Cursor,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use binrw::{BinRead, BinWrite};
+use sdaa_ctrl::{
+    controller::{CollectUntil, Controller},
+    ctrl_msg::{CtrlMsg, DeviceErrorCode, Outcome},
+    retry::RetryPolicy,
+    sim::{DummyDevices, dummy_reply},
+    transport::{Device, Loopback},
+};
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+fn encode(msg: &CtrlMsg) -> Vec<u8> {
+    let mut buf = Cursor::new(Vec::new());
+    msg.write(&mut buf).unwrap();
+    buf.into_inner()
+}
+
+fn controller<D: Device + 'static>(device: D) -> Controller {
+    Controller::from_transport(Loopback::new(addr("10.0.0.100:3001"), device))
+        .unwrap()
+        .with_timeout(Some(Duration::from_millis(100)))
+}
+
+#[test]
+fn query_is_answered() {
+    let ctrl = controller(DummyDevices::new().with_device(addr("10.0.0.1:3000")));
+    let summary = ctrl
+        .send(CtrlMsg::Query { msg_id: 0 }, "10.0.0.1:3000")
+        .unwrap();
+    assert!(summary.all_ok());
+    let report = &summary.targets[&addr("10.0.0.1:3000")];
+    assert_eq!(report.attempts, 1);
+    assert!(matches!(
+        report.outcome,
+        Outcome::Ok(CtrlMsg::QueryReply { .. })
+    ));
+}
+
+#[test]
+fn absent_device_times_out() {
+    let ctrl = controller(DummyDevices::new().with_device(addr("10.0.0.1:3000")));
+    let summary = ctrl
+        .send_many(
+            CtrlMsg::Query { msg_id: 0 },
+            &["10.0.0.1:3000", "10.0.0.2:3000"],
+        )
+        .unwrap();
+    assert!(summary.targets[&addr("10.0.0.1:3000")].outcome.is_ok());
+    assert!(matches!(
+        summary.targets[&addr("10.0.0.2:3000")].outcome,
+        Outcome::Timeout
+    ));
+}
+
+#[test]
+fn lost_request_is_retried() {
+    let received = Arc::new(AtomicUsize::new(0));
+    let device = {
+        let received = Arc::clone(&received);
+        move |a: SocketAddr, data: &[u8]| {
+            // the first request gets lost on the way
+            if received.fetch_add(1, Ordering::Relaxed) == 0 {
+                return Vec::new();
+            }
+            let msg = CtrlMsg::read(&mut Cursor::new(data)).unwrap();
+            vec![(a, encode(&dummy_reply(msg)))]
+        }
+    };
+    let retry = RetryPolicy {
+        initial_backoff: Duration::from_millis(10),
+        ..RetryPolicy::new(3, Some(Duration::from_millis(50)))
+    };
+    let ctrl = controller(device).with_retry(retry);
+    let summary = ctrl
+        .send(CtrlMsg::Sync { msg_id: 0 }, "10.0.0.1:3000")
+        .unwrap();
+    assert!(summary.all_ok());
+    assert_eq!(summary.targets[&addr("10.0.0.1:3000")].attempts, 2);
+    assert_eq!(received.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn device_error_is_reported() {
+    let device = |a: SocketAddr, data: &[u8]| {
+        let msg = CtrlMsg::read(&mut Cursor::new(data)).unwrap();
+        let reply = CtrlMsg::invalid_msg(msg.get_msg_id(), DeviceErrorCode::UnknownCmd, "busy");
+        vec![(a, encode(&reply))]
+    };
+    let summary = controller(device)
+        .send(CtrlMsg::StreamStart { msg_id: 0 }, "10.0.0.1:3000")
+        .unwrap();
+    assert!(matches!(
+        summary.targets[&addr("10.0.0.1:3000")].outcome,
+        Outcome::DeviceError {
+            err_code: DeviceErrorCode::UnknownCmd,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn broadcast_collects_every_device() {
+    let devices = (1..=100)
+        .map(|i| SocketAddr::from(([10, 0, 0, i], 3000)))
+        .collect::<Vec<_>>();
+    let ctrl = controller(
+        DummyDevices::new().with_broadcast(addr("10.0.0.255:3000"), devices.iter().copied()),
+    );
+    let summary = ctrl
+        .broadcast_until(
+            CtrlMsg::Query { msg_id: 0 },
+            "10.0.0.255:3000",
+            CollectUntil::new(Some(devices.len()), None),
+        )
+        .unwrap();
+    assert_eq!(summary.targets.keys().copied().collect::<Vec<_>>(), devices);
+    assert!(summary.all_ok());
+}
+
+#[test]
+fn broadcast_stops_when_the_caller_says_so() {
+    let devices = (1..=10)
+        .map(|i| SocketAddr::from(([10, 0, 0, i], 3000)))
+        .collect::<Vec<_>>();
+    let ctrl = controller(
+        DummyDevices::new().with_broadcast(addr("10.0.0.255:3000"), devices.iter().copied()),
+    );
+    let mut seen = Vec::new();
+    let summary = ctrl
+        .broadcast_each(
+            CtrlMsg::Query { msg_id: 0 },
+            "10.0.0.255:3000",
+            CollectUntil::default(),
+            |a, report| {
+                assert!(report.outcome.is_ok());
+                seen.push(a);
+                seen.len() < 4
+            },
+        )
+        .unwrap();
+    assert_eq!(seen, devices[..4]);
+    assert_eq!(summary.targets.keys().copied().collect::<Vec<_>>(), seen);
+}
+
+#[test]
+fn late_reply_counts_as_stray() {
+    // answers every request, repeating the reply to the previous one first
+    let mut previous = None;
+    let device = move |a: SocketAddr, data: &[u8]| {
+        let msg = CtrlMsg::read(&mut Cursor::new(data)).unwrap();
+        let reply = encode(&dummy_reply(msg));
+        let mut replies = previous
+            .take()
+            .map(|late| vec![(a, late)])
+            .unwrap_or_default();
+        replies.push((a, reply.clone()));
+        previous = Some(reply);
+        replies
+    };
+    let ctrl = controller(device);
+    let first = ctrl
+        .send(CtrlMsg::Sync { msg_id: 0 }, "10.0.0.1:3000")
+        .unwrap();
+    assert!(first.all_ok());
+    assert!(first.stray_reply.is_empty());
+    let old_id = first.targets[&addr("10.0.0.1:3000")].msg_id;
+
+    let second = ctrl
+        .send(CtrlMsg::Sync { msg_id: 0 }, "10.0.0.1:3000")
+        .unwrap();
+    assert!(second.all_ok());
+    assert_ne!(second.targets[&addr("10.0.0.1:3000")].msg_id, old_id);
+    assert_eq!(second.stray_count[&addr("10.0.0.1:3000")], 1);
+    assert_eq!(second.stray_reply.len(), 1);
+    assert_eq!(second.stray_reply[0].1.get_msg_id(), old_id);
+}
+
+#[test]
+fn unroutable_datagrams_are_reported() {
+    // another operator's reply and a datagram too short to carry a msg_id,
+    // both from addresses the cmd was not sent to
+    let device = |a: SocketAddr, data: &[u8]| {
+        let msg = CtrlMsg::read(&mut Cursor::new(data)).unwrap();
+        let reply = encode(&dummy_reply(msg));
+        let foreign = encode(&CtrlMsg::SyncReply {
+            msg_id: 0xdead_beef,
+        });
+        vec![
+            (addr("10.0.0.7:3000"), foreign),
+            (addr("10.0.0.8:3000"), vec![0xff, 0x00, 0x00]),
+            (a, reply),
+        ]
+    };
+    let summary = controller(device)
+        .send(CtrlMsg::Sync { msg_id: 0 }, "10.0.0.1:3000")
+        .unwrap();
+    assert!(summary.all_ok());
+    assert_eq!(summary.stray_count[&addr("10.0.0.7:3000")], 1);
+    assert_eq!(summary.undecodable.len(), 1);
+    assert_eq!(summary.undecodable[0].0, addr("10.0.0.8:3000"));
+    assert_eq!(summary.undecodable[0].1, [0xff, 0x00, 0x00]);
+}
+
+#[test]
+fn late_reply_to_a_broadcast_counts_as_stray() {
+    let mut previous = None;
+    let device = move |_: SocketAddr, data: &[u8]| {
+        let msg = CtrlMsg::read(&mut Cursor::new(data)).unwrap();
+        let reply = encode(&dummy_reply(msg));
+        let mut replies = previous
+            .take()
+            .map(|late| vec![(addr("10.0.0.1:3000"), late)])
+            .unwrap_or_default();
+        replies.push((addr("10.0.0.1:3000"), reply.clone()));
+        previous = Some(reply);
+        replies
+    };
+    let ctrl = controller(device);
+    let until = CollectUntil::new(Some(1), None);
+    let first = ctrl
+        .broadcast_until(CtrlMsg::Query { msg_id: 0 }, "10.0.0.255:3000", until)
+        .unwrap();
+    assert!(first.stray_reply.is_empty());
+    let second = ctrl
+        .broadcast_until(CtrlMsg::Query { msg_id: 0 }, "10.0.0.255:3000", until)
+        .unwrap();
+    assert!(second.all_ok());
+    assert_eq!(second.stray_count[&addr("10.0.0.1:3000")], 1);
+}
@@ -0,0 +1,129 @@
+//! The blocking controller on a real local socket.
+
+mod common;
+
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use sdaa_ctrl::{
+    controller::{CollectUntil, Controller},
+    ctrl_msg::{CtrlError, CtrlMsg, Outcome, find_devices_iter},
+};
+
+use common::Served;
+
+#[test]
+fn send_failure_spares_the_other_targets() {
+    let served = Served::spawn(2);
+    // an IPv4 socket cannot send to an IPv6 target
+    let unreachable = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 3000));
+    let targets = [served.devices[0], unreachable, served.devices[1]];
+    let ctrl = Controller::bind("127.0.0.1:0")
+        .unwrap()
+        .with_timeout(Some(Duration::from_millis(200)));
+
+    let mut summary = ctrl
+        .send_many(CtrlMsg::Sync { msg_id: 0 }, &targets)
+        .unwrap();
+    assert!(summary.targets[&served.devices[0]].outcome.is_ok());
+    assert!(summary.targets[&served.devices[1]].outcome.is_ok());
+    let report = summary.targets.remove(&unreachable).unwrap();
+    assert!(
+        matches!(report.outcome, Outcome::SendFailed(_)),
+        "{:?}",
+        report.outcome
+    );
+    assert_eq!(report.attempts, 1);
+    match report.outcome.into_result(unreachable) {
+        Err(CtrlError::Send { addr, .. }) => assert_eq!(addr, unreachable),
+        r => panic!("not a send failure: {r:?}"),
+    }
+}
+
+#[test]
+fn broadcast_collects_every_device() {
+    let served = Served::spawn(16);
+    let ctrl = Controller::bind("127.0.0.1:0")
+        .unwrap()
+        .with_timeout(Some(Duration::from_millis(200)));
+    let summary = ctrl
+        .broadcast_until(
+            CtrlMsg::Query { msg_id: 0 },
+            served.baddr,
+            CollectUntil::new(Some(served.devices.len()), None),
+        )
+        .unwrap();
+    assert_eq!(
+        summary.targets.keys().copied().collect::<Vec<_>>(),
+        served.devices
+    );
+    assert!(summary.all_ok());
+}
+
+#[test]
+fn concurrent_callers_get_their_own_replies() {
+    let served = Served::spawn(8);
+    let ctrl = Controller::bind("127.0.0.1:0")
+        .unwrap()
+        .with_timeout(Some(Duration::from_millis(500)));
+    // the I/O thread routes each reply to the caller waiting for its msg_id
+    std::thread::scope(|s| {
+        for devices in served.devices.chunks(2) {
+            let ctrl = &ctrl;
+            s.spawn(move || {
+                for _ in 0..50 {
+                    let summary = ctrl
+                        .send_many(CtrlMsg::Query { msg_id: 0 }, devices)
+                        .unwrap();
+                    assert_eq!(summary.targets.keys().copied().collect::<Vec<_>>(), devices);
+                    assert!(summary.all_ok());
+                    assert!(summary.stray_reply.is_empty());
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn dropping_a_device_stream_ends_the_discovery() {
+    let served = Served::spawn(2);
+    let local = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    // no timeout, only dropping the stream ends it
+    let mut stream = find_devices_iter(served.baddr, local, None).unwrap();
+    assert!(stream.next().unwrap().is_ok());
+    drop(stream);
+
+    // the discovery thread is gone once its socket is closed
+    let start = Instant::now();
+    while UdpSocket::bind(local).is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "discovery still running"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn dropping_a_device_stream_before_it_started() {
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let local = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    drop(find_devices_iter(silent.local_addr().unwrap(), local, None).unwrap());
+
+    let start = Instant::now();
+    while UdpSocket::bind(local).is_err() {
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "discovery still running"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}